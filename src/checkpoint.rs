@@ -0,0 +1,256 @@
+use crate::manifest::{KeyError, ManifestEntry};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often keys recorded in a checkpoint should be flushed to disk
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The first line of a checkpoint file, followed by a Done line for every key done
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// None for snapshots dumped at their latest revision
+    revision: Option<i64>,
+    /// The etcd cluster dumped, None for snapshots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cluster_id: Option<String>,
+}
+
+/// A key a dump went through, with what goes into the manifest for it
+#[derive(Serialize, Deserialize)]
+struct Done {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entry: Option<ManifestEntry>,
+    /// Why ouger couldn't decode the value, for keys skipped because of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    undecodable: Option<String>,
+}
+
+/// The keys an interrupted dump already went through, so that running it again resumes it
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    revision: Option<i64>,
+    cluster_id: Option<String>,
+    /// Keys done by previous runs
+    done: HashSet<String>,
+    /// The manifest entries of the keys done by previous runs, until taken
+    entries: Vec<ManifestEntry>,
+    undecodable: Vec<KeyError>,
+    file: File,
+    /// Recorded keys not flushed yet. Unlike with a BufWriter, they are lost rather than flushed
+    /// when the dump is dropped, as the output may not have flushed them
+    unflushed: Vec<u8>,
+    last_flush: Instant,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint at path to append to it, None if there is none yet. A key a crash
+    /// left half written is dropped, so it's dumped again
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("reading checkpoint {}", path.display())),
+        };
+
+        let mut lines = contents.split_inclusive('\n');
+        let header: Header = serde_json::from_str(lines.next().unwrap_or_default())
+            .context(format!("invalid checkpoint {}", path.display()))?;
+        let mut complete_len = contents.len() - lines.clone().map(str::len).sum::<usize>();
+        let mut done = HashSet::new();
+        let mut entries = Vec::new();
+        let mut undecodable = Vec::new();
+        for line in lines {
+            let Some(key_done) = line
+                .strip_suffix('\n')
+                .and_then(|line| serde_json::from_str::<Done>(line).ok())
+            else {
+                break;
+            };
+            entries.extend(key_done.entry);
+            if let Some(error) = key_done.undecodable {
+                undecodable.push(KeyError {
+                    key: key_done.key.clone(),
+                    stage: None,
+                    error,
+                });
+            }
+            done.insert(key_done.key);
+            complete_len += line.len();
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .context(format!("opening checkpoint {}", path.display()))?;
+        file.set_len(complete_len as u64)
+            .context(format!("truncating checkpoint {}", path.display()))?;
+
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            revision: header.revision,
+            cluster_id: header.cluster_id,
+            done,
+            entries,
+            undecodable,
+            file,
+            unflushed: Vec::new(),
+            last_flush: Instant::now(),
+        }))
+    }
+
+    /// Starts a new checkpoint at path for a dump of the etcd cluster cluster_id at revision
+    pub(crate) fn create(
+        path: &Path,
+        revision: Option<i64>,
+        cluster_id: Option<String>,
+    ) -> Result<Self> {
+        let mut header = serde_json::to_vec(&Header {
+            revision,
+            cluster_id: cluster_id.clone(),
+        })?;
+        header.push(b'\n');
+        let mut file =
+            File::create(path).context(format!("creating checkpoint {}", path.display()))?;
+        file.write_all(&header)
+            .context(format!("writing checkpoint {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            revision,
+            cluster_id,
+            done: HashSet::new(),
+            entries: Vec::new(),
+            undecodable: Vec::new(),
+            file,
+            unflushed: Vec::new(),
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// The revision the checkpointed dump is taken at, every run must use it
+    pub(crate) fn revision(&self) -> Option<i64> {
+        self.revision
+    }
+
+    /// The etcd cluster the checkpointed dump is taken of, every run must dump it
+    pub(crate) fn cluster_id(&self) -> Option<&str> {
+        self.cluster_id.as_deref()
+    }
+
+    /// How many keys previous runs went through
+    pub(crate) fn done(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Whether a previous run went through key
+    pub(crate) fn is_done(&self, key: &str) -> bool {
+        self.done.contains(key)
+    }
+
+    /// Takes the manifest entries and undecodable keys of the keys previous runs went through
+    pub(crate) fn take_previous(&mut self) -> (Vec<ManifestEntry>, Vec<KeyError>) {
+        (
+            std::mem::take(&mut self.entries),
+            std::mem::take(&mut self.undecodable),
+        )
+    }
+
+    /// Records that key was dumped with entry, or deliberately skipped. Only lasts once flushed
+    pub(crate) fn record(
+        &mut self,
+        key: &str,
+        entry: Option<&ManifestEntry>,
+        undecodable: Option<&str>,
+    ) -> Result<()> {
+        #[derive(Serialize)]
+        struct DoneRef<'a> {
+            key: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            entry: Option<&'a ManifestEntry>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            undecodable: Option<&'a str>,
+        }
+
+        serde_json::to_writer(
+            &mut self.unflushed,
+            &DoneRef {
+                key,
+                entry,
+                undecodable,
+            },
+        )?;
+        self.unflushed.push(b'\n');
+        Ok(())
+    }
+
+    /// Whether the recorded keys should be flushed, once the output flushed their values
+    pub(crate) fn flush_due(&self) -> bool {
+        self.last_flush.elapsed() >= FLUSH_INTERVAL
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.last_flush = Instant::now();
+        self.file
+            .write_all(&std::mem::take(&mut self.unflushed))
+            .context(format!("writing checkpoint {}", self.path.display()))
+    }
+
+    /// Removes the checkpoint once every key has been dumped, so that the next dump starts over
+    pub(crate) fn remove(self) -> Result<()> {
+        std::fs::remove_file(&self.path)
+            .context(format!("removing checkpoint {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "etcddump-checkpoint-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resumes_with_what_it_was_started_with() {
+        let dir = test_dir("resume");
+        let path = dir.join("dump.checkpoint");
+        let mut checkpoint =
+            Checkpoint::create(&path, Some(42), Some("c0ffee".to_string())).unwrap();
+        checkpoint.record("/a", None, Some("not JSON")).unwrap();
+        checkpoint.flush().unwrap();
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(checkpoint.revision(), Some(42));
+        assert_eq!(checkpoint.cluster_id(), Some("c0ffee"));
+        assert!(checkpoint.is_done("/a"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_without_cluster_id_still_load() {
+        let dir = test_dir("no-cluster-id");
+        let path = dir.join("dump.checkpoint");
+        std::fs::write(&path, "{\"revision\":7}\n{\"key\":\"/a\"}\n").unwrap();
+
+        let checkpoint = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(checkpoint.revision(), Some(7));
+        assert_eq!(checkpoint.cluster_id(), None);
+        assert_eq!(checkpoint.done(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
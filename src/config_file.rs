@@ -0,0 +1,228 @@
+use crate::cli::Cli;
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, CommandFactory};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Appends the flags set in the --config file, if any, to args. Only flags that weren't already
+/// given on the command line (or through the environment), nor conflict with one that was, are
+/// appended, so those take precedence over the file, which in turn takes precedence over the
+/// built-in defaults.
+///
+/// Top-level keys of the file are global flags, and a table per subcommand holds the flags of that
+/// subcommand, all named after their long flag. Arrays set repeatable flags, `true` sets switches
+/// and integers set the count of counted flags like verbose:
+///
+/// ```toml
+/// verbose = 1
+///
+/// [dump]
+/// etcd-endpoint = ["node1:2379", "node2:2379"]
+/// output-dir = "/backups/etcd"
+/// exclude-prefix = ["/kubernetes.io/events/"]
+/// compress-files = true
+/// ```
+pub(crate) fn with_config_file(args: Vec<OsString>) -> Result<Vec<OsString>> {
+    // Errors, --help included, are reported by the real parse
+    let Ok(matches) = Cli::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+    else {
+        return Ok(args);
+    };
+    let Some((subcommand_name, subcommand_matches)) = matches.subcommand() else {
+        return Ok(args);
+    };
+    let Some(path) = subcommand_matches
+        .get_one::<PathBuf>("config")
+        .or_else(|| matches.get_one::<PathBuf>("config"))
+    else {
+        return Ok(args);
+    };
+
+    let contents =
+        std::fs::read_to_string(path).context(format!("reading config file {}", path.display()))?;
+    let table: toml::Table =
+        toml::from_str(&contents).context(format!("parsing config file {}", path.display()))?;
+
+    let command = Cli::command();
+    let mut args = args;
+    for (key, value) in &table {
+        match value {
+            toml::Value::Table(subcommand_table) => {
+                let Some(subcommand) = command.find_subcommand(key) else {
+                    bail!("unknown subcommand {:?} in config file", key);
+                };
+                // Tables of the other subcommands are for other invocations
+                if key == subcommand_name {
+                    for (key, value) in subcommand_table {
+                        args.extend(flag_args(subcommand, subcommand_matches, key, value)?);
+                    }
+                }
+            }
+            value => args.extend(flag_args(&command, subcommand_matches, key, value)?),
+        }
+    }
+
+    Ok(args)
+}
+
+/// Whether arg was given on the command line or through the environment
+fn is_given(matches: &ArgMatches, arg: &Arg) -> bool {
+    matches!(
+        matches.value_source(arg.get_id().as_str()),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Whether a flag that conflicts with arg, or that arg conflicts with, was given
+fn conflicts_with_given(command: &clap::Command, matches: &ArgMatches, arg: &Arg) -> bool {
+    command.get_arguments().any(|other| {
+        other.get_id() != arg.get_id()
+            && is_given(matches, other)
+            && (command.get_arg_conflicts_with(arg).contains(&other)
+                || command.get_arg_conflicts_with(other).contains(&arg))
+    })
+}
+
+/// The command line arguments setting the flag of command named key to value, none if it or a
+/// flag conflicting with it was already set
+fn flag_args(
+    command: &clap::Command,
+    matches: &ArgMatches,
+    key: &str,
+    value: &toml::Value,
+) -> Result<Vec<OsString>> {
+    let Some(arg) = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(key) && key != "config")
+    else {
+        bail!("unknown flag {:?} in config file", key);
+    };
+
+    if is_given(matches, arg) || conflicts_with_given(command, matches, arg) {
+        return Ok(Vec::new());
+    }
+
+    let flag = OsString::from(format!("--{}", key));
+    let values = match value {
+        toml::Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+
+    let mut args = Vec::new();
+    for value in values {
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+                if *set {
+                    args.push(flag.clone());
+                }
+            }
+            (ArgAction::Count, toml::Value::Integer(count)) => {
+                for _ in 0..*count {
+                    args.push(flag.clone());
+                }
+            }
+            (ArgAction::Set | ArgAction::Append, toml::Value::String(value)) => {
+                args.extend([flag.clone(), value.into()]);
+            }
+            (ArgAction::Set | ArgAction::Append, toml::Value::Integer(value)) => {
+                args.extend([flag.clone(), value.to_string().into()]);
+            }
+            (ArgAction::Set | ArgAction::Append, toml::Value::Float(value)) => {
+                args.extend([flag.clone(), value.to_string().into()]);
+            }
+            _ => bail!(
+                "unexpected value {} for flag {:?} in config file",
+                value,
+                key
+            ),
+        }
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The args with_config_file adds to args for a config file with contents
+    fn added_args(name: &str, contents: &str, args: &[&str]) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!(
+            "etcddump-config-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+
+        let mut args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        args.extend(["--config".into(), path.clone().into()]);
+        let given = args.len();
+        let added = with_config_file(args)
+            .unwrap()
+            .into_iter()
+            .skip(given)
+            .map(|arg| arg.into_string().unwrap())
+            .collect();
+
+        std::fs::remove_file(&path).unwrap();
+        added
+    }
+
+    #[test]
+    fn file_sets_flags_not_given() {
+        let added = added_args(
+            "not-given",
+            "verbose = 2\n[dump]\nconcurrency = 8\ncompress-files = true\n\
+             exclude-prefix = [\"/a/\", \"/b/\"]\n[restore]\nconcurrency = 1\n",
+            &["etcddump", "dump"],
+        );
+        assert_eq!(
+            added,
+            [
+                "--compress-files",
+                "--concurrency",
+                "8",
+                "--exclude-prefix",
+                "/a/",
+                "--exclude-prefix",
+                "/b/",
+                "--verbose",
+                "--verbose",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let added = added_args(
+            "precedence",
+            "[dump]\nconcurrency = 8\npage-size = 10\n",
+            &["etcddump", "dump", "--concurrency", "2"],
+        );
+        assert_eq!(added, ["--page-size", "10"]);
+    }
+
+    #[test]
+    fn flags_conflicting_with_the_command_line_are_left_out() {
+        let password_file =
+            std::env::temp_dir().join(format!("etcddump-config-password-{}", std::process::id()));
+        std::fs::write(&password_file, "secret").unwrap();
+
+        let added = added_args(
+            "conflicts",
+            "[dump]\npassword = \"x\"\nconcurrency = 8\n",
+            &[
+                "etcddump",
+                "dump",
+                "--password-file",
+                password_file.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(added, ["--concurrency", "8"]);
+
+        std::fs::remove_file(&password_file).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use crate::restore;
+use anyhow::{Context, Result};
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// How the keys of two dumps differ, each list sorted by key
+#[derive(Debug, Default)]
+pub struct DumpDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<ModifiedKey>,
+}
+
+/// A key present in both dumps with different values
+#[derive(Debug)]
+pub struct ModifiedKey {
+    pub key: String,
+    pub old_path: PathBuf,
+    pub new_path: PathBuf,
+    /// Whether the old and the new dump were written with compression
+    old_compressed: bool,
+    new_compressed: bool,
+}
+
+impl DumpDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl ModifiedKey {
+    /// A unified diff of the old and new values, lossily converted to UTF-8
+    pub fn unified_diff(&self) -> Result<String> {
+        let old_value = restore::read_dump_file(&self.old_path, self.old_compressed)?;
+        let new_value = restore::read_dump_file(&self.new_path, self.new_compressed)?;
+
+        Ok(TextDiff::from_lines(
+            String::from_utf8_lossy(&old_value).as_ref(),
+            String::from_utf8_lossy(&new_value).as_ref(),
+        )
+        .unified_diff()
+        .header(
+            &self.old_path.display().to_string(),
+            &self.new_path.display().to_string(),
+        )
+        .to_string())
+    }
+}
+
+/// Compares two dump dirs written with the same layout, raw dumps have to be compared with raw
+pub fn diff_dumps(old_dir: &Path, new_dir: &Path, raw: bool) -> Result<DumpDiff> {
+    let old_files: BTreeMap<String, PathBuf> = restore::dump_files(old_dir, raw)
+        .context(format!("listing files of {}", old_dir.display()))?
+        .into_iter()
+        .collect();
+    let mut new_files: BTreeMap<String, PathBuf> = restore::dump_files(new_dir, raw)
+        .context(format!("listing files of {}", new_dir.display()))?
+        .into_iter()
+        .collect();
+    let old_compressed = restore::is_compressed(old_dir)?;
+    let new_compressed = restore::is_compressed(new_dir)?;
+
+    let mut diff = DumpDiff::default();
+    for (key, old_path) in old_files {
+        let Some(new_path) = new_files.remove(&key) else {
+            diff.removed.push(key);
+            continue;
+        };
+
+        if restore::read_dump_file(&old_path, old_compressed)?
+            != restore::read_dump_file(&new_path, new_compressed)?
+        {
+            diff.modified.push(ModifiedKey {
+                key,
+                old_path,
+                new_path,
+                old_compressed,
+                new_compressed,
+            });
+        }
+    }
+    diff.added = new_files.into_keys().collect();
+
+    Ok(diff)
+}
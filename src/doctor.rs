@@ -0,0 +1,187 @@
+use crate::etcd::{self, EtcdConfig};
+use crate::ouger::{self, OugerCommand, OugerConfig};
+use anyhow::{ensure, Context, Result};
+use etcd_client::GetOptions;
+
+/// What Kubernetes protobuf values start with
+const K8S_PROTOBUF_MAGIC: &[u8] = b"k8s\0";
+
+/// A check of the environment of a dump, with what to look into when it fails
+pub struct Check {
+    pub name: &'static str,
+    pub result: CheckResult,
+    pub hint: &'static str,
+}
+
+pub enum CheckResult {
+    /// What was found
+    Pass(String),
+    Fail(anyhow::Error),
+    /// A check this one needs failed
+    Skip,
+}
+
+impl Check {
+    pub fn failed(&self) -> bool {
+        matches!(self.result, CheckResult::Fail(_))
+    }
+}
+
+/// Checks that ouger can be launched or reached, that it round-trips a ConfigMap through encode
+/// and decode, and that etcd can be connected to and listed, in this order. Checks needing one
+/// that failed are skipped
+pub async fn run_checks(etcd_config: &EtcdConfig, ouger_config: &OugerConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    // Launching already waits for the servers to be healthy, but a remote one isn't asked
+    let ouger = async {
+        let ouger_pool = ouger_config.launch().await?;
+        let client = ouger_config.client(1)?;
+        ouger::health_check(&client, ouger_pool.url()).await?;
+        anyhow::Ok((ouger_pool, client))
+    }
+    .await;
+    let (ouger, result) = outcome(ouger, |(ouger_pool, _)| {
+        format!("{} is up", ouger_pool.url())
+    });
+    checks.push(Check {
+        name: "ouger",
+        result,
+        hint: "put ouger_server in PATH, or point --ouger-bin or --ouger-url at a running one",
+    });
+    checks.push(Check {
+        name: "ouger round-trip",
+        result: match &ouger {
+            Some((ouger_pool, client)) => {
+                outcome(round_trip(client, ouger_pool.url()).await, |size| {
+                    format!(
+                        "a ConfigMap encodes to {} bytes of Kubernetes protobuf and decodes back",
+                        size
+                    )
+                })
+                .1
+            }
+            None => CheckResult::Skip,
+        },
+        hint: "ouger answers but doesn't convert Kubernetes objects, is it really ouger?",
+    });
+    drop(ouger);
+
+    // Connecting alone doesn't tell whether the endpoints answer
+    let etcd_client = async {
+        let etcd_client = etcd_config.connect().await?;
+        let mut maintenance_client = etcd_client.maintenance_client();
+        let status = etcd::with_timeout(etcd_config.request_timeout, maintenance_client.status())
+            .await
+            .context("getting the status of etcd")?;
+        anyhow::Ok((etcd_client, status))
+    }
+    .await;
+    let (etcd_client, result) = outcome(etcd_client, |(_, status)| {
+        format!(
+            "connected to etcd {} at {}",
+            status.version(),
+            etcd_config.endpoints.join(", ")
+        )
+    });
+    checks.push(Check {
+        name: "etcd",
+        result,
+        hint: "check --etcd-endpoint, the TLS flags and the credentials",
+    });
+    checks.push(Check {
+        name: "etcd list",
+        result: match &etcd_client {
+            Some((etcd_client, _)) => {
+                outcome(first_key(etcd_client, etcd_config).await, |key| match key {
+                    Some(key) => format!("listed {}", key),
+                    None => "etcd has no keys".to_string(),
+                })
+                .1
+            }
+            None => CheckResult::Skip,
+        },
+        hint: "the etcd user may not be allowed to read keys",
+    });
+
+    checks
+}
+
+/// The value of result for the checks that need it, and the result of the check itself
+fn outcome<T>(result: Result<T>, describe: impl FnOnce(&T) -> String) -> (Option<T>, CheckResult) {
+    match result {
+        Ok(value) => {
+            let description = describe(&value);
+            (Some(value), CheckResult::Pass(description))
+        }
+        Err(err) => (None, CheckResult::Fail(err)),
+    }
+}
+
+/// Encodes a ConfigMap with ouger and decodes it back, returning the size of the encoded value
+async fn round_trip(client: &reqwest::Client, url: &str) -> Result<usize> {
+    let config_map = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": {"name": "etcddump-doctor", "namespace": "default"},
+        "data": {"key": "value"},
+    });
+
+    let encoded = ouger::ouger(
+        client,
+        url,
+        OugerCommand::Encode,
+        &serde_json::to_vec(&config_map)?,
+    )
+    .await
+    .context("encoding a ConfigMap")?;
+    ensure!(
+        encoded.starts_with(K8S_PROTOBUF_MAGIC),
+        "the encoded ConfigMap isn't Kubernetes protobuf"
+    );
+
+    let decoded = ouger::ouger(client, url, OugerCommand::Decode, &encoded)
+        .await
+        .context("decoding the encoded ConfigMap")?;
+    let decoded: serde_json::Value =
+        serde_json::from_slice(&decoded).context("the decoded ConfigMap isn't JSON")?;
+    // Decoding fills in defaults, e.g. a null creationTimestamp, so only what was set is compared
+    for pointer in ["/apiVersion", "/kind", "/metadata/name", "/data"] {
+        ensure!(
+            decoded.pointer(pointer) == config_map.pointer(pointer),
+            "{} of the ConfigMap changed from {} to {} in the round-trip",
+            pointer,
+            config_map.pointer(pointer).unwrap(),
+            decoded.pointer(pointer).unwrap_or(&serde_json::Value::Null)
+        );
+    }
+
+    Ok(encoded.len())
+}
+
+/// The first key of the keyspace, if there is any
+async fn first_key(
+    etcd_client: &etcd_client::Client,
+    etcd_config: &EtcdConfig,
+) -> Result<Option<String>> {
+    let mut kv_client = etcd::kv_client(etcd_client, etcd_config.max_recv_message_size);
+    let get_response = etcd::with_timeout(
+        etcd_config.request_timeout,
+        kv_client.get(
+            "",
+            Some(
+                GetOptions::new()
+                    .with_all_keys()
+                    .with_keys_only()
+                    .with_limit(1),
+            ),
+        ),
+    )
+    .await
+    .context("listing a key")?;
+
+    Ok(get_response
+        .kvs()
+        .first()
+        .map(|kv| String::from_utf8_lossy(kv.key()).into_owned()))
+}
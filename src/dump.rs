@@ -0,0 +1,2894 @@
+use crate::checkpoint::Checkpoint;
+use crate::manifest::ErrorStage;
+use crate::metrics::Metrics;
+use crate::output::{
+    expand_template, is_template, sort_json_fields, JsonFormat, Layout, Output, OutputConfig,
+    PipeClosed,
+};
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryBreaker;
+use crate::timings::{TimingSamples, Timings};
+use crate::utc::UtcTime;
+use crate::{etcd, k8s, manifest, ouger, paths, retry, snapshot, transform};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use etcd_client::{Client as EtcdClient, GetOptions, KeyValue};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{future, stream, Stream, StreamExt};
+use regex::Regex;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+use tracing::Instrument;
+
+/// What to do with values ouger fails to decode
+#[derive(Clone, Copy, Debug)]
+pub enum DecodeErrorPolicy {
+    /// Fail the key
+    Fail,
+    /// Write the value as stored in etcd, with a .bin extension
+    Raw,
+    /// Leave the key out of the dump
+    Skip,
+}
+
+/// What to do with keys that map to the same file as another key, e.g. /a and a in the nested
+/// layout
+#[derive(Clone, Copy, Debug)]
+pub enum CollisionPolicy {
+    /// Fail the dump before writing anything, listing every collision
+    Fail,
+    /// Write the value of every key but the first one to the file name followed by ~ and the
+    /// lowest number not taken. The manifest maps such files back to their keys
+    Suffix,
+}
+
+/// What to dump and where to
+pub struct DumpConfig {
+    pub etcd: etcd::EtcdConfig,
+    pub ouger: ouger::OugerConfig,
+    pub output: OutputConfig,
+    /// How key files are arranged in the output, doesn't apply to stdout
+    pub layout: Layout,
+    /// Trimmed off the keys that start with it before mapping them to paths, keys that don't are
+    /// written under their whole key
+    pub strip_prefix: Option<String>,
+    /// Skip the keys that don't start with strip_prefix instead
+    pub skip_unstripped: bool,
+    /// How many keys are in flight at once, be they fetched, decoded or waiting in between
+    pub concurrency: usize,
+    /// How many of the keys in flight may be fetched from etcd at once, all of them by default
+    pub fetch_concurrency: Option<usize>,
+    /// How many of the keys in flight may be decoded by ouger at once, all of them by default. A
+    /// single ouger server decodes one value at a time anyway
+    pub decode_concurrency: Option<usize>,
+    /// Fetch at most this many keys per second, regardless of concurrency
+    pub max_keys_per_sec: Option<NonZeroU32>,
+    /// How many key names to list per request
+    pub page_size: i64,
+    /// Only keys under these prefixes get dumped
+    pub prefixes: Vec<String>,
+    /// Dump the keys of every prefix, leaving out those under another one, into a dir of its own
+    /// under the output dir, concurrently. The keys in flight of all the prefixes together are
+    /// still limited by concurrency. Only applies to dir outputs
+    pub parallel_prefixes: bool,
+    /// Keys under these prefixes are skipped without even listing them
+    pub exclude_prefixes: Vec<String>,
+    /// Dump exactly these keys instead of listing prefixes, keys that don't exist are failures
+    pub keys: Option<BTreeSet<String>>,
+    pub include: Option<Regex>,
+    pub exclude: Option<Regex>,
+    /// Only dump keys in [from_key, to_key), within the prefixes. Ranges outside of it aren't
+    /// listed
+    pub from_key: Option<String>,
+    pub to_key: Option<String>,
+    /// Write the values as stored in etcd instead of decoding them with ouger
+    pub raw: bool,
+    /// How to write values ouger decoded to JSON, doesn't apply to raw dumps
+    pub json_format: JsonFormat,
+    /// Only applies to values ouger was reached for but couldn't decode, and to values the
+    /// transform command failed on. Failing to reach ouger always fails the key
+    pub on_decode_error: DecodeErrorPolicy,
+    /// Give up on a value ouger didn't decode within this long, failing it with DecodeTimeout
+    /// which is handled like any value ouger couldn't decode
+    pub decode_timeout: Option<Duration>,
+    /// Keys under these prefixes that ouger couldn't decode are skipped whatever on_decode_error
+    /// is, without being counted as errors nor recorded as undecodable
+    pub ignore_decode_error_prefixes: Vec<String>,
+    /// Keys under these prefixes are written as stored in etcd without sending them to ouger, for
+    /// values ouger would return unchanged anyway
+    pub skip_ouger_prefixes: Vec<String>,
+    /// Record failing keys in the summary instead of aborting the dump
+    pub continue_on_error: bool,
+    /// Also print every key failing while continuing on errors to stderr as a JSON line, the
+    /// moment it fails
+    pub stream_errors: bool,
+    /// Dump the keyspace as of this revision instead of the latest one
+    pub revision: Option<i64>,
+    /// Warn when revision is less than this many revisions newer than the last compaction, as
+    /// compacting it while the dump is running fails the keys not fetched yet
+    pub compaction_margin: i64,
+    /// Skip keys whose value as stored in etcd is smaller than this, without decoding them
+    pub min_value_bytes: Option<usize>,
+    /// Skip keys whose value as stored in etcd is larger than this, without decoding them
+    pub max_value_bytes: Option<usize>,
+    /// Skip keys attached to a lease, which are usually transient
+    pub skip_leased: bool,
+    /// Also write the names of the users and roles of etcd auth to auth.json, when dumping etcd
+    /// to files
+    pub include_auth: bool,
+    /// Also write the active leases with their TTLs and keys to leases.json, when dumping etcd to
+    /// files
+    pub include_leases: bool,
+    /// Skip keys last modified before this revision, without decoding them. Dumping from the
+    /// revision after the one of a previous dump only writes the keys changed since, but not
+    /// which ones were deleted
+    pub min_mod_revision: Option<i64>,
+    /// Log every key with an empty value, which may be a decode bug
+    pub warn_on_empty: bool,
+    /// Don't write keys with an empty value
+    pub skip_empty: bool,
+    /// Gzip compress every key file, adding a .gz extension
+    pub compress_files: bool,
+    /// Write every distinct value once, to a file of the blobs dir named after its SHA-256, rather
+    /// than a file per key. Only the manifest maps keys to their value, and key paths aren't used
+    pub dedup: bool,
+    /// Add a .json, .yaml, .txt or .bin extension to files of decoded values depending on what
+    /// they contain, before any .gz one. The manifest records it so that reading the dump strips
+    /// it again. Not supported by skip_existing, which can't tell which file a key would get
+    pub add_extensions: bool,
+    /// Record the SHA-256 of every written file in the manifest and in a SHA256SUMS file
+    pub checksums: bool,
+    /// Don't fetch keys that already have a non-empty file in the output dir, to resume an
+    /// interrupted dump. Keys that changed in etcd since are not detected
+    pub skip_existing: bool,
+    /// Time every phase of the dump and every request, reported in the summary
+    pub timings: bool,
+    /// Read the keys from this etcd snapshot file, as saved by etcdctl snapshot save, instead of
+    /// contacting etcd at all
+    pub snapshot: Option<PathBuf>,
+    /// Only dump the first this many keys, in key order, of those left after filtering. Doesn't
+    /// apply to an explicit list of keys
+    pub limit: Option<usize>,
+    /// Write the etcd metadata of every key to a .meta.json sidecar next to its value file
+    pub sidecar_meta: bool,
+    /// Also write the value as stored in etcd next to every decoded value, with a .raw extension
+    pub include_raw: bool,
+    pub on_collision: CollisionPolicy,
+    /// Dotted paths of fields to remove from decoded Kubernetes objects, e.g.
+    /// k8s::DEFAULT_NOISE_FIELDS. Objects are written compactly before json_format applies when
+    /// any field was removed
+    pub strip_fields: Vec<String>,
+    /// Sort the fields of every object of decoded JSON values by name, recursively, before
+    /// json_format applies, so that values only differing in field order are written the same.
+    /// Sorted values are written compactly when json_format is raw
+    pub normalize_json: bool,
+    /// A shell command to pipe every decoded value through, after json_format applied, writing
+    /// its output instead. Failing commands are treated like values ouger can't decode
+    pub transform: Option<String>,
+    /// Inflate values that start with the gzip magic bytes, e.g. ones an application compressed
+    /// before storing them, before json_format and transform apply. Decoded values are inflated
+    /// after ouger, raw ones as stored in etcd. The manifest flags inflated values
+    pub decompress: bool,
+    /// Only write the first this many bytes of every value, after transform, followed by a marker
+    /// saying how large it was. The manifest records the original size of truncated values
+    pub max_value_output_bytes: Option<usize>,
+    /// Keys under these prefixes are written as a JSON placeholder with the SHA-256 and size of
+    /// their value as stored in etcd, without decoding it, and flagged in the manifest. Raw copies
+    /// of them aren't written either
+    pub redact_prefixes: Vec<String>,
+    /// Abort the dump once it has been running this long, counting from connecting to etcd.
+    /// Keys still being fetched are canceled. When continuing on errors, they are recorded as
+    /// failures and everything dumped until then is written along with the manifest, otherwise
+    /// the dump fails with DeadlineExceeded
+    pub deadline: Option<Duration>,
+    /// Abort the dump with EtcdUnavailable once etcd gets of all keys together were retried more
+    /// than this many times within max_retries_window, instead of every key retrying on its own
+    pub max_retries_total: Option<usize>,
+    pub max_retries_window: Duration,
+    /// Write through symlinks found under an output dir, which may lead outside of it
+    pub follow_symlinks: bool,
+    /// Remove the dirs of the output dir left empty once the dump is done, e.g. by failed writes
+    /// or by the files of a previous dump it was forced into
+    pub prune_empty_dirs: bool,
+    /// Record every key done in this file, and skip the keys it lists when it already exists so
+    /// that an interrupted dump resumes where it stopped, adding to its output. It pins the
+    /// revision of the dump, so resuming at another one fails. Removed once no key failed
+    pub checkpoint: Option<PathBuf>,
+}
+
+/// Decodes every key under / to stdout as pretty JSON, failing on the first error, with none of
+/// the optional features
+impl Default for DumpConfig {
+    fn default() -> Self {
+        Self {
+            etcd: etcd::EtcdConfig::default(),
+            ouger: ouger::OugerConfig::default(),
+            output: OutputConfig::Stdout,
+            layout: Layout::Nested,
+            strip_prefix: None,
+            skip_unstripped: false,
+            concurrency: 64,
+            fetch_concurrency: None,
+            decode_concurrency: None,
+            max_keys_per_sec: None,
+            page_size: 1000,
+            prefixes: vec!["/".to_string()],
+            parallel_prefixes: false,
+            exclude_prefixes: Vec::new(),
+            keys: None,
+            include: None,
+            exclude: None,
+            from_key: None,
+            to_key: None,
+            raw: false,
+            json_format: JsonFormat::Pretty,
+            on_decode_error: DecodeErrorPolicy::Fail,
+            decode_timeout: None,
+            ignore_decode_error_prefixes: Vec::new(),
+            skip_ouger_prefixes: Vec::new(),
+            continue_on_error: false,
+            stream_errors: false,
+            revision: None,
+            compaction_margin: 0,
+            min_value_bytes: None,
+            max_value_bytes: None,
+            skip_leased: false,
+            include_auth: false,
+            include_leases: false,
+            min_mod_revision: None,
+            warn_on_empty: false,
+            skip_empty: false,
+            compress_files: false,
+            dedup: false,
+            add_extensions: false,
+            checksums: false,
+            skip_existing: false,
+            timings: false,
+            snapshot: None,
+            limit: None,
+            sidecar_meta: false,
+            include_raw: false,
+            on_collision: CollisionPolicy::Fail,
+            strip_fields: Vec::new(),
+            normalize_json: false,
+            transform: None,
+            decompress: false,
+            max_value_output_bytes: None,
+            redact_prefixes: Vec::new(),
+            deadline: None,
+            max_retries_total: None,
+            max_retries_window: Duration::ZERO,
+            follow_symlinks: false,
+            prune_empty_dirs: false,
+            checkpoint: None,
+        }
+    }
+}
+
+/// The outcome of a dump
+#[derive(Debug, Default)]
+pub struct DumpSummary {
+    /// How many keys were written
+    pub keys: usize,
+    /// How many keys were skipped because they were already in the output dir or checkpoint
+    pub skipped: usize,
+    /// How many keys were skipped because their value was outside of the requested size range
+    pub skipped_by_size: usize,
+    /// How many keys were skipped because they were attached to a lease
+    pub skipped_leased: usize,
+    /// How many keys were skipped because they were last modified before the minimum revision
+    pub skipped_unmodified: usize,
+    /// How many keys had an empty value in etcd, which are not written when skipping them
+    pub empty: usize,
+    /// How many keys were skipped because ouger couldn't decode them
+    pub skipped_undecodable: usize,
+    /// The total size of the written values
+    pub bytes_written: u64,
+    /// The revision the keys were dumped at, as of the start of the dump when not pinned to one.
+    /// None for the latest revision of a snapshot
+    pub revision: Option<i64>,
+    /// The hex ID of the etcd cluster, None for snapshots
+    pub cluster_id: Option<String>,
+    /// Keys that failed to dump, only ever non-empty when continuing on errors
+    pub errors: Vec<manifest::KeyError>,
+    /// Where the time went, only when requested
+    pub timings: Option<Timings>,
+    /// The dump was interrupted before every key was done, the counts only cover those that were
+    pub interrupted: bool,
+}
+
+/// Stops the dumps of the dumpers it's given early, once they wrote the keys dumped so far along
+/// with their manifest
+#[derive(Clone)]
+pub struct DumpInterrupt(Arc<watch::Sender<bool>>);
+
+impl Default for DumpInterrupt {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl DumpInterrupt {
+    /// Returns false if no dump is running, or if it was interrupted already, then it's up to the
+    /// caller to stop right away
+    pub fn interrupt(&self) -> bool {
+        self.0.receiver_count() > 0
+            && self
+                .0
+                .send_if_modified(|interrupted| !std::mem::replace(interrupted, true))
+    }
+}
+
+/// The error of a dump that ran past DumpConfig::deadline
+#[derive(Debug)]
+pub struct DeadlineExceeded {
+    pub deadline: Duration,
+    /// How many keys were dumped or failed before the deadline
+    pub done: usize,
+    /// How many keys were going to be dumped, 0 when the deadline passed before listing them
+    pub total: usize,
+}
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "deadline of {}s exceeded with {} of {} keys done",
+            self.deadline.as_secs(),
+            self.done,
+            self.total
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// The error of a value ouger didn't decode within DumpConfig::decode_timeout
+#[derive(Debug)]
+pub struct DecodeTimeout {
+    pub key: String,
+    pub elapsed: Duration,
+}
+
+impl std::fmt::Display for DecodeTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ouger didn't decode key {} within {:.1}s",
+            self.key,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for DecodeTimeout {}
+
+/// Context of the error of the etcd get that tripped DumpConfig::max_retries_total, or that
+/// failed after it tripped, which ends the dump right away
+#[derive(Debug)]
+pub struct EtcdUnavailable {
+    pub max_retries: usize,
+    pub window: Duration,
+}
+
+impl std::fmt::Display for EtcdUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "etcd appears unavailable, gets were retried more than {} times within {}s",
+            self.max_retries,
+            self.window.as_secs()
+        )
+    }
+}
+
+impl DumpSummary {
+    /// Writes the stats of the run to path as a single JSON object, for monitoring
+    pub fn write_json(
+        &self,
+        path: &Path,
+        started_at: SystemTime,
+        finished_at: SystemTime,
+    ) -> Result<()> {
+        let run_summary = manifest::RunSummary {
+            keys: self.keys,
+            bytes: self.bytes_written,
+            skipped: self.skipped
+                + self.skipped_by_size
+                + self.skipped_leased
+                + self.skipped_unmodified
+                + self.skipped_undecodable,
+            errors: self.errors.len(),
+            duration_ms: finished_at
+                .duration_since(started_at)
+                .unwrap_or_default()
+                .as_millis(),
+            revision: self.revision,
+            cluster_id: self.cluster_id.clone(),
+            complete: !self.interrupted,
+            started_at: rfc3339(started_at),
+            finished_at: rfc3339(finished_at),
+        };
+        let contents = serde_json::to_vec_pretty(&run_summary).context("serializing summary")?;
+        std::fs::write(path, contents).context(format!("writing {}", path.display()))
+    }
+}
+
+/// e.g. 2024-05-01T12:34:56Z
+fn rfc3339(time: SystemTime) -> String {
+    let time = UtcTime::from(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        time.year, time.month, time.day, time.hour, time.minute, time.second
+    )
+}
+
+/// How many of the most recent key errors a DumpProgress keeps
+const RECENT_ERRORS: usize = 100;
+
+/// Counters of a running dump, shared with the dumper they were obtained from
+#[derive(Clone, Default)]
+pub struct DumpProgress {
+    total: Arc<AtomicUsize>,
+    done: Arc<AtomicUsize>,
+    prefixes: Arc<Mutex<Vec<PrefixProgress>>>,
+    recent_errors: Arc<Mutex<VecDeque<manifest::KeyError>>>,
+}
+
+/// How far a running dump is into the keys under one of its prefixes
+#[derive(Clone, Debug)]
+pub struct PrefixProgress {
+    pub prefix: String,
+    pub total: usize,
+    pub done: usize,
+}
+
+impl DumpProgress {
+    /// How many keys are going to be dumped, 0 until they have been listed
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// How many keys have been dumped or failed so far
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// The progress under every prefix, empty until the keys have been listed and when dumping
+    /// given keys
+    pub fn prefixes(&self) -> Vec<PrefixProgress> {
+        self.prefixes.lock().unwrap().clone()
+    }
+
+    /// The last keys that failed, oldest first, only ever non-empty when continuing on errors
+    pub fn recent_errors(&self) -> Vec<manifest::KeyError> {
+        self.recent_errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn start(&self, prefixes: &[String], keys: &BTreeSet<String>) {
+        self.total.store(keys.len(), Ordering::Relaxed);
+        let mut prefixes: Vec<PrefixProgress> = prefixes
+            .iter()
+            .map(|prefix| PrefixProgress {
+                prefix: prefix.clone(),
+                total: 0,
+                done: 0,
+            })
+            .collect();
+        for key in keys {
+            if let Some(prefix) = longest_prefix(&mut prefixes, key) {
+                prefix.total += 1;
+            }
+        }
+        *self.prefixes.lock().unwrap() = prefixes;
+    }
+
+    fn key_done(&self, key: &str) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+        if let Some(prefix) = longest_prefix(&mut self.prefixes.lock().unwrap(), key) {
+            prefix.done += 1;
+        }
+    }
+
+    fn key_failed(&self, error: &manifest::KeyError) {
+        let mut recent_errors = self.recent_errors.lock().unwrap();
+        if recent_errors.len() == RECENT_ERRORS {
+            recent_errors.pop_front();
+        }
+        recent_errors.push_back(error.clone());
+    }
+}
+
+/// prefixes in order, without those under another one of them, whose keys that one covers
+pub(crate) fn distinct_prefixes(prefixes: &[String]) -> Vec<String> {
+    let mut distinct: Vec<String> = Vec::new();
+    for prefix in prefixes.iter().collect::<BTreeSet<_>>() {
+        // Everything sorted between a prefix and one under it is under it too
+        if !distinct
+            .last()
+            .is_some_and(|last| prefix.starts_with(last.as_str()))
+        {
+            distinct.push(prefix.clone());
+        }
+    }
+    distinct
+}
+
+/// The most specific of prefixes key is under, as prefixes may be nested
+fn longest_prefix<'a>(
+    prefixes: &'a mut [PrefixProgress],
+    key: &str,
+) -> Option<&'a mut PrefixProgress> {
+    prefixes
+        .iter_mut()
+        .filter(|prefix| key.starts_with(&prefix.prefix))
+        .max_by_key(|prefix| prefix.prefix.len())
+}
+
+/// Expands the tokens of an output dir template in config, see is_template, and creates the dir
+/// it expands to. Connects to etcd for the cluster id when the template uses it
+pub async fn expand_output_dir(config: &mut DumpConfig) -> Result<()> {
+    let OutputConfig::Dir(output_dir) = &config.output else {
+        return Ok(());
+    };
+    let Some(template) = output_dir.to_str().filter(|dir| is_template(dir)) else {
+        return Ok(());
+    };
+
+    let cluster_id = if template.contains("{cluster_id}") {
+        ensure!(
+            config.snapshot.is_none(),
+            "a snapshot doesn't say which cluster it was saved from, {{cluster_id}} needs a live etcd"
+        );
+        let client = config.etcd.connect().await?;
+        let cluster_info =
+            etcd::cluster_info(&client, &config.etcd.endpoints, config.etcd.request_timeout)
+                .await
+                .context("describing etcd cluster")?;
+        Some(cluster_info.cluster_id)
+    } else {
+        None
+    };
+    let expanded = PathBuf::from(expand_template(
+        template,
+        SystemTime::now(),
+        cluster_id.as_deref(),
+    )?);
+    std::fs::create_dir_all(&expanded).context(format!("creating {}", expanded.display()))?;
+    tracing::info!(dir = %expanded.display(), "dumping into expanded output dir");
+
+    config.output = OutputConfig::Dir(expanded);
+    Ok(())
+}
+
+/// Dumps the keys of an etcd cluster with their values decoded by ouger
+pub struct EtcdDumper {
+    pub(crate) config: DumpConfig,
+    progress: DumpProgress,
+    pub(crate) metrics: Metrics,
+    interrupt: DumpInterrupt,
+}
+
+impl EtcdDumper {
+    pub fn new(config: DumpConfig) -> Self {
+        Self {
+            config,
+            progress: DumpProgress::default(),
+            metrics: Metrics::default(),
+            interrupt: DumpInterrupt::default(),
+        }
+    }
+
+    /// Lets interrupt stop the dumps of this dumper, which are otherwise only stopped by dropping
+    /// them
+    pub fn with_interrupt(mut self, interrupt: DumpInterrupt) -> Self {
+        self.interrupt = interrupt;
+        self
+    }
+
+    /// Progress of dump, can be polled from elsewhere while it runs
+    pub fn progress(&self) -> DumpProgress {
+        self.progress.clone()
+    }
+
+    /// Metrics of everything the dumper does, across dumps
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Lists the keys that dump would write, without fetching their values
+    pub async fn list_keys(&self) -> Result<BTreeSet<String>> {
+        let source = self.open_source(self.config.revision).await?;
+        self.list_keys_with(&source, self.config.revision).await
+    }
+
+    /// Connects to etcd, or reads the whole snapshot at revision when dumping one
+    async fn open_source(&self, revision: Option<i64>) -> Result<KeySource> {
+        Ok(match &self.config.snapshot {
+            Some(path) => {
+                tracing::info!(path = %path.display(), "reading snapshot");
+                KeySource::Snapshot(snapshot::read_snapshot(path, revision)?)
+            }
+            None => {
+                let client = self.config.etcd.connect().await?;
+                if let Some(revision) = self.config.revision {
+                    self.check_compaction(&client, revision).await?;
+                }
+                KeySource::Etcd(Box::new(client))
+            }
+        })
+    }
+
+    /// Fails if revision was compacted already, and warns if it's about to be
+    async fn check_compaction(&self, client: &EtcdClient, revision: i64) -> Result<()> {
+        let request_timeout = self.config.etcd.request_timeout;
+        let current = etcd::current_revision(client, request_timeout).await?;
+        ensure!(
+            revision <= current,
+            "revision {} is newer than the current revision {}",
+            revision,
+            current
+        );
+        let oldest = etcd::oldest_revision(client, current, request_timeout).await?;
+        ensure!(
+            revision >= oldest,
+            "revision {} has been compacted, the oldest revision left is {} and the current one {}",
+            revision,
+            oldest,
+            current
+        );
+
+        if revision - oldest < self.config.compaction_margin {
+            tracing::warn!(
+                revision,
+                oldest,
+                current,
+                "revision is close to the last compaction, keys fail to dump if it gets compacted \
+                 before they are fetched"
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn list_keys_with(
+        &self,
+        source: &KeySource,
+        revision: Option<i64>,
+    ) -> Result<BTreeSet<String>> {
+        if let Some(keys) = &self.config.keys {
+            return Ok(keys.clone());
+        }
+
+        let mut keys = match source {
+            KeySource::Etcd(client) => {
+                let mut listed = Vec::new();
+                for prefix in &self.config.prefixes {
+                    tracing::info!(prefix, "listing keys");
+                    let ranges = listing_ranges(prefix, &self.config.exclude_prefixes)
+                        .into_iter()
+                        .filter_map(|range| clamp_range(range, &self.config));
+                    for (start, range_end) in ranges {
+                        let range_keys = list_keys(
+                            client,
+                            start,
+                            range_end,
+                            self.config.page_size,
+                            revision,
+                            &self.config.etcd,
+                        )
+                        .await
+                        .context(format!("listing keys under {}", prefix))?;
+                        listed.push(range_keys);
+                    }
+                }
+                merge_listed(listed)
+            }
+            KeySource::Snapshot(kvs) => kvs
+                .keys()
+                .filter(|key| {
+                    self.config
+                        .prefixes
+                        .iter()
+                        .any(|prefix| key.starts_with(prefix.as_str()))
+                })
+                .cloned()
+                .collect(),
+        };
+        keys.retain(|key| key_matches(key, &self.config));
+        tracing::info!(count = keys.len(), "listed keys");
+
+        if let Some(limit) = self.config.limit {
+            if keys.len() > limit {
+                tracing::info!(limit, "only keeping the first keys");
+                keys = keys.into_iter().take(limit).collect();
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Lists the keys to dump and yields each of them with its value, decoded by ouger unless raw,
+    /// in key order as soon as it's ready. At most concurrency keys are fetched ahead of the
+    /// consumer. The ouger server lives as long as the stream does
+    pub fn stream(&self) -> impl Stream<Item = Result<(String, Vec<u8>)>> + '_ {
+        self.fetched_keys()
+            .map(|fetched| fetched.map(|(key, fetched_key)| (key, fetched_key.value)))
+    }
+
+    /// Like stream, along with the key as found in etcd
+    pub(crate) fn fetched_keys(&self) -> impl Stream<Item = Result<(String, FetchedKey)>> + '_ {
+        stream::once(self.prepare(self.config.revision))
+            .map(|prepared| match prepared {
+                Ok((context, keys)) => fetch_keys(context, keys, self.config.concurrency)
+                    .filter_map(|(key, result)| {
+                        future::ready(match result {
+                            Ok(Fetched::Key(fetched_key)) => Some(Ok((key, fetched_key))),
+                            Ok(_) => None,
+                            Err(err) => Some(Err(err)),
+                        })
+                    })
+                    .left_stream(),
+                Err(err) => stream::once(future::ready(Err(err))).right_stream(),
+            })
+            .flatten()
+    }
+
+    /// Runs the whole dump, writing every key streamed by stream to the output along with the
+    /// manifest. Dropping the returned future kills the ouger server too, but leaves the output
+    /// incomplete, interrupting it writes the manifest of the keys dumped so far first
+    pub async fn dump(&self) -> Result<DumpSummary> {
+        let deadline = self
+            .config
+            .deadline
+            .map(|deadline| Instant::now() + deadline);
+        let (checkpoint, resumed) = match &self.config.checkpoint {
+            Some(path) => {
+                let (checkpoint, resumed) = self.open_checkpoint(path).await?;
+                (Some(checkpoint), resumed)
+            }
+            None => (None, false),
+        };
+        let revision = checkpoint
+            .as_ref()
+            .map_or(self.config.revision, Checkpoint::revision);
+        if self.config.parallel_prefixes {
+            return self.dump_prefixes(revision, deadline).await;
+        }
+
+        let (context, keys) = until(deadline, self.prepare(revision))
+            .await
+            .ok_or_else(|| self.deadline_exceeded())??;
+        let output = if resumed {
+            self.config.output.reopen(self.config.follow_symlinks)?
+        } else {
+            self.config.output.create(self.config.follow_symlinks)?
+        };
+
+        self.dump_to(context, keys, &output, deadline, checkpoint)
+            .await
+    }
+
+    /// Dumps the keys of every distinct prefix into its own dir under the output dir, with one
+    /// context for all of them so that they share etcd, ouger and how many keys are in flight
+    async fn dump_prefixes(
+        &self,
+        revision: Option<i64>,
+        deadline: Option<Instant>,
+    ) -> Result<DumpSummary> {
+        let OutputConfig::Dir(output_dir) = &self.config.output else {
+            bail!("dumping prefixes in parallel needs an output dir");
+        };
+        let prefixes = distinct_prefixes(&self.config.prefixes);
+        let (context, keys) = until(deadline, self.prepare(revision))
+            .await
+            .ok_or_else(|| self.deadline_exceeded())??;
+        self.progress.start(&prefixes, &keys);
+
+        let dumps = prefixes.iter().map(|prefix| {
+            let keys: BTreeSet<String> = keys
+                .range(prefix.clone()..)
+                .take_while(|key| key.starts_with(prefix.as_str()))
+                .cloned()
+                .collect();
+            let context = Arc::clone(&context);
+            async move {
+                let prefix_dir = output_dir.join(paths::key_to_path(prefix, Layout::Flat)?);
+                std::fs::create_dir_all(&prefix_dir)
+                    .context(format!("creating {}", prefix_dir.display()))?;
+                let output = OutputConfig::Dir(prefix_dir).create(self.config.follow_symlinks)?;
+                let summary = self
+                    .dump_to(context, keys, &output, deadline, None)
+                    .instrument(tracing::info_span!("prefix", prefix))
+                    .await
+                    .context(format!("dumping prefix {}", prefix))?;
+                tracing::info!(
+                    prefix,
+                    count = summary.keys,
+                    errors = summary.errors.len(),
+                    "dumped prefix"
+                );
+                anyhow::Ok(summary)
+            }
+        });
+        let summaries = future::try_join_all(dumps).await?;
+
+        let mut summary = DumpSummary::default();
+        for prefix_summary in summaries {
+            summary.keys += prefix_summary.keys;
+            summary.skipped += prefix_summary.skipped;
+            summary.skipped_by_size += prefix_summary.skipped_by_size;
+            summary.skipped_leased += prefix_summary.skipped_leased;
+            summary.skipped_unmodified += prefix_summary.skipped_unmodified;
+            summary.empty += prefix_summary.empty;
+            summary.skipped_undecodable += prefix_summary.skipped_undecodable;
+            summary.bytes_written += prefix_summary.bytes_written;
+            summary.errors.extend(prefix_summary.errors);
+            // Every prefix is dumped from the same source at the same revision
+            summary.revision = summary.revision.or(prefix_summary.revision);
+            summary.cluster_id = summary.cluster_id.or(prefix_summary.cluster_id);
+            summary.interrupted |= prefix_summary.interrupted;
+        }
+        summary.errors.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(summary)
+    }
+
+    /// Loads the checkpoint at path, refusing it if it's not at the revision of the dump, or
+    /// starts one at the current revision. Also returns whether it was loaded
+    async fn open_checkpoint(&self, path: &Path) -> Result<(Checkpoint, bool)> {
+        let cluster_info = match &self.config.snapshot {
+            Some(_) => None,
+            None => {
+                let client = self.config.etcd.connect().await?;
+                Some(
+                    etcd::cluster_info(
+                        &client,
+                        &self.config.etcd.endpoints,
+                        self.config.etcd.request_timeout,
+                    )
+                    .await
+                    .context("describing etcd cluster")?,
+                )
+            }
+        };
+        let current = cluster_info
+            .as_ref()
+            .map(|cluster_info| cluster_info.revision);
+        let cluster_id = cluster_info.map(|cluster_info| cluster_info.cluster_id);
+
+        let Some(checkpoint) = Checkpoint::load(path)? else {
+            let checkpoint =
+                Checkpoint::create(path, self.config.revision.or(current), cluster_id)?;
+            return Ok((checkpoint, false));
+        };
+
+        ensure!(
+            self.config
+                .revision
+                .is_none_or(|revision| checkpoint.revision() == Some(revision)),
+            "checkpoint {} is not at revision {}",
+            path.display(),
+            self.config.revision.unwrap_or_default()
+        );
+        match (checkpoint.revision(), current) {
+            (Some(revision), Some(current)) => ensure!(
+                revision <= current,
+                "checkpoint {} is at revision {}, newer than the current revision {}, is etcd the \
+                 same cluster?",
+                path.display(),
+                revision,
+                current
+            ),
+            (None, Some(_)) => bail!(
+                "checkpoint {} is of the latest revision of a snapshot, not of etcd",
+                path.display()
+            ),
+            (_, None) => {}
+        }
+        // Checkpoints of older versions don't say
+        if let (Some(checkpoint_cluster_id), Some(cluster_id)) =
+            (checkpoint.cluster_id(), &cluster_id)
+        {
+            ensure!(
+                checkpoint_cluster_id == cluster_id,
+                "checkpoint {} is of etcd cluster {}, not of cluster {}",
+                path.display(),
+                checkpoint_cluster_id,
+                cluster_id
+            );
+        }
+
+        tracing::info!(
+            path = %path.display(),
+            revision = checkpoint.revision(),
+            count = checkpoint.done(),
+            "resuming from checkpoint"
+        );
+        Ok((checkpoint, true))
+    }
+
+    fn deadline_exceeded(&self) -> DeadlineExceeded {
+        DeadlineExceeded {
+            deadline: self.config.deadline.unwrap_or_default(),
+            done: self.progress.done(),
+            total: self.progress.total(),
+        }
+    }
+
+    /// Fetches keys with context and writes them to output, followed by the manifest. Fetching
+    /// stops at deadline. Keys done according to checkpoint are skipped, the others recorded in it
+    pub(crate) async fn dump_to(
+        &self,
+        context: Arc<DumpContext>,
+        mut keys: BTreeSet<String>,
+        output: &Output,
+        deadline: Option<Instant>,
+        mut checkpoint: Option<Checkpoint>,
+    ) -> Result<DumpSummary> {
+        let config = &self.config;
+
+        let listed = keys.len();
+        if config.skip_existing {
+            keys.retain(|key| {
+                !output_path(key, config, config.raw).is_ok_and(|path| output.has_file(&path))
+            });
+        }
+        if let Some(checkpoint) = &checkpoint {
+            keys.retain(|key| !checkpoint.is_done(key));
+        }
+        let skipped = listed - keys.len();
+        if skipped > 0 {
+            tracing::info!(count = skipped, "skipping keys already dumped");
+        }
+        let prefixes = match config.keys {
+            Some(_) => &[][..],
+            None => &config.prefixes[..],
+        };
+        // dump_prefixes already started it with the keys of every prefix
+        if !config.parallel_prefixes {
+            self.progress.start(prefixes, &keys);
+        }
+
+        let renamed = if output.is_files() && !config.dedup {
+            assign_paths(&keys, config)?
+        } else {
+            HashMap::new()
+        };
+
+        // A snapshot doesn't say which cluster it was saved from
+        let cluster_info = match &context.source {
+            KeySource::Etcd(client) => {
+                match etcd::cluster_info(
+                    client,
+                    &config.etcd.endpoints,
+                    config.etcd.request_timeout,
+                )
+                .await
+                .context("describing etcd cluster")
+                {
+                    Ok(cluster_info) => Some(cluster_info),
+                    // Only the summary needs it when there are no metadata files to write it to
+                    Err(err) if !output.is_files() => {
+                        tracing::warn!("{:#}", err);
+                        None
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            KeySource::Snapshot(_) => None,
+        };
+        if let (true, KeySource::Etcd(client)) = (output.is_files(), &context.source) {
+            if let Some(cluster_info) = &cluster_info {
+                cluster_info.write(output)?;
+            }
+            if config.include_auth {
+                etcd::auth_info(client, config.etcd.request_timeout)
+                    .await
+                    .context("dumping etcd auth")?
+                    .write(output)?;
+            }
+            if config.include_leases {
+                let leases = etcd::leases(client, config.etcd.request_timeout, config.concurrency)
+                    .await
+                    .context("dumping etcd leases")?;
+                manifest::write_leases(output, &leases)?;
+            }
+        }
+
+        let revision = context.revision.or(cluster_info
+            .as_ref()
+            .map(|cluster_info| cluster_info.revision));
+        let timing_samples = context.timing_samples.clone();
+        let mut write_time = Duration::ZERO;
+        // Tracked to tell which keys the deadline cut off, and that no key got lost on the way
+        let mut pending = keys.clone();
+        let mut fetched_keys = Box::pin(fetch_keys(context, keys, config.concurrency));
+
+        let mut manifest_entries = Vec::new();
+        let mut bytes_written = 0;
+        let mut errors = Vec::new();
+        let mut skipped_by_size = 0;
+        let mut skipped_leased = 0;
+        let mut skipped_unmodified = 0;
+        let mut empty = 0;
+        let mut undecodable = Vec::new();
+        let mut ignored_undecodable = 0;
+        let mut blobs = HashSet::new();
+        let mut interrupt = self.interrupt.0.subscribe();
+        let mut interrupted = false;
+        loop {
+            let next = tokio::select! {
+                biased;
+                Ok(_) = interrupt.wait_for(|interrupted| *interrupted) => {
+                    tracing::warn!(
+                        count = pending.len(),
+                        "interrupted, writing the keys dumped so far without the others"
+                    );
+                    interrupted = true;
+                    break;
+                }
+                next = until(deadline, fetched_keys.next()) => next,
+            };
+            let Some(next) = next else {
+                let err = self.deadline_exceeded();
+                if !config.continue_on_error {
+                    return Err(err.into());
+                }
+                tracing::warn!("{}, writing the keys dumped so far", err);
+                for key in std::mem::take(&mut pending) {
+                    self.record_error(
+                        &mut errors,
+                        manifest::KeyError {
+                            key,
+                            stage: Some(ErrorStage::Deadline),
+                            error: "deadline exceeded before the key was dumped".to_string(),
+                        },
+                    )?;
+                }
+                break;
+            };
+            let Some((key, result)) = next else {
+                break;
+            };
+            self.progress.key_done(&key);
+            pending.remove(&key);
+            match result.and_then(|fetched| match fetched {
+                Fetched::Key(fetched_key) => {
+                    if fetched_key.kv.value().is_empty() {
+                        empty += 1;
+                        if config.warn_on_empty {
+                            tracing::warn!(key, "key has an empty value");
+                        }
+                    }
+                    let write_start = Instant::now();
+                    let written = write_key(
+                        output,
+                        config,
+                        &key,
+                        renamed.get(&key).map(String::as_str),
+                        fetched_key,
+                        &mut blobs,
+                    )
+                    .with_context(|| StageContext {
+                        stage: ErrorStage::Write,
+                        message: format!("writing key {}", key),
+                    });
+                    write_time += write_start.elapsed();
+                    written.map(Some)
+                }
+                Fetched::Missing if config.keys.is_some() => Err(anyhow!("key {} not found", key)),
+                Fetched::Missing => Ok(None),
+                Fetched::OutOfSizeRange => {
+                    skipped_by_size += 1;
+                    Ok(None)
+                }
+                Fetched::Leased => {
+                    skipped_leased += 1;
+                    Ok(None)
+                }
+                Fetched::Unmodified => {
+                    skipped_unmodified += 1;
+                    Ok(None)
+                }
+                Fetched::Empty => {
+                    empty += 1;
+                    if config.warn_on_empty {
+                        tracing::warn!(key, "skipping key with an empty value");
+                    }
+                    Ok(None)
+                }
+                Fetched::Undecodable(error) => {
+                    tracing::warn!(key, "skipping undecodable key: {}", error);
+                    undecodable.push(manifest::KeyError {
+                        key: key.clone(),
+                        stage: None,
+                        error,
+                    });
+                    Ok(None)
+                }
+                Fetched::DecodeErrorIgnored => {
+                    ignored_undecodable += 1;
+                    Ok(None)
+                }
+            }) {
+                Ok(written) => {
+                    if let Some(checkpoint) = &mut checkpoint {
+                        let undecodable = undecodable
+                            .last()
+                            .filter(|error| error.key == key)
+                            .map(|error| error.error.as_str());
+                        checkpoint.record(
+                            &key,
+                            written.as_ref().map(|(manifest_entry, _)| manifest_entry),
+                            undecodable,
+                        )?;
+                        // Keys must not be recorded as done before their files are
+                        if checkpoint.flush_due() {
+                            output.flush()?;
+                            checkpoint.flush()?;
+                        }
+                    }
+                    if let Some((manifest_entry, written)) = written {
+                        self.metrics.key_written(written);
+                        manifest_entries.push(manifest_entry);
+                        bytes_written += written as u64;
+                    }
+                }
+                // Nothing more can be written, whatever the key
+                Err(err) if err.is::<PipeClosed>() || err.is::<EtcdUnavailable>() => {
+                    return Err(err)
+                }
+                Err(err) if config.continue_on_error => {
+                    tracing::warn!("{:#}", err);
+                    self.record_error(
+                        &mut errors,
+                        manifest::KeyError {
+                            key,
+                            // Fetching a key that doesn't exist, or whose task panicked, fails too
+                            stage: Some(error_stage(&err).unwrap_or(ErrorStage::Fetch)),
+                            error: format!("{:#}", err),
+                        },
+                    )?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        // Every key listed comes out of fetch_keys one way or another, one that didn't would leave
+        // a gap in the dump that nothing else notices. Interrupted dumps leave them out on purpose
+        if let (Some(first), false) = (pending.first(), interrupted) {
+            let message = format!(
+                "{} listed keys were neither written, skipped nor failed, starting with {}",
+                pending.len(),
+                first
+            );
+            ensure!(config.continue_on_error, "{}", message);
+            tracing::error!("{}", message);
+            for key in std::mem::take(&mut pending) {
+                self.record_error(
+                    &mut errors,
+                    manifest::KeyError {
+                        key,
+                        stage: Some(ErrorStage::Fetch),
+                        error: "listed but never dumped".to_string(),
+                    },
+                )?;
+            }
+        }
+        // Cancels the fetches the deadline or an interrupt cut off and shuts ouger down unless the
+        // caller holds on to context, before writing the rest of the dump
+        drop(fetched_keys);
+        tracing::info!(count = manifest_entries.len(), "dumped keys");
+        if config.dedup {
+            tracing::info!(
+                count = blobs.len(),
+                keys = manifest_entries.len(),
+                "wrote distinct values"
+            );
+        }
+        if skipped_by_size > 0 {
+            tracing::info!(
+                count = skipped_by_size,
+                "skipped keys outside of the size range"
+            );
+        }
+        if skipped_leased > 0 {
+            tracing::info!(count = skipped_leased, "skipped leased keys");
+        }
+        if skipped_unmodified > 0 {
+            tracing::info!(
+                count = skipped_unmodified,
+                "skipped keys not modified since the minimum revision"
+            );
+        }
+        if ignored_undecodable > 0 {
+            tracing::info!(
+                count = ignored_undecodable,
+                "skipped undecodable keys under the ignored prefixes"
+            );
+        }
+
+        let summary_keys = manifest_entries.len();
+        let skipped_undecodable = undecodable.len();
+        // The manifest of a resumed dump covers the keys of previous runs too
+        if let Some(checkpoint) = &mut checkpoint {
+            let (previous_entries, previous_undecodable) = checkpoint.take_previous();
+            manifest_entries.extend(previous_entries);
+            undecodable.extend(previous_undecodable);
+        }
+
+        errors.sort_by(|a, b| a.key.cmp(&b.key));
+        if output.is_files() {
+            write_manifest(output, config, manifest_entries, undecodable, !interrupted)?;
+
+            if config.continue_on_error {
+                manifest::write_errors(output, &errors)?;
+            }
+
+            if config.prune_empty_dirs {
+                let pruned = output.prune_empty_dirs()?;
+                tracing::info!(count = pruned, "pruned empty dirs");
+            }
+        }
+
+        output.finish()?;
+        // Failed keys, including those the deadline cut off, are dumped when resuming, as are those
+        // an interrupt did
+        if let Some(mut checkpoint) = checkpoint {
+            if errors.is_empty() && !interrupted {
+                checkpoint.remove()?;
+            } else {
+                checkpoint.flush()?;
+            }
+        }
+
+        Ok(DumpSummary {
+            keys: summary_keys,
+            skipped,
+            skipped_by_size,
+            skipped_leased,
+            skipped_unmodified,
+            empty,
+            skipped_undecodable,
+            bytes_written,
+            revision,
+            cluster_id: cluster_info.map(|cluster_info| cluster_info.cluster_id),
+            errors,
+            timings: timing_samples.map(|samples| samples.timings(write_time)),
+            interrupted,
+        })
+    }
+
+    /// Adds error to errors, printing it to stderr right away when streaming errors
+    fn record_error(
+        &self,
+        errors: &mut Vec<manifest::KeyError>,
+        error: manifest::KeyError,
+    ) -> Result<()> {
+        if self.config.stream_errors {
+            let mut line = serde_json::to_vec(&error).context("serializing error")?;
+            line.push(b'\n');
+            // A single write of the whole line under the lock, so that log lines can't interleave
+            std::io::stderr()
+                .lock()
+                .write_all(&line)
+                .context("writing error to stderr")?;
+        }
+        self.progress.key_failed(&error);
+        errors.push(error);
+
+        Ok(())
+    }
+
+    /// Connects to etcd, lists the keys to dump at revision and launches ouger if it's needed
+    pub(crate) async fn prepare(
+        &self,
+        revision: Option<i64>,
+    ) -> Result<(Arc<DumpContext>, BTreeSet<String>)> {
+        let config = &self.config;
+        let connect_start = Instant::now();
+        let source = self.open_source(revision).await?;
+        let list_start = Instant::now();
+        let keys = self.list_keys_with(&source, revision).await?;
+        let timing_samples = config.timings.then(|| {
+            Arc::new(TimingSamples::new(
+                list_start - connect_start,
+                list_start.elapsed(),
+            ))
+        });
+
+        let ouger_pool = if config.raw {
+            None
+        } else {
+            Some(config.ouger.launch().await?)
+        };
+
+        let context = Arc::new(DumpContext {
+            reqclient: config
+                .ouger
+                .client(config.decode_concurrency.unwrap_or(config.concurrency))?,
+            source,
+            json_format: config.json_format,
+            strip_fields: config.strip_fields.clone(),
+            normalize_json: config.normalize_json,
+            redact_prefixes: config.redact_prefixes.clone(),
+            transform: config.transform.clone(),
+            decompress: config.decompress,
+            max_value_output_bytes: config.max_value_output_bytes,
+            on_decode_error: config.on_decode_error,
+            decode_timeout: config.decode_timeout,
+            ignore_decode_error_prefixes: config.ignore_decode_error_prefixes.clone(),
+            skip_ouger_prefixes: config.skip_ouger_prefixes.clone(),
+            value_size_range: config.min_value_bytes.unwrap_or(0)
+                ..=config.max_value_bytes.unwrap_or(usize::MAX),
+            skip_leased: config.skip_leased,
+            min_mod_revision: config.min_mod_revision,
+            skip_empty: config.skip_empty,
+            metrics: self.metrics.clone(),
+            timing_samples,
+            ouger_pool,
+            revision,
+            rate_limiter: config
+                .max_keys_per_sec
+                .map(|rate| RateLimiter::per_second(rate.get())),
+            key_permits: config
+                .parallel_prefixes
+                .then(|| Semaphore::new(config.concurrency)),
+            fetch_permits: config.fetch_concurrency.map(Semaphore::new),
+            decode_permits: config.decode_concurrency.map(Semaphore::new),
+            request_timeout: config.etcd.request_timeout,
+            max_recv_message_size: config.etcd.max_recv_message_size,
+            etcd_retry_policy: config.etcd.retry_policy,
+            etcd_retry_breaker: config
+                .max_retries_total
+                .map(|max_retries| RetryBreaker::new(max_retries, config.max_retries_window)),
+            ouger_retry_policy: config.ouger.retry_policy,
+        });
+
+        Ok((context, keys))
+    }
+}
+
+/// Fetches keys in the background, at most concurrency at a time, yielding them in order
+fn fetch_keys(
+    context: Arc<DumpContext>,
+    keys: BTreeSet<String>,
+    concurrency: usize,
+) -> impl Stream<Item = (String, Result<Fetched>)> {
+    stream::iter(keys)
+        .map(move |key| {
+            let metrics = context.metrics.clone();
+            metrics.fetch_started();
+            let task = tokio::spawn(fetch_key(Arc::clone(&context), key.clone()));
+            let abort_on_drop = AbortOnDrop(task.abort_handle());
+            async move {
+                let _abort_on_drop = abort_on_drop;
+                let result = task
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result)
+                    .with_context(|| format!("dumping key {}", key));
+                metrics.fetch_done();
+                (key, result)
+            }
+        })
+        .buffered(concurrency)
+}
+
+/// The context of errors of a stage of dumping a key, telling error_stage which one failed
+#[derive(Debug)]
+struct StageContext {
+    stage: ErrorStage,
+    message: String,
+}
+
+impl std::fmt::Display for StageContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StageContext {
+    fn new(stage: ErrorStage, message: &str) -> Self {
+        Self {
+            stage,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Where dumping a key failed with err, if it's known
+fn error_stage(err: &anyhow::Error) -> Option<ErrorStage> {
+    err.downcast_ref::<StageContext>()
+        .map(|context| context.stage)
+}
+
+/// Aborts a task when dropped, so that fetches of an abandoned dump don't keep running
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs future to completion, or returns None if deadline passes first
+async fn until<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+pub(crate) fn key_matches(key: &str, config: &DumpConfig) -> bool {
+    if config
+        .from_key
+        .as_ref()
+        .is_some_and(|from_key| key < from_key.as_str())
+        || config
+            .to_key
+            .as_ref()
+            .is_some_and(|to_key| key >= to_key.as_str())
+    {
+        return false;
+    }
+
+    if config
+        .exclude_prefixes
+        .iter()
+        .any(|exclude_prefix| key.starts_with(exclude_prefix.as_str()))
+    {
+        return false;
+    }
+
+    if config.skip_unstripped
+        && config
+            .strip_prefix
+            .as_ref()
+            .is_some_and(|strip_prefix| !key.starts_with(strip_prefix.as_str()))
+    {
+        return false;
+    }
+
+    if config
+        .exclude
+        .as_ref()
+        .is_some_and(|exclude| exclude.is_match(key))
+    {
+        return false;
+    }
+
+    config
+        .include
+        .as_ref()
+        .is_none_or(|include| include.is_match(key))
+}
+
+fn get_options(revision: Option<i64>) -> GetOptions {
+    match revision {
+        Some(revision) => GetOptions::new().with_revision(revision),
+        None => GetOptions::new(),
+    }
+}
+
+/// The end of the range covering every key starting with prefix, as computed by etcd's with_prefix
+fn prefix_range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut range_end = prefix.to_vec();
+    while let Some(last) = range_end.pop() {
+        if last < 0xff {
+            range_end.push(last + 1);
+            return range_end;
+        }
+    }
+
+    // Every key is >= prefix
+    vec![0]
+}
+
+/// The [start, end) ranges covering every key under prefix but none under exclude_prefixes, so
+/// that excluded subtrees are skipped instead of listed and discarded. Ends are in the format of
+/// prefix_range_end
+pub(crate) fn listing_ranges(prefix: &str, exclude_prefixes: &[String]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    // None is the end of the keyspace, which etcd spells as \0
+    let range_end = |prefix: &str| match prefix_range_end(prefix.as_bytes()) {
+        range_end if range_end == [0] => None,
+        range_end => Some(range_end),
+    };
+    let before =
+        |key: &[u8], end: &Option<Vec<u8>>| end.as_ref().is_none_or(|end| key < end.as_slice());
+
+    let mut ranges = vec![(prefix.as_bytes().to_vec(), range_end(prefix))];
+    for exclude_prefix in exclude_prefixes {
+        let exclude_start = exclude_prefix.as_bytes().to_vec();
+        let exclude_end = range_end(exclude_prefix);
+
+        ranges = ranges
+            .into_iter()
+            .flat_map(|(start, end)| {
+                let mut remaining = Vec::new();
+                if start < exclude_start {
+                    let left_end = if before(&exclude_start, &end) {
+                        Some(exclude_start.clone())
+                    } else {
+                        end.clone()
+                    };
+                    remaining.push((start.clone(), left_end));
+                }
+                if let Some(exclude_end) = &exclude_end {
+                    let right_start = start.max(exclude_end.clone());
+                    if before(&right_start, &end) {
+                        remaining.push((right_start, end));
+                    }
+                }
+                remaining
+            })
+            .collect();
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start, end)| (start, end.unwrap_or_else(|| vec![0])))
+        .collect()
+}
+
+/// Narrows a listing range down to config.from_key and config.to_key, None if nothing is left of it
+fn clamp_range(
+    (start, range_end): (Vec<u8>, Vec<u8>),
+    config: &DumpConfig,
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    let start = match &config.from_key {
+        Some(from_key) => start.max(from_key.as_bytes().to_vec()),
+        None => start,
+    };
+    let range_end = match &config.to_key {
+        // \0 is the end of the keyspace
+        Some(to_key) if range_end == [0] || to_key.as_bytes() < range_end.as_slice() => {
+            to_key.as_bytes().to_vec()
+        }
+        _ => range_end,
+    };
+
+    (range_end == [0] || start < range_end).then_some((start, range_end))
+}
+
+/// Lists the keys in [start, range_end) page_size keys at a time, each page starting right after
+/// the last key of the previous one. Without an explicit revision, all pages after the first one
+/// are listed at the revision of the first one so that the listing is consistent
+async fn list_keys(
+    client: &EtcdClient,
+    mut start: Vec<u8>,
+    range_end: Vec<u8>,
+    page_size: i64,
+    revision: Option<i64>,
+    etcd_config: &etcd::EtcdConfig,
+) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut page_revision = revision;
+
+    loop {
+        let etcd_get_options = get_options(page_revision)
+            .with_range(range_end.clone())
+            .with_limit(page_size)
+            .with_keys_only();
+
+        let mut kv_client = etcd::kv_client(client, etcd_config.max_recv_message_size);
+        let get_response = etcd::with_timeout(
+            etcd_config.request_timeout,
+            kv_client.get(start.clone(), Some(etcd_get_options)),
+        )
+        .await
+        .map_err(|err| etcd::revision_error(err, revision))?;
+
+        if page_revision.is_none() {
+            page_revision = get_response.header().map(|header| header.revision());
+        }
+
+        let page = get_response.kvs();
+        for kv in page {
+            keys.push(kv.key_str()?.to_string());
+        }
+        tracing::debug!(
+            count = page.len(),
+            total = keys.len(),
+            "listed page of keys"
+        );
+
+        match page.last() {
+            Some(last) if page.len() as i64 >= page_size => {
+                start = last.key().to_vec();
+                start.push(0);
+            }
+            _ => return Ok(keys),
+        }
+    }
+}
+
+/// The keys of all the listed ranges, each of them once so that it's fetched once. Pages are
+/// listed at the revision of the first one so they can't overlap, but the same key is listed again
+/// when several ranges cover it
+fn merge_listed(listed: impl IntoIterator<Item = Vec<String>>) -> BTreeSet<String> {
+    let mut keys = BTreeSet::new();
+    let mut count = 0;
+    for range_keys in listed {
+        count += range_keys.len();
+        keys.extend(range_keys);
+    }
+    if count > keys.len() {
+        tracing::debug!(
+            count = count - keys.len(),
+            "collapsed keys listed more than once"
+        );
+    }
+
+    keys
+}
+
+/// Where the keys of a dump come from
+pub(crate) enum KeySource {
+    Etcd(Box<EtcdClient>),
+    /// Every key of a snapshot that exists as of the dumped revision
+    Snapshot(BTreeMap<String, KeyValue>),
+}
+
+/// Everything fetch_key needs that is shared between all keys of a dump
+pub(crate) struct DumpContext {
+    reqclient: Client,
+    source: KeySource,
+    json_format: JsonFormat,
+    strip_fields: Vec<String>,
+    normalize_json: bool,
+    redact_prefixes: Vec<String>,
+    transform: Option<String>,
+    decompress: bool,
+    max_value_output_bytes: Option<usize>,
+    on_decode_error: DecodeErrorPolicy,
+    decode_timeout: Option<Duration>,
+    ignore_decode_error_prefixes: Vec<String>,
+    skip_ouger_prefixes: Vec<String>,
+    /// Keys with values of other sizes are not decoded nor written
+    value_size_range: RangeInclusive<usize>,
+    skip_leased: bool,
+    min_mod_revision: Option<i64>,
+    skip_empty: bool,
+    metrics: Metrics,
+    /// Only when timing the dump
+    timing_samples: Option<Arc<TimingSamples>>,
+    /// Shuts ouger down once the last key has been fetched, None when raw
+    ouger_pool: Option<ouger::OugerPool>,
+    revision: Option<i64>,
+    rate_limiter: Option<RateLimiter>,
+    /// Keys wait for one of these before anything else when dumping prefixes in parallel, as
+    /// every prefix fetches concurrency keys ahead on its own
+    key_permits: Option<Semaphore>,
+    /// Keys wait for one of these before getting fetched, and for one of the decode ones before
+    /// getting decoded, so that neither stage takes up all the keys in flight
+    fetch_permits: Option<Semaphore>,
+    decode_permits: Option<Semaphore>,
+    request_timeout: Duration,
+    max_recv_message_size: usize,
+    etcd_retry_policy: retry::RetryPolicy,
+    /// Shared by the gets of all keys
+    etcd_retry_breaker: Option<RetryBreaker>,
+    ouger_retry_policy: retry::RetryPolicy,
+}
+
+/// A key as found in etcd along with its value as it should be written
+pub(crate) struct FetchedKey {
+    pub(crate) kv: KeyValue,
+    pub(crate) value: Vec<u8>,
+    /// The remaining TTL of the lease of the key in seconds, if it has one
+    lease_ttl: Option<i64>,
+    /// Why ouger couldn't decode the value, which is then the raw one
+    decode_error: Option<String>,
+    /// The value is a placeholder
+    redacted: bool,
+    /// The size of the value before it was truncated
+    original_size: Option<usize>,
+    /// The value was inflated from gzip
+    decompressed: bool,
+    /// The value is the raw one, as its prefix is one ouger is skipped for
+    ouger_skipped: bool,
+}
+
+impl FetchedKey {
+    /// A key with its value as stored in etcd, decode_error saying why ouger couldn't decode it if
+    /// it was meant to
+    pub(crate) fn raw(kv: KeyValue, decode_error: Option<String>) -> Self {
+        Self {
+            value: kv.value().to_vec(),
+            kv,
+            lease_ttl: None,
+            decode_error,
+            redacted: false,
+            original_size: None,
+            decompressed: false,
+            ouger_skipped: false,
+        }
+    }
+}
+
+/// What became of a key when fetching it
+pub(crate) enum Fetched {
+    Key(FetchedKey),
+    /// The key no longer exists
+    Missing,
+    /// The value is outside of the requested size range, so it wasn't decoded
+    OutOfSizeRange,
+    /// The key is attached to a lease and leased keys are skipped
+    Leased,
+    /// The key was last modified before the minimum revision
+    Unmodified,
+    /// The value is empty and empty values are skipped
+    Empty,
+    /// ouger couldn't decode the value and undecodable keys are skipped, with the reason why
+    Undecodable(String),
+    /// ouger couldn't decode the value, which is expected under the prefix of the key
+    DecodeErrorIgnored,
+}
+
+/// Fetches and decodes a single key
+#[tracing::instrument(skip(context))]
+async fn fetch_key(context: Arc<DumpContext>, key: String) -> Result<Fetched> {
+    let _key_permit = acquire(&context.key_permits).await;
+    if let Some(rate_limiter) = &context.rate_limiter {
+        rate_limiter.acquire().await;
+    }
+    let fetch_permit = acquire(&context.fetch_permits).await;
+
+    let get_start = Instant::now();
+    let kv = match &context.source {
+        KeySource::Etcd(client) => get_key(&context, client, &key).await?,
+        KeySource::Snapshot(kvs) => kvs.get(&key).cloned(),
+    };
+    if let Some(timing_samples) = &context.timing_samples {
+        timing_samples.get(get_start.elapsed());
+    }
+    let Some(kv) = kv else {
+        tracing::debug!("key disappeared since listing");
+        return Ok(Fetched::Missing);
+    };
+    tracing::trace!(size = kv.value().len(), "fetched key");
+
+    if context
+        .min_mod_revision
+        .is_some_and(|min_mod_revision| kv.mod_revision() < min_mod_revision)
+    {
+        tracing::debug!(mod_revision = kv.mod_revision(), "key not modified since");
+        return Ok(Fetched::Unmodified);
+    }
+
+    if !context.value_size_range.contains(&kv.value().len()) {
+        tracing::debug!(size = kv.value().len(), "value outside of the size range");
+        return Ok(Fetched::OutOfSizeRange);
+    }
+
+    if context.skip_empty && kv.value().is_empty() {
+        return Ok(Fetched::Empty);
+    }
+
+    let lease_ttl = match (kv.lease(), &context.source) {
+        (0, _) => None,
+        _ if context.skip_leased => {
+            tracing::debug!(lease = kv.lease(), "skipping leased key");
+            return Ok(Fetched::Leased);
+        }
+        // The lease may well have expired since the snapshot was saved
+        (_, KeySource::Snapshot(_)) => None,
+        (lease, KeySource::Etcd(client)) => Some(
+            context
+                .etcd_retry_policy
+                .retry(etcd::is_retriable, || {
+                    let mut lease_client = client.lease_client();
+                    let request_timeout = context.request_timeout;
+                    async move {
+                        etcd::with_timeout(request_timeout, lease_client.time_to_live(lease, None))
+                            .await
+                    }
+                })
+                .await
+                .context(StageContext::new(ErrorStage::Fetch, "getting lease TTL"))?
+                .ttl(),
+        ),
+    };
+
+    drop(fetch_permit);
+
+    Ok(match decode_key(&context, kv).await? {
+        Fetched::Key(mut fetched_key) => {
+            fetched_key.lease_ttl = lease_ttl;
+            Fetched::Key(fetched_key)
+        }
+        fetched => fetched,
+    })
+}
+
+/// Gets key from etcd at the revision of the dump, None if it doesn't exist
+async fn get_key(
+    context: &DumpContext,
+    client: &EtcdClient,
+    key: &str,
+) -> Result<Option<KeyValue>> {
+    let mut attempts = 0;
+    let breaker = context.etcd_retry_breaker.as_ref();
+    let get_result = context
+        .etcd_retry_policy
+        .retry(
+            |err| etcd::is_retriable(err) && !breaker.is_some_and(RetryBreaker::retry),
+            || {
+                attempts += 1;
+                if attempts > 1 {
+                    context.metrics.etcd_retry();
+                }
+                let mut kv_client = etcd::kv_client(client, context.max_recv_message_size);
+                let key = key.to_string();
+                let etcd_get_options = get_options(context.revision);
+                let request_timeout = context.request_timeout;
+                async move {
+                    etcd::with_timeout(request_timeout, kv_client.get(key, Some(etcd_get_options)))
+                        .await
+                }
+            },
+        )
+        .await
+        .map_err(|err| etcd::revision_error(err, context.revision));
+    let get_result = match (get_result, breaker) {
+        (Err(err), Some(breaker)) if breaker.tripped() => {
+            return Err(err.context(EtcdUnavailable {
+                max_retries: breaker.max_retries,
+                window: breaker.window,
+            }))
+        }
+        (get_result, _) => {
+            get_result.context(StageContext::new(ErrorStage::Fetch, "during etcd get"))?
+        }
+    };
+
+    Ok(get_result.kvs().first().cloned())
+}
+
+/// Decodes the value of kv with ouger and formats it, unless the dump is raw, then truncates it
+pub(crate) async fn decode_key(context: &DumpContext, kv: KeyValue) -> Result<Fetched> {
+    Ok(match decode_value(context, kv).await? {
+        Fetched::Key(mut fetched_key) => {
+            if let Some(max_size) = context.max_value_output_bytes {
+                if fetched_key.value.len() > max_size {
+                    fetched_key.original_size = Some(fetched_key.value.len());
+                    fetched_key.value = truncate(fetched_key.value, max_size);
+                }
+            }
+            Fetched::Key(fetched_key)
+        }
+        fetched => fetched,
+    })
+}
+
+/// The first max_size bytes of value followed by a marker saying how large it was. Text is cut at
+/// a character boundary, so that it stays text
+fn truncate(mut value: Vec<u8>, max_size: usize) -> Vec<u8> {
+    let original_size = value.len();
+    let mut size = max_size;
+    if let Ok(text) = std::str::from_utf8(&value) {
+        while !text.is_char_boundary(size) {
+            size -= 1;
+        }
+    }
+    value.truncate(size);
+    value.extend_from_slice(format!("[truncated, {} bytes in total]", original_size).as_bytes());
+
+    value
+}
+
+async fn decode_value(context: &DumpContext, kv: KeyValue) -> Result<Fetched> {
+    let raw_key = |kv: KeyValue, decode_error| Fetched::Key(FetchedKey::raw(kv, decode_error));
+
+    if context
+        .redact_prefixes
+        .iter()
+        .any(|prefix| kv.key().starts_with(prefix.as_bytes()))
+    {
+        return Ok(Fetched::Key(FetchedKey {
+            value: context.json_format.apply(redacted_value(kv.value())),
+            kv,
+            lease_ttl: None,
+            decode_error: None,
+            redacted: true,
+            original_size: None,
+            decompressed: false,
+            ouger_skipped: false,
+        }));
+    }
+
+    let Some(ouger_pool) = &context.ouger_pool else {
+        if !context.decompress {
+            return Ok(raw_key(kv, None));
+        }
+        let (value, decompressed) = decompress(kv.value().to_vec());
+        return Ok(Fetched::Key(FetchedKey {
+            value,
+            kv,
+            lease_ttl: None,
+            decode_error: None,
+            redacted: false,
+            original_size: None,
+            decompressed,
+            ouger_skipped: false,
+        }));
+    };
+
+    if context
+        .skip_ouger_prefixes
+        .iter()
+        .any(|prefix| kv.key().starts_with(prefix.as_bytes()))
+    {
+        tracing::debug!("writing value without decoding it");
+        return Ok(Fetched::Key(FetchedKey {
+            value: kv.value().to_vec(),
+            kv,
+            lease_ttl: None,
+            decode_error: None,
+            redacted: false,
+            original_size: None,
+            decompressed: false,
+            ouger_skipped: true,
+        }));
+    }
+
+    let decode_permit = acquire(&context.decode_permits).await;
+    let decode_start = Instant::now();
+    let key = kv.key();
+    let decoded = context
+        .ouger_retry_policy
+        .retry(ouger::is_retriable, || {
+            let decoded = ouger::ouger(
+                &context.reqclient,
+                ouger_pool.url(),
+                ouger::OugerCommand::Decode,
+                kv.value(),
+            );
+            let attempt_start = Instant::now();
+            async move {
+                let Some(decode_timeout) = context.decode_timeout else {
+                    return decoded.await;
+                };
+                tokio::time::timeout(decode_timeout, decoded)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(DecodeTimeout {
+                            key: String::from_utf8_lossy(key).into_owned(),
+                            elapsed: attempt_start.elapsed(),
+                        }
+                        .into())
+                    })
+            }
+        })
+        .await;
+    if let Some(timing_samples) = &context.timing_samples {
+        timing_samples.decode(decode_start.elapsed());
+    }
+    drop(decode_permit);
+    let ignore_decode_error = context
+        .ignore_decode_error_prefixes
+        .iter()
+        .any(|prefix| kv.key().starts_with(prefix.as_bytes()));
+    if let Err(err) = &decoded {
+        if !(ignore_decode_error && is_undecodable(err)) {
+            context.metrics.decode_error();
+        }
+    }
+
+    let mut decompressed = false;
+    let value = match decoded {
+        Ok(value) => {
+            let value = if context.decompress {
+                let (value, inflated) = decompress(value);
+                decompressed = inflated;
+                value
+            } else {
+                value
+            };
+            let mut value = k8s::strip_fields(value, &context.strip_fields);
+            if context.normalize_json {
+                value = sort_json_fields(value);
+            }
+            let value = context.json_format.apply(value);
+            match &context.transform {
+                Some(command) => transform::transform(command, value)
+                    .await
+                    .map_err(|err| (ErrorStage::Transform, err)),
+                None => Ok(value),
+            }
+        }
+        Err(err) => Err((ErrorStage::Decode, err)),
+    };
+
+    Ok(match value {
+        Ok(value) => Fetched::Key(FetchedKey {
+            value,
+            kv,
+            lease_ttl: None,
+            decode_error: None,
+            redacted: false,
+            original_size: None,
+            decompressed,
+            ouger_skipped: false,
+        }),
+        Err((ErrorStage::Decode, err)) if ignore_decode_error && is_undecodable(&err) => {
+            tracing::debug!("ignoring undecodable value: {:#}", err);
+            Fetched::DecodeErrorIgnored
+        }
+        Err((stage, err)) if is_undecodable(&err) || err.is::<transform::TransformError>() => {
+            match context.on_decode_error {
+                DecodeErrorPolicy::Fail => return Err(stage_error(stage, err)),
+                DecodeErrorPolicy::Raw => {
+                    tracing::warn!("writing undecodable value raw: {:#}", err);
+                    raw_key(kv, Some(format!("{:#}", err)))
+                }
+                DecodeErrorPolicy::Skip => Fetched::Undecodable(format!("{:#}", err)),
+            }
+        }
+        Err((stage, err)) => return Err(stage_error(stage, err)),
+    })
+}
+
+/// One of permits, if the stage is limited at all
+async fn acquire(permits: &Option<Semaphore>) -> Option<SemaphorePermit<'_>> {
+    match permits {
+        Some(permits) => Some(permits.acquire().await.expect("permits are never closed")),
+        None => None,
+    }
+}
+
+/// Inflates value if it starts with the gzip magic bytes, returning whether it did. Values that
+/// only look like gzip are kept as they are
+fn decompress(value: Vec<u8>) -> (Vec<u8>, bool) {
+    if !value.starts_with(&[0x1f, 0x8b]) {
+        return (value, false);
+    }
+
+    let mut decompressed = Vec::new();
+    match GzDecoder::new(value.as_slice()).read_to_end(&mut decompressed) {
+        Ok(_) => (decompressed, true),
+        Err(err) => {
+            tracing::warn!("keeping value that looks gzip compressed as-is: {}", err);
+            (value, false)
+        }
+    }
+}
+
+/// Whether ouger was reached but didn't decode the value, as opposed to failing to reach it
+fn is_undecodable(err: &anyhow::Error) -> bool {
+    err.is::<ouger::ConversionError>() || err.is::<DecodeTimeout>()
+}
+
+/// Adds the context of a failure to decode or transform a value to err
+fn stage_error(stage: ErrorStage, err: anyhow::Error) -> anyhow::Error {
+    let message = match stage {
+        ErrorStage::Transform => "transforming value",
+        _ => "decoding value with ouger",
+    };
+    err.context(StageContext::new(stage, message))
+}
+
+/// What is written instead of a redacted value, enough to tell whether it changed
+fn redacted_value(value: &[u8]) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "redacted": true,
+        "sha256": format!("{:x}", Sha256::digest(value)),
+        "size": value.len(),
+    }))
+    .expect("serializing a JSON value can't fail")
+}
+
+/// The extension that tells what value is: JSON, YAML objects and lists, other text or binary
+fn sniff_extension(value: &[u8]) -> &'static str {
+    if serde_json::from_slice::<serde::de::IgnoredAny>(value).is_ok() {
+        return "json";
+    }
+    let Ok(text) = std::str::from_utf8(value) else {
+        return "bin";
+    };
+    // Any text parses as a YAML string
+    match serde_yaml::from_str(text) {
+        Ok(serde_yaml::Value::Mapping(_) | serde_yaml::Value::Sequence(_)) => "yaml",
+        _ => "txt",
+    }
+}
+
+/// Where the value of key goes, relative to the root of the dump. Raw values get a .bin extension
+/// and compressed files a .gz one
+pub(crate) fn output_path(key: &str, config: &DumpConfig, raw: bool) -> Result<String> {
+    Ok(with_extensions(key_path(key, config)?, config, raw))
+}
+
+/// The path of key without extensions, after trimming strip_prefix off it if it starts with it
+fn key_path(key: &str, config: &DumpConfig) -> Result<String> {
+    let key = config
+        .strip_prefix
+        .as_ref()
+        .and_then(|strip_prefix| key.strip_prefix(strip_prefix.as_str()))
+        .unwrap_or(key);
+    paths::key_to_path(key, config.layout)
+}
+
+pub(crate) fn with_extensions(mut relative_path: String, config: &DumpConfig, raw: bool) -> String {
+    if raw {
+        relative_path.push_str(".bin");
+    }
+    if config.compress_files {
+        relative_path.push_str(".gz");
+    }
+
+    relative_path
+}
+
+/// Writes the manifest of the dump, and its checksums if enabled, sorted by key. Keys already come
+/// in order, but metadata files must be reproducible byte for byte no matter how they are fetched
+fn write_manifest(
+    output: &Output,
+    config: &DumpConfig,
+    mut entries: Vec<manifest::ManifestEntry>,
+    mut undecodable: Vec<manifest::KeyError>,
+    complete: bool,
+) -> Result<()> {
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    undecodable.sort_by(|a, b| a.key.cmp(&b.key));
+    if config.checksums {
+        manifest::write_checksums(output, &entries)?;
+    }
+
+    manifest::Manifest {
+        keys: entries,
+        undecodable,
+        strip_prefix: config.strip_prefix.clone(),
+        complete,
+        dedup: config.dedup,
+        compress_files: config.compress_files,
+    }
+    .write(output)
+}
+
+/// The paths, without extensions, of the keys that map to the same path as a key before them, if
+/// the collision policy allows renaming them, and of the keys whose path is the dir of other keys
+fn assign_paths(keys: &BTreeSet<String>, config: &DumpConfig) -> Result<HashMap<String, String>> {
+    let mut keys_by_path: HashMap<String, Vec<&str>> = HashMap::new();
+    for key in keys {
+        // Keys that can't be mapped at all fail on their own when written
+        if let Ok(path) = key_path(key, config) {
+            keys_by_path.entry(path).or_default().push(key);
+        }
+    }
+
+    let mut collisions: Vec<(&String, &Vec<&str>)> = keys_by_path
+        .iter()
+        .filter(|(_, keys)| keys.len() > 1)
+        .collect();
+    collisions.sort();
+
+    let mut renamed = HashMap::new();
+    match config.on_collision {
+        _ if collisions.is_empty() => {}
+        CollisionPolicy::Fail => bail!(
+            "keys map to the same file: {}",
+            collisions
+                .iter()
+                .map(|(path, keys)| format!("{} <- {}", path, keys.join(", ")))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ),
+        CollisionPolicy::Suffix => {
+            let mut taken: HashSet<String> = keys_by_path.keys().cloned().collect();
+            for (path, keys) in collisions {
+                for key in &keys[1..] {
+                    let new_path = (1..)
+                        .map(|number| format!("{}~{}", path, number))
+                        .find(|new_path| !taken.contains(new_path))
+                        .expect("some number is free");
+                    tracing::warn!(key, path = new_path, "key collides with {}", keys[0]);
+                    taken.insert(new_path.clone());
+                    renamed.insert(key.to_string(), new_path);
+                }
+            }
+        }
+    }
+
+    if let Layout::Nested = config.layout {
+        move_values_into_dirs(&keys_by_path, &mut renamed);
+    }
+
+    Ok(renamed)
+}
+
+/// Renames the keys whose path is also the dir of other keys, e.g. /a/b of /a/b/c, to a
+/// paths::DIR_VALUE_FILE_NAME file in that dir, as it can't be both a file and a dir. Which keys
+/// get renamed only depends on the paths of all of them, not on the order they are written in
+fn move_values_into_dirs(
+    keys_by_path: &HashMap<String, Vec<&str>>,
+    renamed: &mut HashMap<String, String>,
+) {
+    let mut paths: BTreeMap<String, &str> = BTreeMap::new();
+    for (path, keys) in keys_by_path {
+        for key in keys {
+            let path = renamed.get(*key).unwrap_or(path);
+            paths.insert(path.clone(), key);
+        }
+    }
+    let is_dir = |paths: &BTreeMap<String, &str>, path: &str| {
+        let dir = format!("{}/", path);
+        paths
+            .range(dir.clone()..)
+            .next()
+            .is_some_and(|(other_path, _)| other_path.starts_with(&dir))
+    };
+
+    let dirs: Vec<(String, &str)> = paths
+        .iter()
+        .filter(|(path, _)| is_dir(&paths, path))
+        .map(|(path, key)| (path.clone(), *key))
+        .collect();
+    for (path, key) in dirs {
+        let new_path = std::iter::once(format!("{}/{}", path, paths::DIR_VALUE_FILE_NAME))
+            .chain(
+                (1..).map(|number| format!("{}/{}~{}", path, paths::DIR_VALUE_FILE_NAME, number)),
+            )
+            .find(|new_path| !paths.contains_key(new_path) && !is_dir(&paths, new_path))
+            .expect("some number is free");
+        tracing::info!(
+            key,
+            path = new_path,
+            "key has keys below it, writing it into its dir"
+        );
+        paths.remove(&path);
+        paths.insert(new_path.clone(), key);
+        renamed.insert(key.to_string(), new_path);
+    }
+}
+
+/// The paths, without extensions, the keys of a dump are written to, kept up to date by watches as
+/// keys are added and removed so that they write them where a dump of all keys would
+pub(crate) struct KeyPaths {
+    /// Every key by its own path
+    keys_by_path: BTreeMap<String, BTreeSet<String>>,
+    /// What assign_paths decided for the keys
+    renamed: HashMap<String, String>,
+    /// The key written to every path after renaming
+    written: BTreeMap<String, String>,
+}
+
+impl KeyPaths {
+    pub(crate) fn new(keys: &BTreeSet<String>, config: &DumpConfig) -> Result<Self> {
+        let mut key_paths = Self {
+            keys_by_path: BTreeMap::new(),
+            renamed: assign_paths(keys, config)?,
+            written: BTreeMap::new(),
+        };
+        for key in keys {
+            // Keys that can't be mapped at all fail on their own when written
+            if let Ok(path) = key_path(key, config) {
+                key_paths
+                    .keys_by_path
+                    .entry(path)
+                    .or_default()
+                    .insert(key.clone());
+            }
+        }
+        key_paths.fill_written();
+
+        Ok(key_paths)
+    }
+
+    fn fill_written(&mut self) {
+        self.written = self
+            .keys_by_path
+            .iter()
+            .flat_map(|(path, keys)| keys.iter().map(move |key| (path, key)))
+            .map(|(path, key)| (self.renamed.get(key).unwrap_or(path).clone(), key.clone()))
+            .collect();
+    }
+
+    /// The path to write key to instead of its own one, if any
+    pub(crate) fn renamed(&self, key: &str) -> Option<&str> {
+        self.renamed.get(key).map(String::as_str)
+    }
+
+    /// The path of the file of key, with extensions
+    pub(crate) fn output_path(&self, key: &str, config: &DumpConfig, raw: bool) -> Result<String> {
+        let path = match self.renamed(key) {
+            Some(renamed) => renamed.to_string(),
+            None => key_path(key, config)?,
+        };
+        Ok(with_extensions(path, config, raw))
+    }
+
+    /// Adds key if it exists and removes it otherwise, returning the other keys that get written
+    /// to another path as a result along with the path they were written to before. The paths of
+    /// all keys are only assigned again when the path of key is taken, is the dir of another path
+    /// or is below another path, so that most changes of a watch don't go through every key
+    pub(crate) fn update(
+        &mut self,
+        key: &str,
+        exists: bool,
+        config: &DumpConfig,
+    ) -> Result<Vec<(String, String)>> {
+        let Ok(path) = key_path(key, config) else {
+            return Ok(Vec::new());
+        };
+        if self
+            .keys_by_path
+            .get(&path)
+            .is_some_and(|keys| keys.contains(key))
+            == exists
+        {
+            return Ok(Vec::new());
+        }
+
+        let entangled = |key_paths: &Self| {
+            key_paths.renamed.contains_key(key)
+                || key_paths
+                    .keys_by_path
+                    .get(&path)
+                    .is_some_and(|keys| keys.len() > 1)
+                || key_paths.is_entangled(&path, key)
+        };
+        let reassign = if exists {
+            self.keys_by_path
+                .entry(path.clone())
+                .or_default()
+                .insert(key.to_string());
+            entangled(self)
+        } else {
+            let reassign = entangled(self);
+            self.remove_key(&path, key);
+            reassign
+        };
+        if !reassign {
+            if exists {
+                self.written.insert(path, key.to_string());
+            } else {
+                self.written.remove(&path);
+            }
+            return Ok(Vec::new());
+        }
+
+        let keys: BTreeSet<String> = self.keys_by_path.values().flatten().cloned().collect();
+        let renamed = match assign_paths(&keys, config) {
+            Ok(renamed) => renamed,
+            Err(err) => {
+                if exists {
+                    self.remove_key(&path, key);
+                } else {
+                    self.keys_by_path
+                        .entry(path)
+                        .or_default()
+                        .insert(key.to_string());
+                }
+                return Err(err);
+            }
+        };
+
+        // Only renamed keys, before or after, can change paths
+        let mut moved = Vec::new();
+        let renamed_keys: BTreeSet<&String> = self.renamed.keys().chain(renamed.keys()).collect();
+        for other_key in renamed_keys {
+            let old_path = self.renamed.get(other_key);
+            if other_key == key || !keys.contains(other_key) || old_path == renamed.get(other_key) {
+                continue;
+            }
+            let old_path = match old_path {
+                Some(old_path) => old_path.clone(),
+                None => key_path(other_key, config)?,
+            };
+            moved.push((other_key.clone(), old_path));
+        }
+        self.renamed = renamed;
+        self.fill_written();
+
+        Ok(moved)
+    }
+
+    fn remove_key(&mut self, path: &str, key: &str) {
+        if let Some(keys) = self.keys_by_path.get_mut(path) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys_by_path.remove(path);
+            }
+        }
+    }
+
+    /// Whether another key than key is written to path, anything is below it, or one of its dirs
+    /// is the path of a key, be it its own path or the one it's written to
+    fn is_entangled(&self, path: &str, key: &str) -> bool {
+        let dir = format!("{}/", path);
+        self.written
+            .get(path)
+            .is_some_and(|written_key| written_key != key)
+            || self
+                .written
+                .range(dir.clone()..)
+                .next()
+                .is_some_and(|(other_path, _)| other_path.starts_with(&dir))
+            || self
+                .keys_by_path
+                .range(dir.clone()..)
+                .next()
+                .is_some_and(|(other_path, _)| other_path.starts_with(&dir))
+            || path.match_indices('/').any(|(index, _)| {
+                self.written.contains_key(&path[..index])
+                    || self.keys_by_path.contains_key(&path[..index])
+            })
+    }
+}
+
+/// Where the value of the key with path, without extensions, goes as stored in etcd when including
+/// raw values next to the decoded ones
+fn raw_copy_path(path: &str, config: &DumpConfig) -> String {
+    let mut relative_path = format!("{}{}", path, manifest::RAW_COPY_SUFFIX);
+    if config.compress_files {
+        relative_path.push_str(".gz");
+    }
+
+    relative_path
+}
+
+/// Writes a fetched key to the output, returning its manifest entry and the amount of bytes
+/// written for it. renamed is the path, without extensions, to write the key to instead of its own.
+/// blobs has the paths of the values already written when deduplicating, which aren't again
+pub(crate) fn write_key(
+    output: &Output,
+    config: &DumpConfig,
+    key: &str,
+    renamed: Option<&str>,
+    fetched_key: FetchedKey,
+    blobs: &mut HashSet<String>,
+) -> Result<(manifest::ManifestEntry, usize)> {
+    if !output.is_files() {
+        tracing::debug!(key, size = fetched_key.value.len(), "writing key");
+        output.write_line(key, &fetched_key.kv, &fetched_key.value)?;
+
+        return Ok((
+            manifest::ManifestEntry::new(
+                key.to_string(),
+                String::new(),
+                &fetched_key.kv,
+                &fetched_key.value,
+            ),
+            fetched_key.value.len(),
+        ));
+    }
+
+    // Deduplicated values don't go to the path of their key, so keys that can't be mapped to one
+    // are fine
+    let own_path = if config.dedup {
+        String::new()
+    } else {
+        key_path(key, config)?
+    };
+    let path = match renamed {
+        Some(renamed) => renamed.to_string(),
+        None => own_path.clone(),
+    };
+    let raw = config.raw || fetched_key.decode_error.is_some();
+    let extension = (config.add_extensions && !raw).then(|| sniff_extension(&fetched_key.value));
+    let relative_path = if config.dedup {
+        with_extensions(
+            format!(
+                "{}/{:x}",
+                manifest::BLOBS_DIR_NAME,
+                Sha256::digest(&fetched_key.value)
+            ),
+            config,
+            false,
+        )
+    } else {
+        with_extensions(
+            match extension {
+                Some(extension) => format!("{}.{}", path, extension),
+                None => path.clone(),
+            },
+            config,
+            raw,
+        )
+    };
+
+    let compressed;
+    let written_value = if config.compress_files {
+        compressed = compress(&fetched_key.value)?;
+        &compressed
+    } else {
+        &fetched_key.value
+    };
+
+    let new_file = !config.dedup || blobs.insert(relative_path.clone());
+    tracing::debug!(
+        key,
+        path = relative_path,
+        size = written_value.len(),
+        new_file,
+        "writing key"
+    );
+    if new_file {
+        output.write(&relative_path, written_value)?;
+    }
+    if config.sidecar_meta {
+        manifest::Sidecar::new(&fetched_key.kv).write(output, &relative_path)?;
+    }
+
+    let mut manifest_entry = manifest::ManifestEntry::new(
+        key.to_string(),
+        relative_path,
+        &fetched_key.kv,
+        &fetched_key.value,
+    );
+    // Values that couldn't be decoded or skipped ouger already are raw, and redacted ones must not
+    // be written
+    if config.include_raw
+        && !config.raw
+        && fetched_key.decode_error.is_none()
+        && !fetched_key.ouger_skipped
+        && !fetched_key.redacted
+    {
+        let raw_path = raw_copy_path(&path, config);
+        let raw_value = if config.compress_files {
+            compress(fetched_key.kv.value())?
+        } else {
+            fetched_key.kv.value().to_vec()
+        };
+        output.write(&raw_path, &raw_value)?;
+        manifest_entry.raw_path = Some(raw_path);
+        manifest_entry.decoded_size = Some(fetched_key.value.len());
+    }
+    manifest_entry.lease_ttl = fetched_key.lease_ttl;
+    manifest_entry.decode_error = fetched_key.decode_error;
+    manifest_entry.redacted = fetched_key.redacted;
+    manifest_entry.original_size = fetched_key.original_size;
+    manifest_entry.decompressed = fetched_key.decompressed;
+    manifest_entry.ouger_skipped = fetched_key.ouger_skipped;
+    manifest_entry.extension = extension.map(str::to_string);
+    manifest_entry.value_in_dir = path
+        .rsplit_once('/')
+        .is_some_and(|(dir, _)| dir == own_path);
+    manifest_entry.renamed = !config.dedup
+        && !manifest_entry.value_in_dir
+        && paths::path_to_key(&path).ok().as_deref() != Some(key);
+    if config.checksums {
+        manifest_entry.sha256 = Some(format!("{:x}", Sha256::digest(written_value)));
+    }
+
+    Ok((
+        manifest_entry,
+        if new_file { written_value.len() } else { 0 },
+    ))
+}
+
+fn compress(value: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(value)?;
+    encoder.finish().context("compressing value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restore;
+    use etcd_client::proto::PbKeyValue;
+
+    fn keys(keys: &[&str]) -> BTreeSet<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    /// A new empty dir for this test, removed first if a previous run left it behind
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("etcddump-dump-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn fetched_key(key: &str, value: &str, mod_revision: i64) -> FetchedKey {
+        let kv = PbKeyValue {
+            key: key.as_bytes().to_vec(),
+            create_revision: mod_revision,
+            mod_revision,
+            version: 1,
+            value: value.as_bytes().to_vec(),
+            lease: 0,
+        };
+        FetchedKey {
+            kv: <&KeyValue>::from(&kv).clone(),
+            value: value.as_bytes().to_vec(),
+            lease_ttl: None,
+            decode_error: None,
+            redacted: false,
+            original_size: None,
+            decompressed: false,
+            ouger_skipped: false,
+        }
+    }
+
+    /// Writes the keys of data in the given order, then the manifest, returning the bytes of the
+    /// manifest and checksums files
+    fn dump_in_order(name: &str, data: &[(&str, &str)], order: &[usize]) -> (Vec<u8>, Vec<u8>) {
+        let dir = test_dir(name);
+        let config = DumpConfig {
+            output: OutputConfig::Dir(dir.clone()),
+            checksums: true,
+            ..Default::default()
+        };
+        let output = config.output.create(false).unwrap();
+
+        let mut entries = Vec::new();
+        let mut undecodable = Vec::new();
+        for &index in order {
+            let (key, value) = data[index];
+            let (entry, _) = write_key(
+                &output,
+                &config,
+                key,
+                None,
+                fetched_key(key, value, index as i64 + 2),
+                &mut HashSet::new(),
+            )
+            .unwrap();
+            entries.push(entry);
+            undecodable.push(manifest::KeyError {
+                key: key.to_string(),
+                stage: None,
+                error: "not really".to_string(),
+            });
+        }
+        write_manifest(&output, &config, entries, undecodable, true).unwrap();
+
+        let files = (
+            std::fs::read(dir.join(manifest::MANIFEST_FILE_NAME)).unwrap(),
+            std::fs::read(dir.join(manifest::CHECKSUMS_FILE_NAME)).unwrap(),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        files
+    }
+
+    #[test]
+    fn manifest_is_the_same_whatever_the_order_keys_are_written_in() {
+        let data = [
+            ("/registry/pods/default/a", "{\"kind\":\"Pod\"}"),
+            ("/registry/pods/default/b", "{\"kind\":\"Pod\"}"),
+            ("/registry/services/default/c", "{\"kind\":\"Service\"}"),
+            ("/registry/configmaps/default/d", "{\"kind\":\"ConfigMap\"}"),
+        ];
+
+        let in_order = dump_in_order("in-order", &data, &[0, 1, 2, 3]);
+        let shuffled = dump_in_order("shuffled", &data, &[2, 0, 3, 1]);
+        assert_eq!(in_order, shuffled);
+    }
+
+    #[test]
+    fn keys_listed_more_than_once_are_merged() {
+        let pages = vec![
+            vec!["/a".to_string(), "/b".to_string(), "/c".to_string()],
+            vec!["/c".to_string(), "/d".to_string()],
+            Vec::new(),
+            vec!["/a".to_string(), "/d".to_string(), "/e".to_string()],
+        ];
+
+        let mut processed: HashMap<String, usize> = HashMap::new();
+        for key in merge_listed(pages) {
+            *processed.entry(key).or_default() += 1;
+        }
+        assert_eq!(
+            processed,
+            HashMap::from(["/a", "/b", "/c", "/d", "/e"].map(|key| (key.to_string(), 1)))
+        );
+    }
+
+    #[test]
+    fn colliding_keys_fail_naming_both() {
+        let config = DumpConfig {
+            on_collision: CollisionPolicy::Fail,
+            ..Default::default()
+        };
+
+        let err = assign_paths(&keys(&["/registry/a", "//registry/a"]), &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "keys map to the same file: registry/a <- //registry/a, /registry/a"
+        );
+    }
+
+    #[test]
+    fn colliding_keys_get_a_suffix() {
+        let config = DumpConfig {
+            on_collision: CollisionPolicy::Suffix,
+            ..Default::default()
+        };
+
+        let renamed = assign_paths(&keys(&["/registry/a", "//registry/a"]), &config).unwrap();
+        assert_eq!(
+            renamed,
+            HashMap::from([("/registry/a".to_string(), "registry/a~1".to_string())])
+        );
+    }
+
+    /// Dumps data to the output dir of config like a dump of etcd would, paths and manifest
+    /// included
+    fn dump_to_dir(config: &DumpConfig, data: &[(&str, &str)]) {
+        let output = config.output.create(false).unwrap();
+        let keys: BTreeSet<String> = data.iter().map(|(key, _)| key.to_string()).collect();
+        let renamed = assign_paths(&keys, config).unwrap();
+
+        let mut entries = Vec::new();
+        for (mod_revision, (key, value)) in data.iter().enumerate() {
+            let (entry, _) = write_key(
+                &output,
+                config,
+                key,
+                renamed.get(*key).map(String::as_str),
+                fetched_key(key, value, mod_revision as i64 + 2),
+                &mut HashSet::new(),
+            )
+            .unwrap();
+            entries.push(entry);
+        }
+        write_manifest(&output, config, entries, Vec::new(), true).unwrap();
+    }
+
+    #[test]
+    fn values_go_into_their_dir_whatever_the_order_keys_come_in() {
+        let config = DumpConfig::default();
+        let all_keys = keys(&["/a/b", "/a/b/c"]);
+        assert_eq!(
+            assign_paths(&all_keys, &config).unwrap(),
+            HashMap::from([("/a/b".to_string(), "a/b/__value".to_string())])
+        );
+
+        let mut key_paths = KeyPaths::new(&BTreeSet::new(), &config).unwrap();
+        assert!(key_paths.update("/a/b", true, &config).unwrap().is_empty());
+        assert_eq!(key_paths.renamed("/a/b"), None);
+        assert_eq!(
+            key_paths.update("/a/b/c", true, &config).unwrap(),
+            vec![("/a/b".to_string(), "a/b".to_string())]
+        );
+        assert_eq!(key_paths.renamed("/a/b"), Some("a/b/__value"));
+
+        let mut key_paths = KeyPaths::new(&BTreeSet::new(), &config).unwrap();
+        assert!(key_paths
+            .update("/a/b/c", true, &config)
+            .unwrap()
+            .is_empty());
+        assert!(key_paths.update("/a/b", true, &config).unwrap().is_empty());
+        assert_eq!(key_paths.renamed("/a/b"), Some("a/b/__value"));
+
+        // Both orders end up where a dump of both keys writes them
+        let dumped = KeyPaths::new(&all_keys, &config).unwrap();
+        assert_eq!(dumped.renamed("/a/b"), Some("a/b/__value"));
+        assert_eq!(dumped.renamed("/a/b/c"), None);
+
+        assert_eq!(
+            key_paths.update("/a/b/c", false, &config).unwrap(),
+            vec![("/a/b".to_string(), "a/b/__value".to_string())]
+        );
+        assert_eq!(key_paths.renamed("/a/b"), None);
+    }
+
+    #[test]
+    fn unrelated_keys_keep_their_paths() {
+        let config = DumpConfig {
+            on_collision: CollisionPolicy::Suffix,
+            ..Default::default()
+        };
+        let mut key_paths = KeyPaths::new(&keys(&["/a", "/b/c"]), &config).unwrap();
+        assert!(key_paths.update("/d", true, &config).unwrap().is_empty());
+        assert!(key_paths.update("/a", false, &config).unwrap().is_empty());
+
+        // Sorts before /b/c, which has to make room for it
+        assert_eq!(
+            key_paths.update("//b/c", true, &config).unwrap(),
+            vec![("/b/c".to_string(), "b/c".to_string())]
+        );
+        assert_eq!(key_paths.renamed("/b/c"), Some("b/c~1"));
+        assert_eq!(key_paths.renamed("//b/c"), None);
+    }
+
+    #[test]
+    fn suffixed_files_map_back_to_their_keys() {
+        let dir = test_dir("suffixed");
+        let config = DumpConfig {
+            output: OutputConfig::Dir(dir.clone()),
+            on_collision: CollisionPolicy::Suffix,
+            ..Default::default()
+        };
+        dump_to_dir(&config, &[("/registry/a", "{}"), ("//registry/a", "{}")]);
+
+        let mut files = restore::dump_files(&dir, false).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                ("//registry/a".to_string(), dir.join("registry/a")),
+                ("/registry/a".to_string(), dir.join("registry/a~1")),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn redacted_and_truncated_keys_are_not_restored() {
+        let dir = test_dir("incomplete");
+        let config = DumpConfig {
+            output: OutputConfig::Dir(dir.clone()),
+            ..Default::default()
+        };
+        let output = config.output.create(false).unwrap();
+        let mut entries = Vec::new();
+        for (mod_revision, key) in ["/x/redacted", "/x/truncated", "/x/whole"]
+            .iter()
+            .enumerate()
+        {
+            let mut fetched_key = fetched_key(key, "{}", mod_revision as i64 + 2);
+            fetched_key.redacted = *key == "/x/redacted";
+            if *key == "/x/truncated" {
+                fetched_key.original_size = Some(4096);
+            }
+            let (entry, _) = write_key(
+                &output,
+                &config,
+                key,
+                None,
+                fetched_key,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+            entries.push(entry);
+        }
+        write_manifest(&output, &config, entries, Vec::new(), true).unwrap();
+
+        assert_eq!(restore::dump_files(&dir, false).unwrap().len(), 3);
+        assert_eq!(
+            restore::restorable_files(&dir, false).unwrap(),
+            vec![("/x/whole".to_string(), dir.join("x/whole"))]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_compressed_dumps_are_inflated() {
+        let data = [("/x/foo.gz", "{\"a\":1}"), ("/x/bar", "{\"b\":2}")];
+        for compress_files in [false, true] {
+            let dir = test_dir(&format!("compressed-{}", compress_files));
+            let config = DumpConfig {
+                output: OutputConfig::Dir(dir.clone()),
+                compress_files,
+                ..Default::default()
+            };
+            dump_to_dir(&config, &data);
+
+            let compressed = restore::is_compressed(&dir).unwrap();
+            assert_eq!(compressed, compress_files);
+            let mut files = restore::dump_files(&dir, false).unwrap();
+            files.sort();
+            let values: Vec<(String, Vec<u8>)> = files
+                .into_iter()
+                .map(|(key, path)| (key, restore::read_dump_file(&path, compressed).unwrap()))
+                .collect();
+            assert_eq!(
+                values,
+                vec![
+                    ("/x/bar".to_string(), b"{\"b\":2}".to_vec()),
+                    ("/x/foo.gz".to_string(), b"{\"a\":1}".to_vec()),
+                ]
+            );
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,634 @@
+use crate::manifest::{AuthInfo, ClusterInfo, ClusterMember, LeaseInfo};
+use crate::retry::RetryPolicy;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::prelude::*;
+use etcd_client::{
+    Client as EtcdClient, ConnectOptions, GetOptions, KvClient, LeaseTimeToLiveOptions,
+    OpenSslClientConfig,
+};
+use futures::{StreamExt, TryStreamExt};
+use openssl::ssl::SslVerifyMode;
+use openssl::x509::X509Ref;
+use reqwest::Url;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+
+/// How to reach and authenticate to etcd
+pub struct EtcdConfig {
+    /// Members of the cluster, the client fails over between them
+    pub endpoints: Vec<String>,
+    pub tls: Option<TlsConfig>,
+    pub credentials: Option<Credentials>,
+    /// An HTTP proxy to tunnel the connections to the TCP endpoints through with CONNECT
+    pub grpc_proxy: Option<Url>,
+    /// Applied to every request sent to etcd
+    pub retry_policy: RetryPolicy,
+    pub connect_timeout: Duration,
+    /// How long a single request may take before it fails, retries get their own timeout
+    pub request_timeout: Duration,
+    /// The largest response accepted from etcd, listing pages and values included
+    pub max_recv_message_size: usize,
+    /// How often to ping etcd over connections with requests in flight, so that dead connections
+    /// are noticed and idle links aren't dropped
+    pub keep_alive_interval: Duration,
+    /// How long to wait for a ping to be answered before dropping the connection
+    pub keep_alive_timeout: Duration,
+    /// Reach unix socket endpoints, and endpoints behind grpc_proxy, through a TCP listener on
+    /// localhost, which any local user can connect to. Connecting to them fails otherwise
+    pub allow_local_forwarding: bool,
+}
+
+/// What etcd's own client accepts, i.e. practically any response
+pub const DEFAULT_MAX_RECV_MESSAGE_SIZE: usize = i32::MAX as usize;
+
+/// No endpoints and no retries, with the timeouts of the command line defaults
+impl Default for EtcdConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            tls: None,
+            credentials: None,
+            grpc_proxy: None,
+            retry_policy: RetryPolicy::none(),
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_recv_message_size: DEFAULT_MAX_RECV_MESSAGE_SIZE,
+            keep_alive_interval: Duration::from_secs(10),
+            keep_alive_timeout: Duration::from_secs(20),
+            allow_local_forwarding: false,
+        }
+    }
+}
+
+/// PEM files to secure the connection to etcd with
+pub struct TlsConfig {
+    pub cacert: Option<PathBuf>,
+    pub client_cert_and_key: Option<(PathBuf, PathBuf)>,
+    pub insecure_skip_tls_verify: bool,
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl TlsConfig {
+    /// The openssl config of the connections. Those to proxied_hosts are made to the local end of
+    /// a tunnel, so their certificates are checked against the endpoint hosts instead
+    fn openssl_client_config(&self, proxied_hosts: &[String]) -> Result<OpenSslClientConfig> {
+        let mut config = OpenSslClientConfig::default();
+
+        if let Some(cacert) = &self.cacert {
+            let cacert_pem =
+                std::fs::read(cacert).context(format!("reading CA cert {}", cacert.display()))?;
+            config = config.ca_cert_pem(&cacert_pem);
+        }
+
+        if let Some((cert, key)) = &self.client_cert_and_key {
+            let cert_pem =
+                std::fs::read(cert).context(format!("reading client cert {}", cert.display()))?;
+            let key_pem =
+                std::fs::read(key).context(format!("reading client key {}", key.display()))?;
+            config = config.client_cert_pem_and_key(&cert_pem, &key_pem);
+        }
+
+        if self.insecure_skip_tls_verify {
+            config = config.manually(|builder| {
+                builder.set_verify(SslVerifyMode::NONE);
+                Ok(())
+            });
+        } else if !proxied_hosts.is_empty() {
+            let proxied_hosts = proxied_hosts.to_vec();
+            config = config.manually(move |builder| {
+                builder.set_verify_callback(SslVerifyMode::PEER, move |verified, context| {
+                    let name_mismatch = [HOSTNAME_MISMATCH, IP_ADDRESS_MISMATCH]
+                        .contains(&context.error().as_raw());
+                    verified
+                        || (name_mismatch
+                            && context.error_depth() == 0
+                            && context.current_cert().is_some_and(|cert| {
+                                proxied_hosts
+                                    .iter()
+                                    .any(|host| cert_matches_host(cert, host))
+                            }))
+                });
+                Ok(())
+            });
+        }
+
+        Ok(config)
+    }
+}
+
+/// Context of every error connecting to etcd, which can be downcast to it to tell those apart from
+/// other failures, as failing to authenticate, to reach a socket or to get through a proxy aren't
+/// etcd_client errors
+#[derive(Debug)]
+pub struct EtcdConnect;
+
+impl std::fmt::Display for EtcdConnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connecting to etcd")
+    }
+}
+
+impl EtcdConfig {
+    pub(crate) async fn connect(&self) -> Result<EtcdClient> {
+        tracing::info!(endpoints = ?self.endpoints, "connecting to etcd");
+        connect(
+            &self.endpoints,
+            self.tls.as_ref(),
+            self.credentials.as_ref(),
+            self.grpc_proxy.as_ref(),
+            self.connect_timeout,
+            (self.keep_alive_interval, self.keep_alive_timeout),
+            self.allow_local_forwarding,
+        )
+        .await
+        .context(EtcdConnect)
+    }
+}
+
+/// The kv client of client, accepting responses of up to max_recv_message_size bytes instead of
+/// the 4MiB gRPC default
+pub(crate) fn kv_client(client: &EtcdClient, max_recv_message_size: usize) -> KvClient {
+    client
+        .kv_client()
+        .max_decoding_message_size(max_recv_message_size)
+}
+
+async fn connect(
+    endpoints: &[String],
+    tls: Option<&TlsConfig>,
+    credentials: Option<&Credentials>,
+    grpc_proxy: Option<&Url>,
+    connect_timeout: Duration,
+    (keep_alive_interval, keep_alive_timeout): (Duration, Duration),
+    allow_local_forwarding: bool,
+) -> Result<EtcdClient> {
+    let mut connect_options = ConnectOptions::new()
+        .with_connect_timeout(connect_timeout)
+        .with_keep_alive(keep_alive_interval, keep_alive_timeout);
+
+    let proxied_hosts: Vec<String> = match grpc_proxy {
+        Some(_) => endpoints
+            .iter()
+            .filter(|endpoint| unix_socket_path(endpoint).is_none())
+            .map(|endpoint| endpoint_host(endpoint).to_string())
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(tls) = tls {
+        connect_options = connect_options.with_openssl_tls(
+            tls.openssl_client_config(&proxied_hosts)
+                .context("building TLS config")?,
+        );
+    }
+
+    if let Some(credentials) = credentials {
+        connect_options = connect_options.with_user(&credentials.username, &credentials.password);
+    }
+
+    let mut tcp_endpoints = Vec::new();
+    for endpoint in endpoints {
+        tcp_endpoints.push(match unix_socket_path(endpoint) {
+            Some(path) => {
+                ensure!(
+                    allow_local_forwarding,
+                    "etcd socket {} can only be reached by forwarding a local TCP port to it, \
+                     which local forwarding isn't allowed for",
+                    path
+                );
+                proxy_unix_socket(path)
+                    .await
+                    .context(format!("connecting to etcd socket {}", path))?
+            }
+            None => match grpc_proxy {
+                Some(grpc_proxy) => {
+                    ensure!(
+                        allow_local_forwarding,
+                        "etcd endpoint {} can only be reached through the proxy by forwarding a \
+                         local TCP port to it, which local forwarding isn't allowed for",
+                        endpoint
+                    );
+                    tunnel_through_proxy(grpc_proxy, endpoint)
+                        .await
+                        .context(format!(
+                            "connecting to etcd endpoint {} through proxy {}:{}",
+                            endpoint,
+                            grpc_proxy.host_str().unwrap_or_default(),
+                            grpc_proxy.port_or_known_default().unwrap_or_default()
+                        ))?
+                }
+                None => endpoint.clone(),
+            },
+        });
+    }
+
+    match (
+        EtcdClient::connect(tcp_endpoints, Some(connect_options)).await,
+        credentials,
+    ) {
+        // The only RPC issued while connecting is the authentication one
+        (Err(etcd_client::Error::GRpcStatus(status)), Some(credentials)) => Err(anyhow!(
+            "authenticating to etcd as user {}: {}",
+            credentials.username,
+            status.message()
+        )),
+        (client, _) => Ok(client?),
+    }
+}
+
+/// The socket path of unix:// (or unix:, like etcd itself accepts) endpoints
+pub fn unix_socket_path(endpoint: &str) -> Option<&str> {
+    endpoint
+        .strip_prefix("unix://")
+        .or_else(|| endpoint.strip_prefix("unix:"))
+}
+
+/// etcd-client can only reach etcd over TCP, so unix sockets are reached through a TCP listener on
+/// localhost that forwards every connection it accepts to the socket. Returns the endpoint of that
+/// listener, which keeps running for as long as the runtime does. Any local user can connect to it,
+/// and through it to the socket whatever its permissions, which is why it takes an opt-in
+async fn proxy_unix_socket(path: &str) -> Result<String> {
+    // Fail early rather than on every forwarded connection
+    UnixStream::connect(path).await?;
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let endpoint = listener.local_addr()?.to_string();
+    tracing::debug!(path, endpoint, "forwarding unix socket");
+
+    let path = path.to_string();
+    tokio::spawn(async move {
+        loop {
+            let mut tcp_stream = match listener.accept().await {
+                Ok((tcp_stream, _)) => tcp_stream,
+                Err(err) => {
+                    tracing::warn!("Could not accept etcd connection: {}", err);
+                    continue;
+                }
+            };
+
+            let path = path.clone();
+            tokio::spawn(async move {
+                match UnixStream::connect(&path).await {
+                    Ok(mut unix_stream) => {
+                        let _ =
+                            tokio::io::copy_bidirectional(&mut tcp_stream, &mut unix_stream).await;
+                    }
+                    Err(err) => tracing::warn!(path, "Could not connect to etcd socket: {}", err),
+                }
+            });
+        }
+    });
+
+    Ok(endpoint)
+}
+
+/// The X509_V_ERR codes of certificates that are valid, but not for the host connected to
+const HOSTNAME_MISMATCH: i32 = 62;
+const IP_ADDRESS_MISMATCH: i32 = 64;
+
+/// The host of a host:port endpoint, with an optional scheme and without the brackets of IPv6
+/// addresses
+fn endpoint_host(endpoint: &str) -> &str {
+    let authority = endpoint_authority(endpoint);
+    let host = authority
+        .rsplit_once(':')
+        .map_or(authority, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// The host:port of an endpoint, without its scheme
+fn endpoint_authority(endpoint: &str) -> &str {
+    endpoint
+        .strip_prefix("http://")
+        .or_else(|| endpoint.strip_prefix("https://"))
+        .unwrap_or(endpoint)
+}
+
+/// Whether the subject alternative names of cert cover host, with a wildcard standing for the
+/// first label of a DNS name
+fn cert_matches_host(cert: &X509Ref, host: &str) -> bool {
+    let host_ip: Option<IpAddr> = host.parse().ok();
+    cert.subject_alt_names().is_some_and(|names| {
+        names
+            .iter()
+            .any(|name| match (host_ip, name.ipaddress(), name.dnsname()) {
+                (Some(host_ip), Some(ip), _) => match host_ip {
+                    IpAddr::V4(host_ip) => ip == host_ip.octets(),
+                    IpAddr::V6(host_ip) => ip == host_ip.octets(),
+                },
+                (None, _, Some(dns_name)) => match dns_name.strip_prefix("*.") {
+                    Some(domain) => host
+                        .split_once('.')
+                        .is_some_and(|(_, host_domain)| host_domain.eq_ignore_ascii_case(domain)),
+                    None => dns_name.eq_ignore_ascii_case(host),
+                },
+                _ => false,
+            })
+    })
+}
+
+/// etcd-client only connects to its endpoints directly, so endpoints behind a proxy are reached
+/// through a TCP listener on localhost that tunnels every connection it accepts to the endpoint
+/// with an HTTP CONNECT request to the proxy. Returns the endpoint of that listener, with the
+/// scheme of endpoint, which keeps running for as long as the runtime does. Any local user can
+/// connect to it, and through it to the endpoint with the credentials of the proxy
+async fn tunnel_through_proxy(proxy: &Url, endpoint: &str) -> Result<String> {
+    let target = endpoint_authority(endpoint).to_string();
+    // Fail early rather than on every tunneled connection
+    open_tunnel(proxy, &target).await?;
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let local_endpoint = format!(
+        "{}{}",
+        &endpoint[..endpoint.len() - target.len()],
+        listener.local_addr()?
+    );
+    tracing::debug!(endpoint, local_endpoint, "tunneling through proxy");
+
+    let proxy = proxy.clone();
+    tokio::spawn(async move {
+        loop {
+            let mut tcp_stream = match listener.accept().await {
+                Ok((tcp_stream, _)) => tcp_stream,
+                Err(err) => {
+                    tracing::warn!("Could not accept etcd connection: {}", err);
+                    continue;
+                }
+            };
+
+            let (proxy, target) = (proxy.clone(), target.clone());
+            tokio::spawn(async move {
+                match open_tunnel(&proxy, &target).await {
+                    Ok(mut tunnel) => {
+                        let _ = tokio::io::copy_bidirectional(&mut tcp_stream, &mut tunnel).await;
+                    }
+                    Err(err) => tracing::warn!(
+                        target,
+                        "Could not tunnel to etcd through the proxy: {:#}",
+                        err
+                    ),
+                }
+            });
+        }
+    });
+
+    Ok(local_endpoint)
+}
+
+/// Connects to target, a host:port, through proxy
+async fn open_tunnel(proxy: &Url, target: &str) -> Result<TcpStream> {
+    let proxy_host = proxy.host_str().context("proxy URL has no host")?;
+    let proxy_port = proxy
+        .port_or_known_default()
+        .context("proxy URL has no port")?;
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))
+        .await
+        .context("connecting to proxy")?;
+
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if !proxy.username().is_empty() {
+        let credentials = format!("{}:{}", proxy.username(), proxy.password().unwrap_or(""));
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64_STANDARD.encode(credentials)
+        );
+    }
+    request += "\r\n";
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .context("sending CONNECT request to proxy")?;
+
+    // Read byte by byte, so that nothing after the headers is taken from the tunnel
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        ensure!(
+            response.len() < MAX_PROXY_RESPONSE_SIZE,
+            "proxy response headers too large"
+        );
+        match stream.read_u8().await {
+            Ok(byte) => response.push(byte),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                bail!("proxy closed the connection without answering CONNECT")
+            }
+            Err(err) => return Err(err).context("reading CONNECT response from proxy"),
+        }
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(stream),
+        _ => bail!("proxy refused CONNECT to {}: {}", target, status_line),
+    }
+}
+
+/// More than any proxy sends in answer to CONNECT
+const MAX_PROXY_RESPONSE_SIZE: usize = 16 * 1024;
+
+/// Fails request if it takes longer than timeout, the same way an etcd side deadline would
+pub(crate) async fn with_timeout<T>(
+    timeout: Duration,
+    request: impl Future<Output = Result<T, etcd_client::Error>>,
+) -> Result<T, etcd_client::Error> {
+    match tokio::time::timeout(timeout, request).await {
+        Ok(result) => result,
+        Err(_) => Err(etcd_client::Error::GRpcStatus(
+            tonic::Status::deadline_exceeded(format!("request timed out after {:?}", timeout)),
+        )),
+    }
+}
+
+/// The latest revision of the cluster
+pub(crate) async fn current_revision(
+    client: &EtcdClient,
+    request_timeout: Duration,
+) -> Result<i64> {
+    let mut kv_client = client.kv_client();
+    let get_response = with_timeout(
+        request_timeout,
+        kv_client.get("/", Some(GetOptions::new().with_count_only())),
+    )
+    .await
+    .context("getting the current revision")?;
+
+    get_response
+        .header()
+        .map(|header| header.revision())
+        .context("no header in etcd response")
+}
+
+/// The oldest revision that can still be read, i.e. the revision of the last compaction, or 1 if
+/// there was none. etcd doesn't report it, so it's bisected with gets between 1 and current
+pub(crate) async fn oldest_revision(
+    client: &EtcdClient,
+    current: i64,
+    request_timeout: Duration,
+) -> Result<i64> {
+    let (mut compacted, mut readable) = (0, current);
+    while readable - compacted > 1 {
+        let revision = compacted + (readable - compacted) / 2;
+        let mut kv_client = client.kv_client();
+        match with_timeout(
+            request_timeout,
+            kv_client.get(
+                "/",
+                Some(GetOptions::new().with_count_only().with_revision(revision)),
+            ),
+        )
+        .await
+        {
+            Ok(_) => readable = revision,
+            Err(err) if is_compacted(&err) => compacted = revision,
+            Err(err) => return Err(err).context("probing for the compacted revision"),
+        }
+    }
+
+    Ok(readable)
+}
+
+/// Describes the cluster client is connected to, as seen by the member serving the requests
+pub(crate) async fn cluster_info(
+    client: &EtcdClient,
+    endpoints: &[String],
+    request_timeout: Duration,
+) -> Result<ClusterInfo> {
+    let mut maintenance_client = client.maintenance_client();
+    let status = with_timeout(request_timeout, maintenance_client.status())
+        .await
+        .context("getting the status of etcd")?;
+    let mut cluster_client = client.cluster_client();
+    let member_list = with_timeout(request_timeout, cluster_client.member_list())
+        .await
+        .context("listing etcd members")?;
+
+    let header = status.header().context("no header in etcd response")?;
+    Ok(ClusterInfo {
+        cluster_id: format!("{:x}", header.cluster_id()),
+        member_id: format!("{:x}", header.member_id()),
+        leader: format!("{:x}", status.leader()),
+        version: status.version().to_string(),
+        revision: header.revision(),
+        endpoints: endpoints.to_vec(),
+        members: member_list
+            .members()
+            .iter()
+            .map(|member| ClusterMember {
+                id: format!("{:x}", member.id()),
+                name: member.name().to_string(),
+                peer_urls: member.peer_urls().to_vec(),
+                client_urls: member.client_urls().to_vec(),
+                is_learner: member.is_learner(),
+            })
+            .collect(),
+    })
+}
+
+/// The names of the users and roles of etcd auth, which only root may list once auth is enabled
+pub(crate) async fn auth_info(client: &EtcdClient, request_timeout: Duration) -> Result<AuthInfo> {
+    let mut auth_client = client.auth_client();
+    let users = with_timeout(request_timeout, auth_client.user_list())
+        .await
+        .context("listing etcd users")?;
+    let roles = with_timeout(request_timeout, auth_client.role_list())
+        .await
+        .context("listing etcd roles")?;
+
+    Ok(AuthInfo {
+        users: users.users().to_vec(),
+        roles: roles.roles().to_vec(),
+    })
+}
+
+/// The active leases of the cluster with the keys attached to them, up to concurrency of them
+/// queried at once
+pub(crate) async fn leases(
+    client: &EtcdClient,
+    request_timeout: Duration,
+    concurrency: usize,
+) -> Result<Vec<LeaseInfo>> {
+    let mut lease_client = client.lease_client();
+    let listed = with_timeout(request_timeout, lease_client.leases())
+        .await
+        .context("listing etcd leases")?;
+    let ids: Vec<i64> = listed.leases().iter().map(|lease| lease.id()).collect();
+
+    let leases: Vec<_> = futures::stream::iter(ids)
+        .map(|id| {
+            let mut lease_client = client.lease_client();
+            async move {
+                with_timeout(
+                    request_timeout,
+                    lease_client.time_to_live(id, Some(LeaseTimeToLiveOptions::new().with_keys())),
+                )
+                .await
+                .context(format!("getting the TTL of lease {:x}", id))
+            }
+        })
+        .buffered(concurrency)
+        .try_collect()
+        .await?;
+
+    Ok(leases
+        .into_iter()
+        // Expired since listing
+        .filter(|lease| lease.ttl() >= 0)
+        .map(|lease| LeaseInfo {
+            id: format!("{:x}", lease.id()),
+            ttl: lease.ttl(),
+            granted_ttl: lease.granted_ttl(),
+            keys: lease
+                .keys()
+                .iter()
+                .map(|key| String::from_utf8_lossy(key).into_owned())
+                .collect(),
+        })
+        .collect())
+}
+
+/// Whether a failed etcd request is worth retrying, i.e. the failure is likely transient
+pub(crate) fn is_retriable(err: &etcd_client::Error) -> bool {
+    match err {
+        etcd_client::Error::TransportError(_) | etcd_client::Error::IoError(_) => true,
+        etcd_client::Error::GRpcStatus(status) => matches!(
+            status.code(),
+            tonic::Code::Unavailable
+                | tonic::Code::DeadlineExceeded
+                | tonic::Code::ResourceExhausted
+                | tonic::Code::Aborted
+        ),
+        _ => false,
+    }
+}
+
+fn is_compacted(err: &etcd_client::Error) -> bool {
+    matches!(err, etcd_client::Error::GRpcStatus(status)
+        if status.code() == tonic::Code::OutOfRange && status.message().contains("compacted"))
+}
+
+/// Whether err was caused by etcd having compacted the requested revision
+pub(crate) fn is_compaction_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<etcd_client::Error>()
+            .is_some_and(is_compacted)
+    })
+}
+
+/// Converts an etcd error, explaining compaction failures when a specific revision was requested
+pub(crate) fn revision_error(err: etcd_client::Error, revision: Option<i64>) -> anyhow::Error {
+    match revision {
+        Some(revision) if is_compacted(&err) => anyhow::Error::from(err).context(format!(
+            "revision {} has been compacted, pick a newer --revision",
+            revision
+        )),
+        _ => err.into(),
+    }
+}
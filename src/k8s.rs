@@ -0,0 +1,40 @@
+use serde_json::Value;
+
+/// Fields of Kubernetes objects that change without the object itself meaningfully changing
+pub const DEFAULT_NOISE_FIELDS: &[&str] = &["metadata.managedFields", "metadata.resourceVersion"];
+
+/// Removes the fields at the dotted paths in fields from value, if it's a Kubernetes object, i.e.
+/// JSON with an apiVersion and a kind. Anything else is returned as is, and so are objects without
+/// any of the fields
+pub(crate) fn strip_fields(value: Vec<u8>, fields: &[String]) -> Vec<u8> {
+    let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(&value) else {
+        return value;
+    };
+    if !object.contains_key("apiVersion") || !object.contains_key("kind") {
+        return value;
+    }
+
+    let mut stripped = false;
+    for field in fields {
+        let mut path: Vec<&str> = field.split('.').collect();
+        let Some(last) = path.pop() else {
+            continue;
+        };
+
+        let parent = path.into_iter().try_fold(&mut object, |object, segment| {
+            match object.get_mut(segment) {
+                Some(Value::Object(child)) => Some(child),
+                _ => None,
+            }
+        });
+        if let Some(parent) = parent {
+            stripped |= parent.remove(last).is_some();
+        }
+    }
+
+    if !stripped {
+        return value;
+    }
+
+    serde_json::to_vec(&object).unwrap_or(value)
+}
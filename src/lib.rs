@@ -0,0 +1,40 @@
+//! Dumps the keys of a Kubernetes etcd into files, decoding the protobuf values with ouger.
+//!
+//! The etcddump binary is a thin CLI around [`EtcdDumper`] and [`restore`].
+
+mod checkpoint;
+pub mod diff;
+pub mod doctor;
+pub mod dump;
+pub mod etcd;
+pub mod k8s;
+mod manifest;
+pub mod merge;
+pub mod metrics;
+pub mod ouger;
+pub mod output;
+mod paths;
+mod rate_limit;
+pub mod restore;
+pub mod retry;
+pub mod s3;
+mod snapshot;
+pub mod summary;
+pub mod timings;
+mod transform;
+mod utc;
+pub mod verify;
+mod watch;
+
+pub use diff::{diff_dumps, DumpDiff};
+pub use dump::{DumpConfig, DumpInterrupt, DumpProgress, DumpSummary, EtcdDumper, PrefixProgress};
+pub use etcd::{Credentials, EtcdConfig, TlsConfig};
+pub use manifest::{ErrorStage, KeyError};
+pub use merge::{merge_dumps, MergeReport};
+pub use metrics::Metrics;
+pub use ouger::OugerConfig;
+pub use output::{JsonFormat, Layout, OutputConfig};
+pub use restore::{restore, RestoreConfig};
+pub use retry::RetryPolicy;
+pub use timings::Timings;
+pub use verify::VerifyReport;
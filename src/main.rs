@@ -1,40 +1,38 @@
-use anyhow::{bail, Context, Result};
-use clap::Parser;
-use clio::*;
-use etcd_client::{Client as EtcdClient, GetOptions};
-use reqwest::Client;
-use std::sync::Arc;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use cli::{NonEmptyOutputDir, ParsedCommand};
+use etcddump::doctor::{self, CheckResult};
+use etcddump::timings::Percentiles;
+use etcddump::{
+    dump, etcd, ouger, restore, s3, summary, DumpInterrupt, DumpProgress, DumpSummary, EtcdDumper,
+    Metrics, OutputConfig, Timings,
+};
+use futures::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
 
-mod ouger;
+mod cli;
+mod config_file;
+mod tui;
 
-/// A program to regenerate cluster certificates, keys and tokens
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-pub(crate) struct Cli {
-    /// etcd endpoint of etcd instance to dump
-    #[clap(long)]
-    pub(crate) etcd_endpoint: String,
-
-    /// dump output dir
-    #[clap(long, value_parser = clap::value_parser!(ClioPath).exists().is_dir())]
-    pub(crate) output_dir: ClioPath,
-}
-
-pub(crate) struct ParsedCLI {
-    pub(crate) etcd_endpoint: String,
-    pub(crate) output_dir: ClioPath,
+/// Every key in flight holds at most an etcd stream, an ouger connection and an output file, on
+/// top of the few files the process needs anyway
+fn needed_open_files(concurrency: usize) -> libc::rlim_t {
+    (concurrency as libc::rlim_t)
+        .saturating_mul(3)
+        .saturating_add(64)
 }
 
-pub(crate) fn parse_cli() -> Result<ParsedCLI> {
-    let cli = Cli::parse();
-
-    Ok(ParsedCLI {
-        etcd_endpoint: cli.etcd_endpoint,
-        output_dir: cli.output_dir,
-    })
-}
-
-pub(crate) fn set_max_open_files_limit() -> Result<()> {
+/// Raises the soft open files limit to needed if it's below that, as far as the platform allows
+pub(crate) fn raise_open_files_limit(needed: libc::rlim_t) -> Result<()> {
     let mut current_limit = libc::rlimit {
         rlim_cur: 0,
         rlim_max: 0,
@@ -42,99 +40,645 @@ pub(crate) fn set_max_open_files_limit() -> Result<()> {
     match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut current_limit) } {
         0 => {}
         _ => {
-            bail!("Failed to get current max open files limit");
+            bail!(
+                "Failed to get current max open files limit: {}",
+                std::io::Error::last_os_error()
+            );
         }
     }
 
-    let new_limit = libc::rlimit {
-        rlim_cur: current_limit.rlim_max,
+    if current_limit.rlim_cur >= needed {
+        return Ok(());
+    }
+
+    // The hard limit may be higher than what the kernel lets a process open, e.g. unlimited on
+    // macOS
+    let ceiling = match max_open_files_per_process() {
+        Some(max) => current_limit.rlim_max.min(max),
+        None => current_limit.rlim_max,
+    };
+    let mut new_limit = libc::rlimit {
+        rlim_cur: needed.min(ceiling),
         rlim_max: current_limit.rlim_max,
     };
 
-    match unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &new_limit) } {
-        0 => {}
-        _ => {
-            bail!("Failed to set max open files limit");
+    // Some platforms refuse limits they don't report anywhere, so back off until one is accepted
+    loop {
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &new_limit) } == 0 {
+            break;
         }
+        let err = std::io::Error::last_os_error();
+        let lower = (new_limit.rlim_cur / 2).max(current_limit.rlim_cur);
+        if lower == new_limit.rlim_cur {
+            bail!("Failed to set max open files limit: {}", err);
+        }
+        tracing::debug!(limit = new_limit.rlim_cur, %err, "open files limit refused, lowering it");
+        new_limit.rlim_cur = lower;
+    }
+
+    if new_limit.rlim_cur < needed {
+        tracing::warn!(
+            limit = new_limit.rlim_cur,
+            needed,
+            "the open files limit can't be raised high enough for the requested concurrency"
+        );
     }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let parsed_cli = parse_cli().context("parsing CLI")?;
-    set_max_open_files_limit().context("Setting open file limits to max")?;
-    tokio::runtime::Runtime::new()?.block_on(async { main_internal(parsed_cli).await })
+/// How many files the kernel lets a single process open, on the platforms that say
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly"))]
+fn max_open_files_per_process() -> Option<libc::rlim_t> {
+    let mut max: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>();
+    let result = unsafe {
+        libc::sysctlbyname(
+            c"kern.maxfilesperproc".as_ptr(),
+            &mut max as *mut libc::c_int as *mut libc::c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    match result {
+        0 => libc::rlim_t::try_from(max).ok(),
+        _ => None,
+    }
 }
 
-async fn main_internal(parsed_cli: ParsedCLI) -> Result<()> {
-    let _ouger_child_process = ouger::launch_ouger_server()
-        .await
-        .context("launching ouger server")?;
+/// Linux and the other platforms enforce the hard limit itself
+#[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly")))]
+fn max_open_files_per_process() -> Option<libc::rlim_t> {
+    None
+}
 
-    let client = Arc::new(
-        EtcdClient::connect([parsed_cli.etcd_endpoint.as_str()], None)
-            .await
-            .context("connecting to etcd")?,
-    );
+/// The exit status of every class of failure, so that scripts can tell them apart
+#[derive(Clone, Copy)]
+enum Failure {
+    /// Anything not covered below, including verify and diff finding differences
+    Other = 1,
+    /// Invalid flags, clap exits with this too
+    Usage = 2,
+    /// Connecting, authenticating or talking to etcd
+    Etcd = 3,
+    /// Launching ouger, reaching it or decoding and encoding values with it
+    Ouger = 4,
+    /// Reading or writing files, uploads included
+    Io = 5,
+    /// Some keys failed to dump with --continue-on-error, all the others were dumped
+    KeysFailed = 6,
+    /// The dump ran past --deadline
+    Deadline = 7,
+}
+
+impl Failure {
+    /// The class of err. ouger errors come first, as reaching ouger fails with IO errors too
+    fn of(err: &anyhow::Error) -> Self {
+        if err.is::<KeysFailed>() {
+            Failure::KeysFailed
+        } else if err.is::<dump::DeadlineExceeded>() {
+            Failure::Deadline
+        } else if err.is::<dump::EtcdUnavailable>() || err.is::<etcd::EtcdConnect>() {
+            Failure::Etcd
+        } else if err.is::<s3::UploadError>() {
+            Failure::Io
+        } else if err.is::<ouger::LaunchError>()
+            || err.is::<ouger::ConversionError>()
+            || err.is::<dump::DecodeTimeout>()
+            || err.is::<reqwest::Error>()
+        {
+            Failure::Ouger
+        } else if err.is::<etcd_client::Error>() {
+            Failure::Etcd
+        } else if err.is::<std::io::Error>() {
+            Failure::Io
+        } else {
+            Failure::Other
+        }
+    }
+}
 
-    let etcd_get_options = GetOptions::new()
-        .with_prefix()
-        .with_limit(0)
-        .with_keys_only();
+/// Dumping went through, but some keys failed
+#[derive(Debug)]
+struct KeysFailed {
+    count: usize,
+    errors_file: &'static str,
+}
 
-    let get_response = client
-        .kv_client()
-        .get("/", Some(etcd_get_options.clone()))
-        .await?;
+impl std::fmt::Display for KeysFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} keys failed to dump, see {}",
+            self.count, self.errors_file
+        )
+    }
+}
 
-    let keys = get_response
-        .kvs()
-        .iter()
-        .map(|k| Ok(k.key_str()?.to_string()))
-        .collect::<Result<Vec<String>>>()?;
+impl std::error::Error for KeysFailed {}
 
-    let reqclient = Client::new();
+fn main() -> ExitCode {
+    let parsed_cli = match cli::parse_cli() {
+        Ok(parsed_cli) => parsed_cli,
+        Err(err) => return fail(Failure::Usage, err.context("parsing CLI")),
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_writer(tui::log_writer))
+        .with(
+            // Dependencies are too chatty below warn to be useful
+            Targets::new()
+                .with_target(env!("CARGO_CRATE_NAME"), parsed_cli.log_level)
+                .with_default(LevelFilter::WARN.min(parsed_cli.log_level)),
+        )
+        .init();
+    if let Some(concurrency) = parsed_cli.command.concurrency() {
+        if parsed_cli.raise_rlimit {
+            if let Err(err) = raise_open_files_limit(needed_open_files(concurrency)) {
+                tracing::warn!("Could not raise the open files limit: {:#}", err);
+            }
+        }
+    }
+    match build_runtime(parsed_cli.worker_threads)
+        .context("creating runtime")
+        .and_then(|runtime| runtime.block_on(async { main_internal(parsed_cli).await }))
+    {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => fail(Failure::of(&err), err),
+    }
+}
 
-    let mut tasks = Vec::new();
-    for key in keys {
-        tasks.push(tokio::spawn(get_key(
-            reqclient.clone(),
-            key,
-            Arc::clone(&client),
-            parsed_cli.output_dir.clone(),
-        )));
+fn build_runtime(worker_threads: Option<NonZeroUsize>) -> std::io::Result<Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(worker_threads) = worker_threads {
+        builder.worker_threads(worker_threads.get());
     }
+    tracing::debug!(
+        worker_threads = worker_threads
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map(NonZeroUsize::get),
+        "starting runtime"
+    );
+
+    builder.build()
+}
 
-    for task in tasks {
-        task.await??;
+/// Reports err the way returning it from main would, but with the exit status of failure
+fn fail(failure: Failure, err: anyhow::Error) -> ExitCode {
+    eprintln!("Error: {:?}", err);
+    ExitCode::from(failure as u8)
+}
+
+async fn main_internal(parsed_cli: cli::ParsedCLI) -> Result<()> {
+    let interrupt = DumpInterrupt::default();
+    let command = run_command(
+        parsed_cli.command,
+        parsed_cli.progress_bar,
+        parsed_cli.summary,
+        interrupt.clone(),
+    );
+    tokio::pin!(command);
+    loop {
+        tokio::select! {
+            result = &mut command => return result,
+            // A running dump stops by itself once it wrote the keys dumped so far, anything else
+            // stops right away, and so does a dump interrupted twice
+            _ = tokio::signal::ctrl_c() => {
+                if !interrupt.interrupt() {
+                    return Err(anyhow!("interrupted"));
+                }
+                tracing::warn!("interrupt again to stop without writing the manifest");
+            }
+        }
+    }
+}
+
+async fn run_command(
+    command: ParsedCommand,
+    progress_bar: bool,
+    summary: bool,
+    interrupt: DumpInterrupt,
+) -> Result<()> {
+    match command {
+        ParsedCommand::Dump {
+            mut config,
+            dry_run,
+            non_empty_output_dir,
+            metrics_addr,
+            tui,
+            value_only,
+            summary_json,
+        } => {
+            let continue_on_error = config.continue_on_error;
+            let errors_file = match config.output {
+                OutputConfig::Stdout
+                | OutputConfig::JsonArray(_)
+                | OutputConfig::Snapshot(_)
+                | OutputConfig::Pipe(_) => "the logs",
+                OutputConfig::Dir(_) | OutputConfig::Archive(_) | OutputConfig::S3(_) => {
+                    "errors.json"
+                }
+            };
+            if !dry_run {
+                dump::expand_output_dir(&mut config).await?;
+            }
+            if let (OutputConfig::Dir(output_dir), false) = (&config.output, dry_run) {
+                prepare_output_dir(output_dir, non_empty_output_dir)?;
+            }
+            let dumper = EtcdDumper::new(config).with_interrupt(interrupt.clone());
+
+            if value_only {
+                return print_value(&dumper).await;
+            }
+
+            if dry_run {
+                let keys = dumper.list_keys().await?;
+                for key in &keys {
+                    println!("{}", key);
+                }
+                println!("{} keys would be dumped", keys.len());
+                return Ok(());
+            }
+
+            if let Some(metrics_addr) = &metrics_addr {
+                serve_metrics(dumper.metrics(), metrics_addr).await?;
+            }
+
+            let start = Instant::now();
+            let started_at = SystemTime::now();
+            let dump_summary = if progress_bar && tui {
+                tokio::select! {
+                    dump_summary = dumper.dump() => dump_summary?,
+                    result = tui::show(dumper.progress(), dumper.metrics(), interrupt) => match result? {},
+                }
+            } else if progress_bar {
+                tokio::select! {
+                    dump_summary = dumper.dump() => dump_summary?,
+                    _ = show_progress(dumper.progress()) => unreachable!(),
+                }
+            } else {
+                dumper.dump().await?
+            };
+
+            if summary {
+                eprintln!("{}", format_summary(&dump_summary, start.elapsed()));
+            }
+            if let Some(timings) = &dump_summary.timings {
+                eprintln!("{}", format_timings(timings));
+            }
+            if let Some(path) = &summary_json {
+                dump_summary.write_json(path, started_at, SystemTime::now())?;
+            }
+
+            if dump_summary.interrupted {
+                bail!("interrupted, the dump lacks the keys that weren't done yet");
+            }
+
+            if continue_on_error && !dump_summary.errors.is_empty() {
+                return Err(KeysFailed {
+                    count: dump_summary.errors.len(),
+                    errors_file,
+                }
+                .into());
+            }
+        }
+        ParsedCommand::Restore { config, dry_run } => {
+            if dry_run {
+                let files = restore::restorable_files(&config.input_dir, config.raw)?;
+                for (key, path) in &files {
+                    println!("{} <- {}", key, path.display());
+                }
+                println!("{} keys would be restored", files.len());
+                return Ok(());
+            }
+
+            restore::restore(&config).await?;
+        }
+        ParsedCommand::Watch {
+            config,
+            metrics_addr,
+        } => {
+            let dumper = EtcdDumper::new(config);
+            if let Some(metrics_addr) = &metrics_addr {
+                serve_metrics(dumper.metrics(), metrics_addr).await?;
+            }
+
+            dumper.watch().await?
+        }
+        ParsedCommand::Verify { config, dump_dir } => {
+            let report = EtcdDumper::new(config).verify(&dump_dir).await?;
+
+            for key in &report.missing_from_etcd {
+                println!("missing from etcd: {}", key);
+            }
+            for key in &report.missing_from_dump {
+                println!("missing from dump: {}", key);
+            }
+            for key in &report.mismatched {
+                println!("mismatched: {}", key);
+            }
+            for key in &report.stale_sidecars {
+                println!("stale sidecar: {}", key);
+            }
+
+            ensure!(report.is_empty(), "dump doesn't match etcd");
+        }
+        ParsedCommand::Summary { config, depth } => {
+            let counts = EtcdDumper::new(config).count_by_group(depth).await?;
+
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_by(|(group_a, count_a), (group_b, count_b)| {
+                count_b.cmp(count_a).then_with(|| group_a.cmp(group_b))
+            });
+            for (group, count) in &counts {
+                println!("{}: {}", group, count);
+            }
+            println!(
+                "{} keys in {} groups",
+                counts.iter().map(|(_, count)| count).sum::<usize>(),
+                counts.len()
+            );
+        }
+        ParsedCommand::Count {
+            etcd,
+            prefixes,
+            exclude_prefixes,
+            revision,
+        } => {
+            let counts = summary::count_keys(&etcd, &prefixes, &exclude_prefixes, revision).await?;
+
+            for (prefix, count) in &counts {
+                println!("{}: {}", prefix, count);
+            }
+            if counts.len() > 1 {
+                println!(
+                    "{} keys in total",
+                    counts.iter().map(|(_, count)| count).sum::<i64>()
+                );
+            }
+        }
+        ParsedCommand::Doctor { etcd, ouger } => {
+            let checks = doctor::run_checks(&etcd, &ouger).await;
+
+            for check in &checks {
+                match &check.result {
+                    CheckResult::Pass(found) => println!("PASS {}: {}", check.name, found),
+                    CheckResult::Fail(err) => {
+                        println!("FAIL {}: {:#}", check.name, err);
+                        println!("     {}", check.hint);
+                    }
+                    CheckResult::Skip => println!("SKIP {}", check.name),
+                }
+            }
+
+            let failed = checks.iter().filter(|check| check.failed()).count();
+            ensure!(failed == 0, "{} of {} checks failed", failed, checks.len());
+        }
+        ParsedCommand::Diff {
+            old_dir,
+            new_dir,
+            raw,
+            summary,
+            unified,
+        } => {
+            let diff = etcddump::diff_dumps(&old_dir, &new_dir, raw)?;
+
+            if summary {
+                println!(
+                    "{} added, {} removed, {} modified",
+                    diff.added.len(),
+                    diff.removed.len(),
+                    diff.modified.len()
+                );
+            } else {
+                for key in &diff.added {
+                    println!("+ {}", key);
+                }
+                for key in &diff.removed {
+                    println!("- {}", key);
+                }
+                for modified in &diff.modified {
+                    println!("~ {}", modified.key);
+                    if unified {
+                        print!("{}", modified.unified_diff()?);
+                    }
+                }
+            }
+
+            ensure!(diff.is_empty(), "dumps differ");
+        }
+        ParsedCommand::Merge {
+            output_dir,
+            input_dirs,
+            force,
+        } => {
+            etcddump::merge_dumps(&output_dir, &input_dirs, force)?;
+        }
     }
 
     Ok(())
 }
 
-async fn get_key(
-    reqclient: Client,
-    key: String,
-    client: Arc<EtcdClient>,
-    output_dir: ClioPath,
-) -> Result<(), anyhow::Error> {
-    let get_result = client
-        .kv_client()
-        .get(key.clone(), None)
+/// Serves metrics on addr in the background for as long as the runtime lives
+async fn serve_metrics(metrics: Metrics, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)
         .await
-        .context("during etcd get")?;
-    if let Some(value) = get_result.kvs().first() {
-        let raw_etcd_value = value.value();
+        .context(format!("binding metrics server to {}", addr))?;
+    tracing::info!(addr, "serving metrics");
+
+    tokio::spawn(async move {
+        if let Err(err) = metrics.serve(listener).await {
+            tracing::warn!("Metrics server stopped: {:#}", err);
+        }
+    });
 
-        let decoded_value = ouger::ouger(&reqclient, "decode", raw_etcd_value)
-            .await
-            .context("decoding value with ouger")?;
+    Ok(())
+}
 
-        let output_file = output_dir.join(key.trim_start_matches('/'));
+/// Makes sure a dump into output_dir won't mix with files already in there, unless asked to
+fn prepare_output_dir(output_dir: &Path, non_empty_output_dir: NonEmptyOutputDir) -> Result<()> {
+    let entries = std::fs::read_dir(output_dir)
+        .context(format!("listing {}", output_dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()
+        .context(format!("listing {}", output_dir.display()))?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    match non_empty_output_dir {
+        NonEmptyOutputDir::Refuse => bail!(
+            "{} is not empty, pass --force to dump into it anyway or --clean to empty it first",
+            output_dir.display()
+        ),
+        NonEmptyOutputDir::Reuse => {}
+        NonEmptyOutputDir::Clean => {
+            tracing::info!(dir = %output_dir.display(), "emptying output dir");
+            for entry in entries {
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    std::fs::remove_dir_all(&path)
+                } else {
+                    std::fs::remove_file(&path)
+                }
+                .context(format!("removing {}", path.display()))?;
+            }
+        }
+    }
 
-        std::fs::create_dir_all(output_file.parent().unwrap())?;
-        std::fs::write(output_file, decoded_value)?;
-    };
     Ok(())
 }
+
+/// Keeps a progress bar in sync with the progress of a dump, never returns
+async fn show_progress(progress: DumpProgress) {
+    let progress_bar = ProgressBar::new(0).with_style(
+        ProgressStyle::with_template("{wide_bar} {pos}/{len} keys, {per_sec}, ETA {eta}").unwrap(),
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+        progress_bar.set_length(progress.total() as u64);
+        progress_bar.set_position(progress.done() as u64);
+    }
+}
+
+/// Writes the decoded value of the single key dumper dumps to stdout, nothing else
+async fn print_value(dumper: &EtcdDumper) -> Result<()> {
+    let mut values = Box::pin(dumper.stream());
+    let Some(value) = values.next().await else {
+        bail!("key not found");
+    };
+    let (_, value) = value?;
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(&value)
+        .and_then(|()| stdout.flush())
+        .context("writing value to stdout")
+}
+
+/// e.g. "Dumped 12,431 keys, 284.0 MiB, 0 errors in 9.2s"
+fn format_summary(summary: &DumpSummary, elapsed: Duration) -> String {
+    let counts: String = [
+        (summary.skipped, "skipped"),
+        (summary.skipped_by_size, "skipped by size"),
+        (summary.skipped_leased, "leased skipped"),
+        (summary.skipped_unmodified, "unmodified skipped"),
+        (summary.skipped_undecodable, "undecodable skipped"),
+        (summary.empty, "empty"),
+    ]
+    .into_iter()
+    .filter(|(count, _)| *count > 0)
+    .map(|(count, what)| format!(", {} {}", format_count(count), what))
+    .collect();
+
+    format!(
+        "Dumped {} keys, {}, {} errors{} in {:.1}s",
+        format_count(summary.keys),
+        format_bytes(summary.bytes_written),
+        format_count(summary.errors.len()),
+        counts,
+        elapsed.as_secs_f64()
+    )
+}
+
+/// e.g. "Timings: connect 3.2ms, list 1.4s, ..." followed by the percentiles of every request
+fn format_timings(timings: &Timings) -> String {
+    let percentiles = |what, percentiles: &Percentiles| {
+        format!(
+            "{}: p50 {:.1?}, p95 {:.1?}, p99 {:.1?}",
+            what, percentiles.p50, percentiles.p95, percentiles.p99
+        )
+    };
+
+    format!(
+        "Timings: connect {:.1?}, list {:.1?}, fetch {:.1?}, decode {:.1?}, write {:.1?} (fetch and decode summed over all keys)\n{}\n{}",
+        timings.connect,
+        timings.list,
+        timings.fetch,
+        timings.decode,
+        timings.write,
+        percentiles("etcd get", &timings.get_percentiles),
+        percentiles("ouger decode", &timings.decode_percentiles),
+    )
+}
+
+fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "connection refused")
+    }
+
+    #[test]
+    fn etcd_connection_failures_are_etcd_failures() {
+        let failures = [
+            // Authenticating
+            anyhow!("authenticating to etcd as user root: invalid password"),
+            // Reaching a unix socket or the proxy
+            anyhow::Error::new(io_error()).context("connecting to etcd socket /run/etcd.sock"),
+            // The proxy refusing CONNECT
+            anyhow!("proxy refused CONNECT to etcd:2379: HTTP/1.1 403 Forbidden"),
+        ];
+        for err in failures {
+            assert!(matches!(
+                Failure::of(&err.context(etcd::EtcdConnect)),
+                Failure::Etcd
+            ));
+        }
+    }
+
+    #[test]
+    fn failures_are_told_apart() {
+        let io = || anyhow::Error::new(io_error());
+        assert!(matches!(Failure::of(&io()), Failure::Io));
+        assert!(matches!(
+            Failure::of(&io().context(ouger::LaunchError)),
+            Failure::Ouger
+        ));
+        assert!(matches!(
+            Failure::of(&io().context(s3::UploadError)),
+            Failure::Io
+        ));
+        assert!(matches!(
+            Failure::of(&anyhow::Error::new(KeysFailed {
+                count: 1,
+                errors_file: "errors.json"
+            })),
+            Failure::KeysFailed
+        ));
+        assert!(matches!(
+            Failure::of(&anyhow!("something else")),
+            Failure::Other
+        ));
+    }
+}
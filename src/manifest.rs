@@ -0,0 +1,439 @@
+use crate::output::Output;
+use anyhow::{Context, Result};
+use etcd_client::KeyValue;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+pub(crate) const MANIFEST_FILE_NAME: &str = "manifest.json";
+pub(crate) const ERRORS_FILE_NAME: &str = "errors.json";
+pub(crate) const CHECKSUMS_FILE_NAME: &str = "SHA256SUMS";
+pub(crate) const CLUSTER_INFO_FILE_NAME: &str = "cluster-info.json";
+pub(crate) const AUTH_FILE_NAME: &str = "auth.json";
+pub(crate) const LEASES_FILE_NAME: &str = "leases.json";
+/// Every file a dump writes at its root besides the files of keys
+pub(crate) const METADATA_FILE_NAMES: [&str; 6] = [
+    MANIFEST_FILE_NAME,
+    ERRORS_FILE_NAME,
+    CHECKSUMS_FILE_NAME,
+    CLUSTER_INFO_FILE_NAME,
+    AUTH_FILE_NAME,
+    LEASES_FILE_NAME,
+];
+/// Appended to the path of a value file to get the path of its sidecar
+pub(crate) const SIDECAR_SUFFIX: &str = ".meta.json";
+/// Appended to the path of a key, before any .gz extension, to get the path of its raw value
+pub(crate) const RAW_COPY_SUFFIX: &str = ".raw";
+/// The dir deduplicated values are written to, each to a file named after its SHA-256
+pub(crate) const BLOBS_DIR_NAME: &str = "blobs";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub(crate) keys: Vec<ManifestEntry>,
+    /// Keys left out of the dump because ouger couldn't decode them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) undecodable: Vec<KeyError>,
+    /// Trimmed off the keys starting with it before mapping them to paths, so paths only map back
+    /// to keys through the entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) strip_prefix: Option<String>,
+    /// False when the dump was interrupted, then it lacks the keys that weren't done yet
+    #[serde(default = "complete_default")]
+    pub(crate) complete: bool,
+    /// Values were written once to the blobs dir for all the keys that have them, so the path of
+    /// an entry is shared by every key with the same value
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) dedup: bool,
+    /// Files of values were gzip compressed and have a .gz extension, other dumps may have keys
+    /// ending with .gz
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) compress_files: bool,
+}
+
+/// Manifests of dumps from before interrupts were recorded are of complete dumps, as those left
+/// no manifest at all
+fn complete_default() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    pub(crate) key: String,
+    /// Where the value of the key was written, relative to the root of the dump
+    pub(crate) path: String,
+    pub(crate) create_revision: i64,
+    pub(crate) mod_revision: i64,
+    pub(crate) version: i64,
+    pub(crate) lease: i64,
+    /// The remaining TTL of the lease in seconds when the key was dumped, -1 if it had already
+    /// expired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) lease_ttl: Option<i64>,
+    pub(crate) value_size: usize,
+    /// The written value isn't valid UTF-8, so tools reading the dump can't treat it as text. The
+    /// stdout output base64 encodes such values
+    pub(crate) binary: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sha256: Option<String>,
+    /// Where the value as stored in etcd was written too, when including raw values
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) raw_path: Option<String>,
+    /// The size of the decoded value, when the raw one was written too
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) decoded_size: Option<usize>,
+    /// Why ouger couldn't decode the value, which was written raw instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) decode_error: Option<String>,
+    /// Only a placeholder with the SHA-256 and size of the value as stored in etcd was written
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) redacted: bool,
+    /// The size of the value before it was truncated, only the beginning of it was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) original_size: Option<usize>,
+    /// The value was gzip compressed in etcd and written inflated
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) decompressed: bool,
+    /// The value was written as stored in etcd without being decoded by ouger, as its prefix is
+    /// one ouger is skipped for. Restore puts it back as it is
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) ouger_skipped: bool,
+    /// The extension added to the path of the key after sniffing its value, before any .gz one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) extension: Option<String>,
+    /// Other keys are below the key, so its value was written into the dir of its path, to
+    /// paths::DIR_VALUE_FILE_NAME unless that was taken by a key too
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) value_in_dir: bool,
+    /// The key can't be told from the path of its value, as it was renamed to its path followed
+    /// by ~ and a number after colliding with another key, or as its path doesn't keep all of its
+    /// slashes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) renamed: bool,
+}
+
+/// The etcd metadata of a single key, written next to its value file so that the file describes
+/// itself without the manifest
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Sidecar {
+    pub(crate) create_revision: i64,
+    pub(crate) mod_revision: i64,
+    pub(crate) version: i64,
+    pub(crate) lease: i64,
+}
+
+/// Which cluster a dump was taken from, as of the start of the dump. IDs are in hex like etcdctl
+/// prints them
+#[derive(Serialize)]
+pub(crate) struct ClusterInfo {
+    pub(crate) cluster_id: String,
+    /// The member that answered
+    pub(crate) member_id: String,
+    pub(crate) leader: String,
+    /// The etcd version of the member that answered
+    pub(crate) version: String,
+    pub(crate) revision: i64,
+    /// The endpoints the dump was configured with
+    pub(crate) endpoints: Vec<String>,
+    pub(crate) members: Vec<ClusterMember>,
+}
+
+/// The stats of a whole dump run, for monitoring to alert on rather than for reading the dump
+#[derive(Serialize)]
+pub(crate) struct RunSummary {
+    pub(crate) keys: usize,
+    pub(crate) bytes: u64,
+    /// Keys listed but not written, for any reason other than failing
+    pub(crate) skipped: usize,
+    pub(crate) errors: usize,
+    pub(crate) duration_ms: u128,
+    pub(crate) revision: Option<i64>,
+    pub(crate) cluster_id: Option<String>,
+    /// RFC 3339 in UTC
+    pub(crate) started_at: String,
+    pub(crate) finished_at: String,
+    /// False when the dump was interrupted before every key was done
+    pub(crate) complete: bool,
+}
+
+/// The users and roles of etcd auth, by name only: passwords and permissions are left out
+#[derive(Serialize)]
+pub(crate) struct AuthInfo {
+    pub(crate) users: Vec<String>,
+    pub(crate) roles: Vec<String>,
+}
+
+/// A lease that was active at the start of a dump, with its ID in hex like etcdctl prints it
+#[derive(Serialize)]
+pub(crate) struct LeaseInfo {
+    pub(crate) id: String,
+    /// The remaining TTL in seconds
+    pub(crate) ttl: i64,
+    pub(crate) granted_ttl: i64,
+    pub(crate) keys: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ClusterMember {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) peer_urls: Vec<String>,
+    pub(crate) client_urls: Vec<String>,
+    pub(crate) is_learner: bool,
+}
+
+/// A key that failed to dump when continuing on errors, or that ouger couldn't decode
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyError {
+    pub key: String,
+    /// Where dumping the key failed, None for undecodable keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<ErrorStage>,
+    pub error: String,
+}
+
+/// The step of dumping a key that failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorStage {
+    /// Getting the key or its lease from etcd
+    Fetch,
+    /// Decoding the value with ouger
+    Decode,
+    /// Piping the decoded value through the transform command
+    Transform,
+    /// Writing the files of the key
+    Write,
+    /// The dump ran past its deadline before the key was dumped
+    Deadline,
+}
+
+impl ManifestEntry {
+    pub(crate) fn new(key: String, path: String, kv: &KeyValue, written_value: &[u8]) -> Self {
+        Self {
+            key,
+            path,
+            create_revision: kv.create_revision(),
+            mod_revision: kv.mod_revision(),
+            version: kv.version(),
+            lease: kv.lease(),
+            lease_ttl: None,
+            value_size: kv.value().len(),
+            binary: std::str::from_utf8(written_value).is_err(),
+            sha256: None,
+            raw_path: None,
+            decoded_size: None,
+            decode_error: None,
+            redacted: false,
+            original_size: None,
+            decompressed: false,
+            ouger_skipped: false,
+            extension: None,
+            value_in_dir: false,
+            renamed: false,
+        }
+    }
+}
+
+impl Manifest {
+    pub(crate) fn write(&self, output: &Output) -> Result<()> {
+        output
+            .write(
+                MANIFEST_FILE_NAME,
+                &serde_json::to_vec_pretty(self).context("serializing manifest")?,
+            )
+            .context("writing manifest")
+    }
+
+    /// The manifest of the dump in dump_dir, None if it has none
+    pub(crate) fn read(dump_dir: &Path) -> Result<Option<Self>> {
+        let path = dump_dir.join(MANIFEST_FILE_NAME);
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("reading {}", path.display())),
+        };
+
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .context(format!("parsing {}", path.display()))
+    }
+}
+
+impl Sidecar {
+    pub(crate) fn new(kv: &KeyValue) -> Self {
+        Self {
+            create_revision: kv.create_revision(),
+            mod_revision: kv.mod_revision(),
+            version: kv.version(),
+            lease: kv.lease(),
+        }
+    }
+
+    pub(crate) fn path(value_path: &str) -> String {
+        format!("{}{}", value_path, SIDECAR_SUFFIX)
+    }
+
+    pub(crate) fn write(&self, output: &Output, value_path: &str) -> Result<()> {
+        output
+            .write(
+                &Self::path(value_path),
+                &serde_json::to_vec_pretty(self).context("serializing sidecar")?,
+            )
+            .context(format!("writing sidecar of {}", value_path))
+    }
+
+    /// The sidecar of the value file at value_path, None if it has none
+    pub(crate) fn read(value_path: &Path) -> Result<Option<Self>> {
+        let mut path = value_path.as_os_str().to_owned();
+        path.push(SIDECAR_SUFFIX);
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("reading {}", Path::new(&path).display())),
+        };
+
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .context(format!("parsing {}", Path::new(&path).display()))
+    }
+}
+
+impl ClusterInfo {
+    pub(crate) fn write(&self, output: &Output) -> Result<()> {
+        output
+            .write(
+                CLUSTER_INFO_FILE_NAME,
+                &serde_json::to_vec_pretty(self).context("serializing cluster info")?,
+            )
+            .context("writing cluster info")
+    }
+}
+
+impl AuthInfo {
+    pub(crate) fn write(&self, output: &Output) -> Result<()> {
+        output
+            .write(
+                AUTH_FILE_NAME,
+                &serde_json::to_vec_pretty(self).context("serializing auth info")?,
+            )
+            .context("writing auth info")
+    }
+}
+
+pub(crate) fn write_leases(output: &Output, leases: &[LeaseInfo]) -> Result<()> {
+    output
+        .write(
+            LEASES_FILE_NAME,
+            &serde_json::to_vec_pretty(leases).context("serializing leases")?,
+        )
+        .context("writing leases")
+}
+
+pub(crate) fn write_errors(output: &Output, errors: &[KeyError]) -> Result<()> {
+    output
+        .write(
+            ERRORS_FILE_NAME,
+            &serde_json::to_vec_pretty(errors).context("serializing errors")?,
+        )
+        .context("writing errors")
+}
+
+/// The errors of the dump in dump_dir, None if it has no errors file
+pub(crate) fn read_errors(dump_dir: &Path) -> Result<Option<Vec<KeyError>>> {
+    let path = dump_dir.join(ERRORS_FILE_NAME);
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context(format!("reading {}", path.display())),
+    };
+
+    serde_json::from_slice(&contents)
+        .map(Some)
+        .context(format!("parsing {}", path.display()))
+}
+
+/// Writes the checksums of entries in the format sha256sum -c expects
+pub(crate) fn write_checksums(output: &Output, entries: &[ManifestEntry]) -> Result<()> {
+    // Keys with the same value share their file when deduplicating
+    let mut paths = HashSet::new();
+    let checksums: String = entries
+        .iter()
+        .filter(|entry| paths.insert(entry.path.as_str()))
+        .filter_map(|entry| {
+            entry
+                .sha256
+                .as_ref()
+                .map(|sha256| format!("{}  {}\n", sha256, entry.path))
+        })
+        .collect();
+
+    output
+        .write(CHECKSUMS_FILE_NAME, checksums.as_bytes())
+        .context("writing checksums")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OutputConfig;
+    use base64::prelude::*;
+    use etcd_client::proto::PbKeyValue;
+
+    /// Not UTF-8, as 0xff never is
+    const BINARY_VALUE: &[u8] = b"\x00\xffk8s\x00";
+
+    fn kv(key: &str, value: &[u8]) -> KeyValue {
+        let kv = PbKeyValue {
+            key: key.as_bytes().to_vec(),
+            create_revision: 2,
+            mod_revision: 3,
+            version: 2,
+            value: value.to_vec(),
+            lease: 0,
+        };
+        <&KeyValue>::from(&kv).clone()
+    }
+
+    #[test]
+    fn binary_values_are_flagged() {
+        let entry = ManifestEntry::new(
+            "/a".to_string(),
+            "a".to_string(),
+            &kv("/a", BINARY_VALUE),
+            BINARY_VALUE,
+        );
+        assert!(entry.binary);
+        assert_eq!(entry.value_size, BINARY_VALUE.len());
+
+        let entry = ManifestEntry::new("/b".to_string(), "b".to_string(), &kv("/b", b"{}"), b"{}");
+        assert!(!entry.binary);
+    }
+
+    #[test]
+    fn binary_values_are_written_as_base64_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "etcddump-manifest-binary-{}.json",
+            std::process::id()
+        ));
+        let output = OutputConfig::JsonArray(Some(path.clone()))
+            .create(false)
+            .unwrap();
+        output
+            .write_line("/a", &kv("/a", BINARY_VALUE), BINARY_VALUE)
+            .unwrap();
+        output.write_line("/b", &kv("/b", b"{}"), b"{}").unwrap();
+        output.finish().unwrap();
+
+        let lines: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            lines,
+            serde_json::json!([
+                {"key": "/a", "value_b64": BASE64_STANDARD.encode(BINARY_VALUE)},
+                {"key": "/b", "value": {}},
+            ])
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use crate::manifest::{self, KeyError, ManifestEntry};
+use crate::output::{self, OutputConfig};
+use anyhow::{ensure, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// What merging dumps did
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    /// How many files were copied, sidecars and raw copies included
+    pub files: usize,
+    /// How many keys the merged manifest lists
+    pub keys: usize,
+    /// Files that several dumps have with different contents, taken from the last of them
+    pub overwritten: Vec<String>,
+}
+
+/// Copies the files of every dump of input_dirs into the empty output_dir, e.g. the shards of a
+/// dump split by key range, with a manifest and checksums covering all of them. Every dump is
+/// checked against its checksums first. Several dumps having different files at the same path
+/// fails the merge before anything is written, unless force, then the last of them wins
+pub fn merge_dumps(output_dir: &Path, input_dirs: &[PathBuf], force: bool) -> Result<MergeReport> {
+    ensure!(
+        std::fs::read_dir(output_dir)
+            .context(format!("reading {}", output_dir.display()))?
+            .next()
+            .is_none(),
+        "{} is not empty",
+        output_dir.display()
+    );
+
+    let mut report = MergeReport::default();
+    // The dump every file is taken from
+    let mut sources: BTreeMap<String, &Path> = BTreeMap::new();
+    for input_dir in input_dirs {
+        verify_checksums(input_dir)?;
+        for relative_path in value_files(input_dir)? {
+            if let Some(previous_dir) = sources.get(relative_path.as_str()) {
+                if std::fs::read(previous_dir.join(&relative_path))?
+                    == std::fs::read(input_dir.join(&relative_path))?
+                {
+                    continue;
+                }
+                ensure!(
+                    force,
+                    "{} differs between {} and {}, pass --force to take it from the last dump",
+                    relative_path,
+                    previous_dir.display(),
+                    input_dir.display()
+                );
+                tracing::warn!(
+                    path = relative_path,
+                    from = %input_dir.display(),
+                    "taking conflicting file from the last dump that has it"
+                );
+                report.overwritten.push(relative_path.clone());
+            }
+            sources.insert(relative_path, input_dir);
+        }
+    }
+
+    // A key is described by the dump its file is taken from, and failed or undecodable keys that
+    // another dump has a file for are not reported anymore
+    let mut entries: BTreeMap<String, ManifestEntry> = BTreeMap::new();
+    let mut undecodable: BTreeMap<String, KeyError> = BTreeMap::new();
+    let mut errors: Option<BTreeMap<String, KeyError>> = None;
+    let mut strip_prefix = None;
+    let mut complete = true;
+    let mut dedup = false;
+    let mut compress_files = false;
+    for input_dir in input_dirs {
+        if let Some(manifest) = manifest::Manifest::read(input_dir)? {
+            strip_prefix = strip_prefix.or(manifest.strip_prefix);
+            complete &= manifest.complete;
+            dedup |= manifest.dedup;
+            compress_files |= manifest.compress_files;
+            for entry in manifest.keys {
+                if !entries.contains_key(&entry.key)
+                    || sources.get(entry.path.as_str()) == Some(&input_dir.as_path())
+                {
+                    entries.insert(entry.key.clone(), entry);
+                }
+            }
+            for error in manifest.undecodable {
+                undecodable.entry(error.key.clone()).or_insert(error);
+            }
+        }
+        if let Some(input_errors) = manifest::read_errors(input_dir)? {
+            let errors = errors.get_or_insert_with(BTreeMap::new);
+            for error in input_errors {
+                errors.entry(error.key.clone()).or_insert(error);
+            }
+        }
+    }
+    undecodable.retain(|key, _| !entries.contains_key(key));
+    if let Some(errors) = &mut errors {
+        errors.retain(|key, _| !entries.contains_key(key));
+    }
+
+    let output = OutputConfig::Dir(output_dir.to_path_buf()).create(false)?;
+    for (relative_path, input_dir) in &sources {
+        let path = input_dir.join(relative_path);
+        let value = std::fs::read(&path).context(format!("reading {}", path.display()))?;
+        output.write(relative_path, &value)?;
+        report.files += 1;
+    }
+    // They describe the cluster rather than the keys, so any dump's are as good as another's
+    for name in [
+        manifest::CLUSTER_INFO_FILE_NAME,
+        manifest::AUTH_FILE_NAME,
+        manifest::LEASES_FILE_NAME,
+    ] {
+        if let Some(path) = input_dirs
+            .iter()
+            .map(|input_dir| input_dir.join(name))
+            .find(|path| path.is_file())
+        {
+            let value = std::fs::read(&path).context(format!("reading {}", path.display()))?;
+            output.write(name, &value)?;
+        }
+    }
+
+    let entries: Vec<ManifestEntry> = entries.into_values().collect();
+    if entries.iter().any(|entry| entry.sha256.is_some()) {
+        manifest::write_checksums(&output, &entries)?;
+    }
+    if let Some(errors) = errors {
+        manifest::write_errors(&output, &errors.into_values().collect::<Vec<_>>())?;
+    }
+    report.keys = entries.len();
+    manifest::Manifest {
+        keys: entries,
+        undecodable: undecodable.into_values().collect(),
+        strip_prefix,
+        complete,
+        dedup,
+        compress_files,
+    }
+    .write(&output)?;
+    output.finish()?;
+
+    tracing::info!(
+        count = report.keys,
+        files = report.files,
+        dumps = input_dirs.len(),
+        "merged dumps"
+    );
+    Ok(report)
+}
+
+/// The paths of every file of the dump in dump_dir but its metadata files, relative to it
+fn value_files(dump_dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(dump_dir).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let relative_path = entry.path().strip_prefix(dump_dir)?;
+        let relative_path = relative_path
+            .to_str()
+            .context(format!("non UTF-8 path {}", relative_path.display()))?;
+        if manifest::METADATA_FILE_NAMES.contains(&relative_path)
+            || relative_path.ends_with(output::TEMP_FILE_SUFFIX)
+        {
+            continue;
+        }
+        files.push(relative_path.to_string());
+    }
+
+    Ok(files)
+}
+
+/// Fails unless every file the checksums of the dump in dump_dir list has its checksum. Dumps
+/// without checksums pass
+fn verify_checksums(dump_dir: &Path) -> Result<()> {
+    let path = dump_dir.join(manifest::CHECKSUMS_FILE_NAME);
+    let checksums = match std::fs::read_to_string(&path) {
+        Ok(checksums) => checksums,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(format!("reading {}", path.display())),
+    };
+
+    for line in checksums.lines() {
+        let (sha256, relative_path) = line.split_once("  ").context(format!(
+            "malformed line in {}: {}",
+            path.display(),
+            line
+        ))?;
+        let file = dump_dir.join(relative_path);
+        let contents = std::fs::read(&file).context(format!("reading {}", file.display()))?;
+        ensure!(
+            format!("{:x}", Sha256::digest(&contents)) == sha256,
+            "{} doesn't match its checksum in {}",
+            file.display(),
+            path.display()
+        );
+    }
+    tracing::debug!(dump = %dump_dir.display(), "checksums match");
+
+    Ok(())
+}
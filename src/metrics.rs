@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Counters of a dumper, shared with the dumper they were obtained from and rendered in the
+/// Prometheus text format
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    keys_dumped: AtomicU64,
+    bytes_written: AtomicU64,
+    decode_errors: AtomicU64,
+    etcd_retries: AtomicU64,
+    keys_in_flight: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn key_written(&self, bytes: usize) {
+        self.0.keys_dumped.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn decode_error(&self) {
+        self.0.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn etcd_retry(&self) {
+        self.0.etcd_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fetch_started(&self) {
+        self.0.keys_in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fetch_done(&self) {
+        self.0.keys_in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// How many keys are being fetched and decoded right now
+    pub fn keys_in_flight(&self) -> u64 {
+        self.0.keys_in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The total size of the values written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.0.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// The current values in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "etcddump_keys_dumped_total",
+                "counter",
+                "Keys written to the output",
+                &self.0.keys_dumped,
+            ),
+            (
+                "etcddump_bytes_written_total",
+                "counter",
+                "Bytes of values written to the output",
+                &self.0.bytes_written,
+            ),
+            (
+                "etcddump_decode_errors_total",
+                "counter",
+                "Values ouger failed to decode",
+                &self.0.decode_errors,
+            ),
+            (
+                "etcddump_etcd_retries_total",
+                "counter",
+                "etcd gets retried after a transient failure",
+                &self.0.etcd_retries,
+            ),
+            (
+                "etcddump_keys_in_flight",
+                "gauge",
+                "Keys being fetched and decoded right now",
+                &self.0.keys_in_flight,
+            ),
+        ];
+
+        let mut rendered = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(rendered, "# HELP {} {}", name, help);
+            let _ = writeln!(rendered, "# TYPE {} {}", name, kind);
+            let _ = writeln!(rendered, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        rendered
+    }
+
+    /// Answers every HTTP request accepted by listener with the rendered metrics, whatever its
+    /// path. Never returns unless accepting fails
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (mut stream, _) = listener
+                .accept()
+                .await
+                .context("accepting metrics connection")?;
+
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                // Only one request per connection, its contents don't matter
+                let mut request = [0; 1024];
+                if stream.read(&mut request).await.is_err() {
+                    return;
+                }
+
+                let body = metrics.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                if let Err(err) = stream.write_all(response.as_bytes()).await {
+                    tracing::debug!("Could not answer metrics request: {}", err);
+                }
+            });
+        }
+    }
+}
@@ -1,26 +1,207 @@
-use anyhow::{ensure, Context, Result};
-use reqwest::Client;
+use crate::retry::RetryPolicy;
+use anyhow::{bail, ensure, Context, Result};
+use futures::future;
+use reqwest::{Client, StatusCode, Url};
+use std::fmt;
+use std::net::TcpListener;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-pub(crate) const OUGER_SERVER_PORT: u16 = 9998;
+pub const DEFAULT_OUGER_BIN: &str = "ouger_server";
 
-pub(crate) async fn ouger(
+/// The Go module ouger is built from, go install records its version in the binary
+const OUGER_MODULE: &str = "github.com/rh-ecosystem-edge/ouger";
+
+/// The ouger versions etcddump is known to decode values right with. ouger has only ever had v0
+/// releases, a v1 may change what values are decoded to
+const COMPATIBLE_OUGER_VERSIONS: Range<(u64, u64, u64)> = (0, 0, 0)..(1, 0, 0);
+
+/// How to run the ouger server
+pub struct OugerConfig {
+    /// Either a path or a name to look up in PATH
+    pub bin: String,
+    /// Defaults to a random free port. With several workers, they listen on consecutive ports
+    /// starting with this one
+    pub port: Option<u16>,
+    /// How many ouger servers to launch and spread calls over
+    pub workers: NonZeroUsize,
+    /// An ouger server that is already running, e.g. shared between several dumps. Nothing is
+    /// launched when set
+    pub url: Option<Url>,
+    /// Applied to ouger calls failing to reach the server
+    pub retry_policy: RetryPolicy,
+    /// How many idle connections to the server to keep around, defaults to the concurrency of the
+    /// command
+    pub connections: Option<usize>,
+    /// The exact version the launched ouger must be, e.g. v0.3.1, instead of any of the known
+    /// compatible ones
+    pub version: Option<String>,
+    /// Fail to launch ouger when its version isn't the expected one, instead of warning
+    pub require_version: bool,
+}
+
+/// A single server from PATH on a random port, without retries
+impl Default for OugerConfig {
+    fn default() -> Self {
+        Self {
+            bin: DEFAULT_OUGER_BIN.to_string(),
+            port: None,
+            workers: NonZeroUsize::MIN,
+            url: None,
+            retry_policy: RetryPolicy::none(),
+            connections: None,
+            version: None,
+            require_version: false,
+        }
+    }
+}
+
+impl OugerConfig {
+    /// Launches the ouger servers unless a remote one is configured, returning the pool of servers
+    /// to spread calls over
+    pub async fn launch(&self) -> Result<OugerPool> {
+        if let Some(url) = &self.url {
+            tracing::info!(%url, "using remote ouger server");
+            return Ok(OugerPool::new(
+                vec![url.as_str().trim_end_matches('/').to_string()],
+                Vec::new(),
+            ));
+        }
+
+        let ports = match self.port {
+            Some(port) => (0..self.workers.get())
+                .map(|worker| u16::try_from(worker).ok()?.checked_add(port))
+                .collect::<Option<Vec<_>>>()
+                .context("not enough ports above the ouger port for every worker")?,
+            None => free_ports(self.workers.get()).context("picking ouger server ports")?,
+        };
+
+        self.check_version().context(LaunchError)?;
+        tracing::info!(?ports, "launching ouger servers");
+        let child_processes = future::try_join_all(
+            ports
+                .iter()
+                .map(|port| launch_ouger_server(&self.bin, *port)),
+        )
+        .await
+        .context(LaunchError)?;
+
+        Ok(OugerPool::new(
+            ports.into_iter().map(local_url).collect(),
+            child_processes,
+        ))
+    }
+
+    /// Warns, or fails when the version is required, if the ouger binary isn't of the expected
+    /// version, as other versions may decode values differently without any error
+    fn check_version(&self) -> Result<()> {
+        let bin_path =
+            which::which(&self.bin).context(format!("finding ouger binary {}", self.bin))?;
+        let version = binary_version(&bin_path)?;
+        let problem = match (&version, &self.version) {
+            (None, _) => format!(
+                "can't tell the version of {}, it wasn't built by go install",
+                bin_path.display()
+            ),
+            (Some(version), Some(expected))
+                if version.trim_start_matches('v') != expected.trim_start_matches('v') =>
+            {
+                format!(
+                    "{} is ouger {}, not the expected {}",
+                    bin_path.display(),
+                    version,
+                    expected
+                )
+            }
+            (Some(version), None) if !is_compatible(version) => {
+                format!(
+                    "{} is ouger {}, which etcddump isn't known to work with, values may be \
+                     decoded wrong",
+                    bin_path.display(),
+                    version
+                )
+            }
+            (Some(version), _) => {
+                tracing::debug!(version, "ouger version");
+                return Ok(());
+            }
+        };
+
+        ensure!(!self.require_version, "{}", problem);
+        tracing::warn!("{}", problem);
+        Ok(())
+    }
+
+    /// A client keeping enough connections to the server alive for concurrency calls at once.
+    /// ouger only speaks HTTP/1.1, so every call in flight needs a connection of its own
+    pub fn client(&self, concurrency: usize) -> Result<Client> {
+        let builder = Client::builder()
+            .pool_max_idle_per_host(self.connections.unwrap_or(concurrency))
+            // Nothing but ouger itself can close connections to localhost
+            .pool_idle_timeout(None)
+            .tcp_nodelay(true);
+        // A remote server is reached through the proxies of the environment like any URL, but
+        // they must not catch the servers launched on localhost
+        let builder = match self.url {
+            Some(_) => builder,
+            None => builder.no_proxy(),
+        };
+        builder.build().context("building ouger client")
+    }
+}
+
+/// How long to wait for a freshly launched ouger server to answer health checks
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for ouger to exit after SIGTERM before resorting to SIGKILL
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// The conversions the ouger server offers
+#[derive(Clone, Copy, Debug)]
+pub enum OugerCommand {
+    /// Raw etcd value to JSON
+    Decode,
+    /// JSON back to a raw etcd value
+    Encode,
+}
+
+impl OugerCommand {
+    fn path(self) -> &'static str {
+        match self {
+            OugerCommand::Decode => "decode",
+            OugerCommand::Encode => "encode",
+        }
+    }
+}
+
+/// The base URL of an ouger server listening on port on this host
+pub fn local_url(port: u16) -> String {
+    format!("http://localhost:{port}")
+}
+
+/// Converts value with the ouger server at base_url
+pub async fn ouger(
     client: &Client,
-    ouger_path: &str,
-    raw_etcd_value: &[u8],
+    base_url: &str,
+    command: OugerCommand,
+    value: &[u8],
 ) -> Result<Vec<u8>> {
+    tracing::trace!(?command, size = value.len(), "calling ouger");
     let res = client
-        .post(format!("http://localhost:{OUGER_SERVER_PORT}/{ouger_path}"))
-        .body(raw_etcd_value.to_vec())
+        .post(format!("{base_url}/{}", command.path()))
+        .body(value.to_vec())
         .send()
         .await
         .context("ouger server not running")?;
 
-    ensure!(
-        res.status().is_success(),
-        "ouger server returned non-success status code: {}",
-        res.status()
-    );
+    if !res.status().is_success() {
+        return Err(ConversionError(res.status()).into());
+    }
     ensure!(
         res.content_length().is_some(),
         "ouger server returned no content length"
@@ -29,42 +210,312 @@ pub(crate) async fn ouger(
     Ok(res.bytes().await?.to_vec())
 }
 
-pub(crate) struct OugerChildProcess(Child);
+/// Asks the ouger server at base_url whether it's healthy
+pub async fn health_check(client: &Client, base_url: &str) -> Result<()> {
+    let res = client
+        .get(format!("{base_url}/healthz"))
+        .send()
+        .await
+        .context(format!("reaching the ouger server at {}", base_url))?;
+    ensure!(
+        res.status().is_success(),
+        "ouger server at {} is unhealthy, it answered {}",
+        base_url,
+        res.status()
+    );
+
+    Ok(())
+}
+
+/// The ouger server was reached but couldn't convert the value, e.g. because it's not in an
+/// encoding ouger knows
+#[derive(Debug)]
+pub struct ConversionError(pub StatusCode);
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ouger server returned non-success status code: {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Context of every error launching the ouger server, which can be downcast to it to tell those
+/// apart from other failures
+#[derive(Debug)]
+pub struct LaunchError;
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "launching ouger server")
+    }
+}
+
+/// Whether a failed ouger call is worth retrying. Only transport failures are, an error status
+/// from the server itself is deterministic for a given value
+pub(crate) fn is_retriable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|err| err.is_connect() || err.is_timeout() || err.is_request())
+}
+
+/// The ouger servers of a dump or restore, calls are spread over them round-robin. Launched
+/// servers are shut down when the pool is dropped
+pub struct OugerPool {
+    urls: Vec<String>,
+    next: AtomicUsize,
+    _child_processes: Vec<OugerChildProcess>,
+}
+
+impl OugerPool {
+    fn new(urls: Vec<String>, child_processes: Vec<OugerChildProcess>) -> Self {
+        Self {
+            urls,
+            next: AtomicUsize::new(0),
+            _child_processes: child_processes,
+        }
+    }
+
+    /// The base URL of the server to send the next call to
+    pub fn url(&self) -> &str {
+        &self.urls[self.next.fetch_add(1, Ordering::Relaxed) % self.urls.len()]
+    }
+}
+
+/// A running ouger server, shut down when dropped
+pub struct OugerChildProcess(Child);
+
+impl OugerChildProcess {
+    fn exited(&mut self) -> bool {
+        matches!(self.0.try_wait(), Ok(Some(_)))
+    }
+
+    pub fn shutdown(&mut self) {
+        if self.exited() {
+            return;
+        }
+
+        if unsafe { libc::kill(self.0.id() as libc::pid_t, libc::SIGTERM) } == 0 {
+            let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+            while Instant::now() < deadline {
+                if self.exited() {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
 
-impl Drop for OugerChildProcess {
-    fn drop(&mut self) {
         if let Err(e) = self.0.kill() {
-            println!("Could not kill child process: {}", e)
+            tracing::warn!("Could not kill child process: {}", e)
         }
+        let _ = self.0.wait();
+    }
+}
+
+impl Drop for OugerChildProcess {
+    fn drop(&mut self) {
+        self.shutdown();
     }
 }
 
-pub(crate) async fn wait_for_ouger() {
-    let mut tries = 0;
-    while tries < 100 {
-        if Client::new()
-            .get(format!("http://localhost:{OUGER_SERVER_PORT}/healthz"))
+/// Polls the health endpoint of the ouger server until it answers successfully, giving up early if
+/// the server exits
+pub async fn wait_for_ouger(port: u16, ouger_child_process: &mut OugerChildProcess) -> Result<()> {
+    // Environment proxies must not catch the server launched on localhost
+    let client = Client::builder()
+        .no_proxy()
+        .build()
+        .context("building ouger client")?;
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+
+    while Instant::now() < deadline {
+        ensure!(
+            !ouger_child_process.exited(),
+            "ouger server exited before becoming ready"
+        );
+
+        match client
+            .get(format!("{}/healthz", local_url(port)))
+            .timeout(Duration::from_secs(1))
             .send()
             .await
-            .is_ok()
         {
-            return;
+            Ok(res) if res.status().is_success() => return Ok(()),
+            Ok(res) => tracing::debug!(status = %res.status(), "ouger server not ready yet"),
+            Err(err) => tracing::trace!("ouger server not ready yet: {}", err),
         }
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        tries += 1;
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
 
-    panic!("Ouger server did not start in time");
+    bail!(
+        "ouger server did not become ready within {:?}",
+        STARTUP_TIMEOUT
+    )
 }
 
-pub(crate) async fn launch_ouger_server() -> Result<OugerChildProcess> {
-    let ouger_child_process = OugerChildProcess(
-        Command::new("ouger_server")
-            .args(["--port", &OUGER_SERVER_PORT.to_string()])
+/// The version of ouger the binary at path was built from, as recorded in its Go build info.
+/// None if it has none, e.g. when built from a checkout rather than installed
+fn binary_version(path: &Path) -> Result<Option<String>> {
+    let binary = std::fs::read(path).context(format!("reading {}", path.display()))?;
+    let marker = format!("\nmod\t{}\t", OUGER_MODULE);
+    let Some(start) = binary
+        .windows(marker.len())
+        .position(|window| window == marker.as_bytes())
+    else {
+        return Ok(None);
+    };
+    let version = &binary[start + marker.len()..];
+    let version = &version[..version
+        .iter()
+        .position(|&byte| byte == b'\t' || byte == b'\n')
+        .unwrap_or(version.len())];
+
+    Ok(std::str::from_utf8(version)
+        .ok()
+        .filter(|version| *version != "(devel)")
+        .map(str::to_string))
+}
+
+/// The major, minor and patch numbers of a Go module version like v0.3.1 or a pseudo-version
+/// like v0.0.0-20240101000000-0123456789ab
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.strip_prefix('v')?;
+    let version = version.split(['-', '+']).next()?;
+    let mut numbers = version.split('.').map(|number| number.parse().ok());
+    let parsed = (numbers.next()??, numbers.next()??, numbers.next()??);
+    numbers.next().is_none().then_some(parsed)
+}
+
+/// Whether version is one of COMPATIBLE_OUGER_VERSIONS
+fn is_compatible(version: &str) -> bool {
+    parse_version(version).is_some_and(|version| COMPATIBLE_OUGER_VERSIONS.contains(&version))
+}
+
+/// Finds a port that is currently free for the ouger server to listen on
+pub fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind(("localhost", 0))
+        .context("binding to a random port")?
+        .local_addr()?
+        .port())
+}
+
+/// Finds count distinct ports that are currently free, holding on to each of them until all are
+/// found
+fn free_ports(count: usize) -> Result<Vec<u16>> {
+    let listeners = (0..count)
+        .map(|_| TcpListener::bind(("localhost", 0)).context("binding to a random port"))
+        .collect::<Result<Vec<_>>>()?;
+
+    listeners
+        .iter()
+        .map(|listener| Ok(listener.local_addr()?.port()))
+        .collect()
+}
+
+pub async fn launch_ouger_server(ouger_bin: &str, port: u16) -> Result<OugerChildProcess> {
+    let ouger_bin_path =
+        which::which(ouger_bin).context(format!("finding ouger binary {}", ouger_bin))?;
+
+    let mut ouger_child_process = OugerChildProcess(
+        Command::new(&ouger_bin_path)
+            .args(["--port", &port.to_string()])
+            // Out of the process group of the terminal, so that Ctrl-C doesn't kill it before
+            // interrupted dumps are done with it, it's shut down along with them
+            .process_group(0)
             .spawn()
-            .context("running ouger_server binary")?,
+            .context(format!("running {}", ouger_bin_path.display()))?,
     );
-    wait_for_ouger().await;
+    wait_for_ouger(port, &mut ouger_child_process).await?;
     Ok(ouger_child_process)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("etcddump-ouger-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The version of a binary with build info recording the ouger module at version
+    fn version_of(name: &str, build_info: &str) -> Option<String> {
+        let dir = test_dir(name);
+        let path = dir.join("ouger_server");
+        let mut binary = b"\x7fELF\x00garbage\x00".to_vec();
+        binary.extend_from_slice(build_info.as_bytes());
+        binary.extend_from_slice(b"\x00more garbage");
+        std::fs::write(&path, binary).unwrap();
+
+        let version = binary_version(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        version
+    }
+
+    #[test]
+    fn versions_are_found_in_build_info() {
+        let build_info = format!(
+            "path\t{}/cmd/server\nmod\t{}\tv0.3.1\th1:abc=\ndep\tk8s.io/api\tv0.29.0\n",
+            OUGER_MODULE, OUGER_MODULE
+        );
+        assert_eq!(
+            version_of("release", &build_info),
+            Some("v0.3.1".to_string())
+        );
+
+        let build_info = format!(
+            "\nmod\t{}\tv0.0.0-20240101000000-0123456789ab\n",
+            OUGER_MODULE
+        );
+        assert_eq!(
+            version_of("pseudo", &build_info),
+            Some("v0.0.0-20240101000000-0123456789ab".to_string())
+        );
+
+        // Built from a checkout
+        let build_info = format!("\nmod\t{}\t(devel)\t\n", OUGER_MODULE);
+        assert_eq!(version_of("devel", &build_info), None);
+
+        // Stripped of its build info, or not ouger at all
+        assert_eq!(
+            version_of("missing", "\nmod\tgithub.com/other/tool\tv0.3.1\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn versions_are_parsed() {
+        assert_eq!(parse_version("v0.3.1"), Some((0, 3, 1)));
+        assert_eq!(
+            parse_version("v0.0.0-20240101000000-0123456789ab"),
+            Some((0, 0, 0))
+        );
+        assert_eq!(
+            parse_version("v0.3.2-0.20240101000000-0123456789ab"),
+            Some((0, 3, 2))
+        );
+        assert_eq!(parse_version("v1.2.3+incompatible"), Some((1, 2, 3)));
+        assert_eq!(parse_version("0.3.1"), None);
+        assert_eq!(parse_version("v0.3"), None);
+        assert_eq!(parse_version("v0.3.1.4"), None);
+        assert_eq!(parse_version("(devel)"), None);
+    }
+
+    #[test]
+    fn only_v0_is_compatible() {
+        assert!(is_compatible("v0.0.0-20240101000000-0123456789ab"));
+        assert!(is_compatible("v0.3.1"));
+        assert!(is_compatible("v0.999.999"));
+        assert!(!is_compatible("v1.0.0"));
+        assert!(!is_compatible("v1.0.0-rc.1"));
+        assert!(!is_compatible("v2.1.0"));
+        assert!(!is_compatible("(devel)"));
+    }
+}
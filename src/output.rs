@@ -0,0 +1,705 @@
+use crate::s3::{S3Config, S3Output};
+use crate::snapshot;
+use crate::utc::UtcTime;
+use anyhow::{bail, ensure, Context, Result};
+use base64::prelude::*;
+use etcd_client::KeyValue;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Seek, SeekFrom, Stdout, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Appended to the name of files while they are being written. Key paths always have their % signs
+/// percent-encoded, so no key can end up with this suffix
+pub(crate) const TEMP_FILE_SUFFIX: &str = "%tmp";
+
+/// The tokens an output dir template can contain
+const TEMPLATE_TOKENS: &str = "{date}, {time} and {cluster_id}";
+
+/// Whether output_dir is a template with tokens to expand before dumping into it
+pub fn is_template(output_dir: &str) -> bool {
+    output_dir.contains('{')
+}
+
+/// Expands the tokens of an output dir template: {date} and {time} to now in UTC, like 2023-09-01
+/// and 123456, and {cluster_id} to cluster_id, which only the templates using it need
+pub(crate) fn expand_template(
+    template: &str,
+    now: SystemTime,
+    cluster_id: Option<&str>,
+) -> Result<String> {
+    let now = UtcTime::from(now);
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .context(format!("unterminated token in {}", template))?
+            + start;
+        match &rest[start + 1..end] {
+            "date" => {
+                expanded.push_str(&format!("{:04}-{:02}-{:02}", now.year, now.month, now.day))
+            }
+            "time" => expanded.push_str(&format!(
+                "{:02}{:02}{:02}",
+                now.hour, now.minute, now.second
+            )),
+            "cluster_id" => {
+                expanded.push_str(cluster_id.context("no cluster id to expand {cluster_id} to")?)
+            }
+            token => bail!(
+                "unknown token {{{}}} in {}, only {} are",
+                token,
+                template,
+                TEMPLATE_TOKENS
+            ),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Where to write a dump
+pub enum OutputConfig {
+    /// One file per key under an existing dir
+    Dir(PathBuf),
+    /// A single tar archive, gzip compressed if the path ends with .gz
+    Archive(PathBuf),
+    /// Newline delimited JSON on stdout, one object per key and no metadata files
+    Stdout,
+    /// A single JSON array of all keys sorted by key, written to the file or to stdout once the
+    /// dump is done. No metadata files either
+    JsonArray(Option<PathBuf>),
+    /// One object per file, laid out like under a dir. Needs a multi-threaded tokio runtime
+    S3(Box<S3Config>),
+    /// An etcd snapshot of the keys as stored in etcd, written to the file once the dump is done.
+    /// No metadata files either
+    Snapshot(PathBuf),
+    /// A named pipe, created if it doesn't exist, that every key is written to as a frame the
+    /// moment it's done. No metadata files either
+    Pipe(PathBuf),
+}
+
+impl OutputConfig {
+    /// Unless follow_symlinks, files are never written through symlinks found under a dir
+    pub(crate) fn create(&self, follow_symlinks: bool) -> Result<Output> {
+        match self {
+            OutputConfig::Dir(output_dir) => Ok(Output::Dir {
+                root: output_dir
+                    .canonicalize()
+                    .context(format!("resolving {}", output_dir.display()))?,
+                follow_symlinks,
+            }),
+            OutputConfig::Archive(archive) => Output::archive(archive),
+            OutputConfig::Stdout => Ok(Output::Stdout(Mutex::new(BufWriter::new(
+                std::io::stdout(),
+            )))),
+            OutputConfig::JsonArray(path) => Ok(Output::JsonArray {
+                path: path.clone(),
+                lines: Mutex::new(Vec::new()),
+            }),
+            OutputConfig::S3(config) => Ok(Output::S3(S3Output::new(config)?)),
+            OutputConfig::Snapshot(path) => Ok(Output::Snapshot {
+                path: path.clone(),
+                kvs: Mutex::new(Vec::new()),
+            }),
+            OutputConfig::Pipe(path) => Output::pipe(path),
+        }
+    }
+
+    /// Like create, but keeps what an interrupted dump already wrote to add more files to it.
+    /// Compressed archives and JSON arrays can't be added to
+    pub(crate) fn reopen(&self, follow_symlinks: bool) -> Result<Output> {
+        match self {
+            OutputConfig::Archive(archive) => Output::reopen_archive(archive),
+            OutputConfig::JsonArray(_) => bail!("JSON arrays can't be resumed"),
+            OutputConfig::Snapshot(_) => bail!("snapshots can't be resumed"),
+            _ => self.create(follow_symlinks),
+        }
+    }
+}
+
+/// How the files of keys are arranged under the root of a dump
+#[derive(Clone, Copy, Debug)]
+pub enum Layout {
+    /// Every slash of a key starts a subdirectory
+    Nested,
+    /// Every key is a single file directly under the root, named after the percent-encoded key
+    Flat,
+}
+
+/// How JSON values decoded by ouger are written, values that aren't JSON are always written as-is
+#[derive(Clone, Copy, Debug)]
+pub enum JsonFormat {
+    /// Indented, one field per line
+    Pretty,
+    /// No whitespace at all
+    Compact,
+    /// Exactly as returned by ouger
+    Raw,
+}
+
+impl JsonFormat {
+    /// Re-serializes value if it's JSON, keeping the order of object fields
+    pub(crate) fn apply(self, value: Vec<u8>) -> Vec<u8> {
+        let serialize = match self {
+            JsonFormat::Pretty => serde_json::to_vec_pretty::<serde_json::Value>,
+            JsonFormat::Compact => serde_json::to_vec::<serde_json::Value>,
+            JsonFormat::Raw => return value,
+        };
+
+        match serde_json::from_slice(&value) {
+            Ok(json) => serialize(&json).unwrap_or(value),
+            Err(_) => value,
+        }
+    }
+}
+
+/// Sorts the fields of every object of value by name if it's JSON, writing it compactly, so that
+/// values only differing in field order end up byte for byte the same. Other values are left as-is
+pub(crate) fn sort_json_fields(value: Vec<u8>) -> Vec<u8> {
+    match serde_json::from_slice(&value) {
+        Ok(mut json) => {
+            sort_fields(&mut json);
+            serde_json::to_vec(&json).unwrap_or(value)
+        }
+        Err(_) => value,
+    }
+}
+
+fn sort_fields(json: &mut serde_json::Value) {
+    match json {
+        serde_json::Value::Object(object) => {
+            let mut fields: Vec<_> = std::mem::take(object).into_iter().collect();
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (name, mut value) in fields {
+                sort_fields(&mut value);
+                object.insert(name, value);
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(sort_fields),
+        _ => {}
+    }
+}
+
+/// Where the files of a dump end up, either as plain files under a directory, as entries of a
+/// single tar archive or as objects in a bucket. Stdout, JSON arrays, snapshots and pipes don't
+/// take files, only lines
+pub(crate) enum Output {
+    Dir {
+        /// Canonical, so that symlinks are only ever found below it
+        root: PathBuf,
+        follow_symlinks: bool,
+    },
+    Archive(Mutex<tar::Builder<ArchiveWriter>>),
+    S3(S3Output),
+    Stdout(Mutex<BufWriter<Stdout>>),
+    /// Lines are held in memory until finish
+    JsonArray {
+        path: Option<PathBuf>,
+        lines: Mutex<Vec<JsonLine>>,
+    },
+    /// Keys are held in memory until finish
+    Snapshot {
+        path: PathBuf,
+        kvs: Mutex<Vec<KeyValue>>,
+    },
+    Pipe(Mutex<File>),
+}
+
+/// Context of the error writing to a pipe whose reader closed it, which ends the dump right away
+/// as nothing can be written anymore
+#[derive(Debug)]
+pub struct PipeClosed;
+
+impl std::fmt::Display for PipeClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the reader of the pipe closed it")
+    }
+}
+
+/// A line of the stdout output, or an element of a JSON array
+#[derive(Serialize)]
+pub(crate) struct JsonLine {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_b64: Option<String>,
+}
+
+pub(crate) enum ArchiveWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveWriter::Plain(writer) => writer.write(buf),
+            ArchiveWriter::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(writer) => writer.flush(),
+            ArchiveWriter::Gzip(writer) => writer.flush(),
+        }
+    }
+}
+
+impl ArchiveWriter {
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(writer) => writer.flush(),
+            ArchiveWriter::Gzip(writer) => {
+                writer.try_finish()?;
+                writer.get_mut().flush()
+            }
+        }
+    }
+}
+
+/// Creates the missing parent dirs of relative_path under root, refusing to go through a symlink
+/// so that no key, whatever its name and whatever is already under root, is written outside of it
+fn create_parent_dirs(root: &Path, relative_path: &str) -> Result<()> {
+    let Some((parents, _)) = relative_path.rsplit_once('/') else {
+        return Ok(());
+    };
+
+    let mut dir = root.to_path_buf();
+    for segment in parents.split('/') {
+        dir.push(segment);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                bail!(
+                    "refusing to write through symlink {}, pass --follow-symlinks to allow it",
+                    dir.display()
+                )
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => match std::fs::create_dir(&dir) {
+                Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+                    return Err(err).context(format!("creating {}", dir.display()))
+                }
+                _ => {}
+            },
+            Err(err) => return Err(err).context(format!("inspecting {}", dir.display())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes value to a file at path that must not exist yet, replacing what an interrupted write
+/// left there. Creating it exclusively means a symlink at path is never followed
+fn write_new(path: &Path, value: &[u8]) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?
+        .write_all(value)
+}
+
+impl Output {
+    /// Creates a tar archive at path, gzip compressed if path ends with .gz
+    pub(crate) fn archive(path: &Path) -> Result<Self> {
+        let file = BufWriter::new(
+            File::create(path).context(format!("creating archive {}", path.display()))?,
+        );
+
+        let writer = if path.extension().is_some_and(|extension| extension == "gz") {
+            ArchiveWriter::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            ArchiveWriter::Plain(file)
+        };
+
+        Ok(Output::Archive(Mutex::new(tar::Builder::new(writer))))
+    }
+
+    /// Opens the named pipe at path for writing, creating it first if there is nothing at path.
+    /// Blocks until a reader opens it too
+    pub(crate) fn pipe(path: &Path) -> Result<Self> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => ensure!(
+                metadata.file_type().is_fifo(),
+                "{} is not a named pipe",
+                path.display()
+            ),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let c_path = CString::new(path.as_os_str().as_bytes())?;
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                    return Err(std::io::Error::last_os_error())
+                        .context(format!("creating pipe {}", path.display()));
+                }
+            }
+            Err(err) => return Err(err).context(format!("inspecting {}", path.display())),
+        }
+
+        tracing::info!(path = %path.display(), "waiting for a reader to open the pipe");
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .context(format!("opening pipe {}", path.display()))?;
+        Ok(Output::Pipe(Mutex::new(file)))
+    }
+
+    /// Opens the uncompressed tar archive at path to append to it, dropping any entry an
+    /// interrupted dump left half written along with the end of archive marker
+    pub(crate) fn reopen_archive(path: &Path) -> Result<Self> {
+        if path.extension().is_some_and(|extension| extension == "gz") {
+            bail!("compressed archives can't be resumed, {}", path.display());
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .context(format!("opening archive {}", path.display()))?;
+        let len = file.metadata()?.len();
+        let mut end = 0;
+        for entry in tar::Archive::new(&file)
+            .entries_with_seek()
+            .context(format!("reading archive {}", path.display()))?
+        {
+            let Ok(entry) = entry else {
+                break;
+            };
+            let entry_end = entry.raw_file_position() + entry.size().next_multiple_of(512);
+            if entry_end > len {
+                break;
+            }
+            end = entry_end;
+        }
+        file.set_len(end)
+            .context(format!("truncating archive {}", path.display()))?;
+        file.seek(SeekFrom::Start(end))?;
+
+        Ok(Output::Archive(Mutex::new(tar::Builder::new(
+            ArchiveWriter::Plain(BufWriter::new(file)),
+        ))))
+    }
+
+    /// Whether a non-empty file was already written at relative_path, only ever true for dirs
+    pub(crate) fn has_file(&self, relative_path: &str) -> bool {
+        match self {
+            Output::Dir { root, .. } => std::fs::metadata(root.join(relative_path))
+                .is_ok_and(|metadata| metadata.is_file() && metadata.len() > 0),
+            Output::Archive(_)
+            | Output::S3(_)
+            | Output::Stdout(_)
+            | Output::JsonArray { .. }
+            | Output::Snapshot { .. }
+            | Output::Pipe(_) => false,
+        }
+    }
+
+    /// Removes the file at relative_path if there is one, only dirs support removal
+    pub(crate) fn remove(&self, relative_path: &str) -> Result<()> {
+        let Output::Dir { root, .. } = self else {
+            bail!("only dirs support removing files");
+        };
+
+        match std::fs::remove_file(root.join(relative_path)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).context(format!("removing {}", relative_path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the file at relative_path and returns what it held, None if there is none, for it to
+    /// be written elsewhere. Its dir is removed too if that leaves it empty, as the file may move to
+    /// where the dir was. Only dirs support removal
+    pub(crate) fn take(&self, relative_path: &str) -> Result<Option<Vec<u8>>> {
+        let Output::Dir { root, .. } = self else {
+            bail!("only dirs support removing files");
+        };
+
+        let path = root.join(relative_path);
+        // Symlinks aren't followed, their target isn't the value of any key
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => {}
+            Ok(_) => bail!("{} is not a file", relative_path),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("reading {}", relative_path)),
+        }
+        let value = std::fs::read(&path).context(format!("reading {}", relative_path))?;
+        std::fs::remove_file(&path).context(format!("removing {}", relative_path))?;
+
+        if let Some(dir) = path.parent().filter(|dir| *dir != root.as_path()) {
+            if std::fs::read_dir(dir)
+                .context(format!("reading {}", dir.display()))?
+                .next()
+                .is_none()
+            {
+                std::fs::remove_dir(dir).context(format!("removing {}", dir.display()))?;
+            }
+        }
+
+        Ok(Some(value))
+    }
+
+    /// Removes the dirs under the root that are empty, or only hold empty dirs, once the dump is
+    /// done, returning how many. Only dirs have dirs to prune
+    pub(crate) fn prune_empty_dirs(&self) -> Result<usize> {
+        let Output::Dir { root, .. } = self else {
+            bail!("only dirs support pruning empty dirs");
+        };
+
+        let mut pruned = 0;
+        // Children come before their parent, which is only empty once they are gone. Symlinks to
+        // dirs aren't dirs, and aren't followed either
+        for entry in walkdir::WalkDir::new(root)
+            .min_depth(1)
+            .contents_first(true)
+        {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            if std::fs::read_dir(path)
+                .context(format!("reading {}", path.display()))?
+                .next()
+                .is_none()
+            {
+                std::fs::remove_dir(path).context(format!("removing {}", path.display()))?;
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    /// Whether the output takes files, stdout, JSON arrays, snapshots and pipes only take lines
+    /// through write_line
+    pub(crate) fn is_files(&self) -> bool {
+        matches!(
+            self,
+            Output::Dir { .. } | Output::Archive(_) | Output::S3(_)
+        )
+    }
+
+    /// Appends a key to the stdout output or JSON array, as JSON if the value is JSON and base64
+    /// encoded otherwise. Snapshots take kv as stored in etcd instead of value. Pipes take a frame
+    /// of the length of the key as 4 big-endian bytes, the key, the length of the value as 8
+    /// big-endian bytes and the value
+    pub(crate) fn write_line(&self, key: &str, kv: &KeyValue, value: &[u8]) -> Result<()> {
+        match self {
+            Output::Snapshot { kvs, .. } => {
+                kvs.lock().unwrap().push(kv.clone());
+                return Ok(());
+            }
+            Output::Pipe(pipe) => {
+                let mut frame = Vec::with_capacity(12 + key.len() + value.len());
+                frame.extend_from_slice(&u32::try_from(key.len())?.to_be_bytes());
+                frame.extend_from_slice(key.as_bytes());
+                frame.extend_from_slice(&(value.len() as u64).to_be_bytes());
+                frame.extend_from_slice(value);
+                // A single write under the lock, so that frames can't interleave
+                return match pipe.lock().unwrap().write_all(&frame) {
+                    Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                        Err(anyhow::Error::new(err).context(PipeClosed))
+                    }
+                    result => result.context("writing to pipe"),
+                };
+            }
+            _ => {}
+        }
+
+        let line = match serde_json::from_slice(value) {
+            Ok(value) => JsonLine {
+                key: key.to_string(),
+                value: Some(value),
+                value_b64: None,
+            },
+            Err(_) => JsonLine {
+                key: key.to_string(),
+                value: None,
+                value_b64: Some(BASE64_STANDARD.encode(value)),
+            },
+        };
+
+        match self {
+            Output::Stdout(stdout) => {
+                let mut stdout = stdout.lock().unwrap();
+                serde_json::to_writer(&mut *stdout, &line).context("serializing line")?;
+                stdout.write_all(b"\n").context("writing to stdout")?;
+            }
+            Output::JsonArray { lines, .. } => lines.lock().unwrap().push(line),
+            Output::Dir { .. } | Output::Archive(_) | Output::S3(_) => {
+                bail!("files don't take lines")
+            }
+            Output::Snapshot { .. } | Output::Pipe(_) => {
+                unreachable!("snapshots and pipes take kvs and frames")
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn write(&self, relative_path: &str, value: &[u8]) -> Result<()> {
+        match self {
+            Output::Dir {
+                root,
+                follow_symlinks,
+            } => {
+                let output_file = root.join(relative_path);
+                if *follow_symlinks {
+                    std::fs::create_dir_all(output_file.parent().unwrap())?;
+                } else {
+                    create_parent_dirs(root, relative_path)?;
+                }
+
+                // Renaming within the same dir is atomic, so a crash never leaves a truncated
+                // file behind for --skip-existing or verify to mistake for a complete one. The
+                // rename replaces a symlink at output_file rather than writing through it
+                let temp_file = root.join(format!("{}{}", relative_path, TEMP_FILE_SUFFIX));
+                if *follow_symlinks {
+                    std::fs::write(&temp_file, value)
+                } else {
+                    write_new(&temp_file, value)
+                }
+                .context(format!("writing {}", temp_file.display()))?;
+                std::fs::rename(&temp_file, &output_file)
+                    .context(format!("renaming {}", temp_file.display()))?;
+            }
+            Output::Archive(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(value.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(
+                    SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)?
+                        .as_secs(),
+                );
+
+                builder
+                    .lock()
+                    .unwrap()
+                    .append_data(&mut header, relative_path, value)
+                    .context(format!("appending {} to archive", relative_path))?;
+            }
+            Output::S3(s3_output) => s3_output.write(relative_path, value)?,
+            Output::Stdout(_)
+            | Output::JsonArray { .. }
+            | Output::Snapshot { .. }
+            | Output::Pipe(_) => {
+                bail!("only dirs, archives and buckets take files")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure everything written so far has made it to its destination, except for JSON arrays
+    /// and snapshots which are only written by finish
+    pub(crate) fn flush(&self) -> Result<()> {
+        match self {
+            Output::Dir { .. }
+            | Output::JsonArray { .. }
+            | Output::Snapshot { .. }
+            | Output::Pipe(_) => {}
+            Output::Archive(builder) => builder
+                .lock()
+                .unwrap()
+                .get_mut()
+                .flush()
+                .context("flushing archive")?,
+            Output::S3(s3_output) => s3_output.finish()?,
+            Output::Stdout(stdout) => stdout.lock().unwrap().flush().context("flushing stdout")?,
+        }
+
+        Ok(())
+    }
+
+    /// Flushes everything written so far, must be called once all writes are done
+    pub(crate) fn finish(&self) -> Result<()> {
+        match self {
+            // Frames are written to pipes unbuffered
+            Output::Dir { .. } | Output::Pipe(_) => {}
+            Output::Archive(builder) => {
+                let mut builder = builder.lock().unwrap();
+                builder.finish().context("finishing archive")?;
+                builder.get_mut().finish().context("flushing archive")?;
+            }
+            Output::S3(s3_output) => s3_output.finish()?,
+            Output::Stdout(stdout) => stdout.lock().unwrap().flush().context("flushing stdout")?,
+            Output::JsonArray { path, lines } => {
+                let mut lines = std::mem::take(&mut *lines.lock().unwrap());
+                lines.sort_by(|a, b| a.key.cmp(&b.key));
+
+                let mut writer: Box<dyn Write> = match path {
+                    Some(path) => Box::new(BufWriter::new(
+                        File::create(path).context(format!("creating {}", path.display()))?,
+                    )),
+                    None => Box::new(BufWriter::new(std::io::stdout())),
+                };
+                serde_json::to_writer_pretty(&mut writer, &lines)
+                    .context("serializing JSON array")?;
+                writer.write_all(b"\n").context("writing JSON array")?;
+                writer.flush().context("flushing JSON array")?;
+            }
+            Output::Snapshot { path, kvs } => {
+                snapshot::write_snapshot(path, &std::mem::take(&mut *kvs.lock().unwrap()))?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restore;
+
+    /// A new empty dir for this test, removed first if a previous run left it behind
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("etcddump-output-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn interrupted_write_leaves_no_partial_file() {
+        let dir = test_dir("interrupted");
+        let output = OutputConfig::Dir(dir.clone()).create(false).unwrap();
+        output.write("a/b", b"old value").unwrap();
+
+        // What a write killed before its rename leaves behind, for an existing file and a new one
+        std::fs::write(dir.join(format!("a/b{}", TEMP_FILE_SUFFIX)), b"new va").unwrap();
+        std::fs::write(dir.join(format!("a/c{}", TEMP_FILE_SUFFIX)), b"new").unwrap();
+        assert_eq!(std::fs::read(dir.join("a/b")).unwrap(), b"old value");
+        assert!(!dir.join("a/c").exists());
+
+        let files = restore::dump_files(&dir, false).unwrap();
+        assert_eq!(files, vec![("/a/b".to_string(), dir.join("a/b"))]);
+
+        // Writing again replaces the leftover rather than appending to it
+        output.write("a/b", b"new value").unwrap();
+        assert_eq!(std::fs::read(dir.join("a/b")).unwrap(), b"new value");
+        assert!(!dir.join(format!("a/b{}", TEMP_FILE_SUFFIX)).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
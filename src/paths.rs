@@ -0,0 +1,131 @@
+use crate::output::Layout;
+use anyhow::{ensure, Context, Result};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+/// Characters that are either invalid in file names on some platforms or needed to keep the
+/// encoding reversible. NUL is covered by CONTROLS
+const SEGMENT_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'%').add(b':').add(b'\\');
+
+/// Slashes too, so that the whole key fits in a single file name
+const FLAT_ENCODE_SET: &AsciiSet = &SEGMENT_ENCODE_SET.add(b'/');
+
+/// The file the value of a key goes into when its path is also the dir of other keys, in that dir
+pub(crate) const DIR_VALUE_FILE_NAME: &str = "__value";
+
+/// Maps an etcd key to a relative path that is guaranteed to stay under the dir it gets joined
+/// onto, according to layout
+pub(crate) fn key_to_path(key: &str, layout: Layout) -> Result<String> {
+    match layout {
+        Layout::Nested => nested_path(key),
+        Layout::Flat => Ok(flat_path(key)),
+    }
+}
+
+/// Leading slashes are dropped, every other slash separates path segments, and each segment is
+/// percent-encoded so that it can't be `.`, `..` or contain characters filesystems choke on. Keys
+/// with empty segments (`//` or a trailing `/`) are rejected, as those can't be represented as
+/// paths.
+fn nested_path(key: &str) -> Result<String> {
+    key.trim_start_matches('/')
+        .split('/')
+        .map(|segment| {
+            ensure!(
+                !segment.is_empty(),
+                "key {:?} has an empty path segment",
+                key
+            );
+
+            Ok(match segment {
+                "." => "%2E".to_string(),
+                ".." => "%2E%2E".to_string(),
+                segment => utf8_percent_encode(segment, SEGMENT_ENCODE_SET).to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|segments| segments.join("/"))
+}
+
+/// The whole key percent-encoded into a single file name. The slash of keys starting with one is
+/// encoded too, so the name can't be `.` or `..`
+fn flat_path(key: &str) -> String {
+    match key {
+        "." => "%2E".to_string(),
+        ".." => "%2E%2E".to_string(),
+        key => utf8_percent_encode(key, FLAT_ENCODE_SET).to_string(),
+    }
+}
+
+/// The inverse of key_to_path, taking a relative path with / separators. Works for both layouts,
+/// nested paths never decode to something starting with a slash while flat ones keep theirs
+pub(crate) fn path_to_key(path: &str) -> Result<String> {
+    let key = percent_decode_str(path)
+        .decode_utf8()
+        .context(format!("path {:?} does not decode to UTF-8", path))?;
+
+    Ok(if key.starts_with('/') {
+        key.into_owned()
+    } else {
+        format!("/{}", key)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_path_escapes_dot_segments() {
+        assert_eq!(nested_path("/a/../b").unwrap(), "a/%2E%2E/b");
+        assert_eq!(nested_path("/..").unwrap(), "%2E%2E");
+        assert_eq!(nested_path("/a/./b").unwrap(), "a/%2E/b");
+        assert_eq!(nested_path("/a/..b").unwrap(), "a/..b");
+    }
+
+    #[test]
+    fn nested_path_drops_leading_slashes() {
+        assert_eq!(nested_path("/a/b").unwrap(), "a/b");
+        assert_eq!(nested_path("//a/b").unwrap(), "a/b");
+        assert_eq!(nested_path("a/b").unwrap(), "a/b");
+    }
+
+    #[test]
+    fn nested_path_rejects_empty_segments() {
+        assert!(nested_path("/a//b").is_err());
+        assert!(nested_path("/a/").is_err());
+        assert!(nested_path("/").is_err());
+    }
+
+    #[test]
+    fn nested_path_encodes_nul() {
+        assert_eq!(nested_path("/a\0b/c").unwrap(), "a%00b/c");
+    }
+
+    #[test]
+    fn flat_path_encodes_slashes_and_dot_keys() {
+        assert_eq!(flat_path("/a/b"), "%2Fa%2Fb");
+        assert_eq!(flat_path("//a//b/"), "%2F%2Fa%2F%2Fb%2F");
+        assert_eq!(flat_path(".."), "%2E%2E");
+        assert_eq!(flat_path("/.."), "%2F..");
+        assert_eq!(flat_path("/a\0b"), "%2Fa%00b");
+    }
+
+    #[test]
+    fn paths_map_back_to_their_keys() {
+        for key in [
+            "/registry/pods/default/a",
+            "/a/../b",
+            "/a/./b",
+            "/a\0b/c",
+            "/a%2Fb:c\\d",
+            "/ünïcode/ключ",
+        ] {
+            assert_eq!(path_to_key(&nested_path(key).unwrap()).unwrap(), key);
+            assert_eq!(path_to_key(&flat_path(key)).unwrap(), key);
+        }
+
+        // Only the flat layout keeps the empty segments and leading slashes
+        for key in ["//a/b", "/a//b", "/a/", "/"] {
+            assert_eq!(path_to_key(&flat_path(key)).unwrap(), key);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Spaces out acquisitions evenly so that at most a given number happen per second, without
+/// allowing bursts
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn per_second(rate: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / rate,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the next free slot
+    pub(crate) async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{etcd, manifest, ouger, output, paths, retry};
+use anyhow::{bail, Context, Result};
+use etcd_client::Client as EtcdClient;
+use flate2::read::GzDecoder;
+use reqwest::Client;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// What dump to restore and where to
+pub struct RestoreConfig {
+    pub etcd: etcd::EtcdConfig,
+    pub ouger: ouger::OugerConfig,
+    /// A dump dir as written by a dump to OutputConfig::Dir
+    pub input_dir: PathBuf,
+    /// How many keys to encode and put at once
+    pub concurrency: usize,
+    /// The dump was written raw, values are put as they are
+    pub raw: bool,
+}
+
+/// Puts every key of a dump back into etcd, returning how many keys were restored
+pub async fn restore(config: &RestoreConfig) -> Result<usize> {
+    let files = restorable_files(&config.input_dir, config.raw)?;
+    tracing::info!(count = files.len(), "listed dump files");
+
+    let (entries, compressed) = manifest::Manifest::read(&config.input_dir)?
+        .map(|manifest| (manifest.keys, manifest.compress_files))
+        .unwrap_or_default();
+    let decompressed = entries.iter().filter(|entry| entry.decompressed).count();
+    if decompressed > 0 {
+        tracing::warn!(
+            count = decompressed,
+            "keys were gzip compressed in etcd when dumped, they are restored decompressed"
+        );
+    }
+    // Written as stored in etcd, encoding them would put something else
+    let as_is: HashSet<String> = entries
+        .iter()
+        .filter(|entry| entry.ouger_skipped || entry.decode_error.is_some())
+        .map(|entry| entry.key.clone())
+        .collect();
+
+    let mut leased = 0;
+    for (_, path) in &files {
+        if manifest::Sidecar::read(path)?.is_some_and(|sidecar| sidecar.lease != 0) {
+            leased += 1;
+        }
+    }
+    if leased > 0 {
+        tracing::warn!(
+            count = leased,
+            "keys were attached to a lease when dumped, they are restored without one"
+        );
+    }
+
+    let client = config.etcd.connect().await?;
+
+    let ouger_pool = if config.raw {
+        None
+    } else {
+        Some(config.ouger.launch().await?)
+    };
+
+    let context = Arc::new(RestoreContext {
+        reqclient: config.ouger.client(config.concurrency)?,
+        client,
+        ouger_pool,
+        as_is,
+        compressed,
+        request_timeout: config.etcd.request_timeout,
+        etcd_retry_policy: config.etcd.retry_policy,
+        ouger_retry_policy: config.ouger.retry_policy,
+    });
+
+    // Shuts ouger down along with the context once every key has been restored
+    let count = restore_keys(context, files, config.concurrency).await?;
+    tracing::info!(count, "restored keys");
+
+    Ok(count)
+}
+
+/// The value files of a dump that restore puts back, those of dump_files but redacted and
+/// truncated values
+pub fn restorable_files(input_dir: &Path, raw: bool) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = dump_files(input_dir, raw).context("listing files of the dump")?;
+
+    // Their files are placeholders or only the beginning of the value, putting them would
+    // overwrite the real values
+    let incomplete: HashSet<String> = manifest::Manifest::read(input_dir)?
+        .map(|manifest| manifest.keys)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|entry| entry.redacted || entry.original_size.is_some())
+        .map(|entry| entry.key)
+        .collect();
+    if !incomplete.is_empty() {
+        files.retain(|(key, _)| !incomplete.contains(key));
+        tracing::warn!(
+            count = incomplete.len(),
+            "skipping redacted and truncated keys"
+        );
+    }
+
+    Ok(files)
+}
+
+/// Whether the files of the dump in input_dir were written with compression, going by its manifest
+pub fn is_compressed(input_dir: &Path) -> Result<bool> {
+    Ok(manifest::Manifest::read(input_dir)?.is_some_and(|manifest| manifest.compress_files))
+}
+
+/// Reads a value file of a dump, decompressing it if the dump is compressed and it has a .gz
+/// extension
+pub fn read_dump_file(path: &Path, compressed: bool) -> Result<Vec<u8>> {
+    let contents = std::fs::read(path).context(format!("reading {}", path.display()))?;
+    if !compressed || path.extension().is_none_or(|extension| extension != "gz") {
+        return Ok(contents);
+    }
+
+    let mut value = Vec::new();
+    GzDecoder::new(contents.as_slice())
+        .read_to_end(&mut value)
+        .context(format!("decompressing {}", path.display()))?;
+    Ok(value)
+}
+
+/// Maps every value file of a dump back to the key it was dumped from, skipping the metadata files
+/// the dump command writes next to them
+pub fn dump_files(input_dir: &Path, raw: bool) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    // Extensions added after sniffing values, the keys of values written into the dir of their
+    // path, renamed as they collided with another key or written raw as ouger couldn't decode
+    // them, and those of every value of dumps that stripped a prefix off keys or deduplicated
+    // values are only known from the manifest
+    let (entries, stripped, dedup, compressed) = manifest::Manifest::read(input_dir)?
+        .map(|manifest| {
+            (
+                manifest.keys,
+                manifest.strip_prefix.is_some(),
+                manifest.dedup,
+                manifest.compress_files,
+            )
+        })
+        .unwrap_or_default();
+    let mut keys_by_path: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in entries.iter().filter(|entry| {
+        stripped || dedup || entry.value_in_dir || entry.renamed || entry.decode_error.is_some()
+    }) {
+        keys_by_path
+            .entry(entry.path.clone())
+            .or_default()
+            .push(entry.key.clone());
+    }
+    let extensions: HashMap<String, String> = entries
+        .into_iter()
+        .filter_map(|entry| Some((entry.path, entry.extension?)))
+        .collect();
+
+    for entry in walkdir::WalkDir::new(input_dir).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let relative_path = entry.path().strip_prefix(input_dir)?;
+        if manifest::METADATA_FILE_NAMES
+            .iter()
+            .any(|name| relative_path == Path::new(name))
+        {
+            continue;
+        }
+
+        let relative_path = relative_path
+            .to_str()
+            .context(format!("non UTF-8 path {}", relative_path.display()))?;
+
+        if is_companion_file(input_dir, relative_path, compressed) {
+            continue;
+        }
+
+        if relative_path.ends_with(output::TEMP_FILE_SUFFIX) {
+            tracing::warn!(
+                path = relative_path,
+                "ignoring leftover of an interrupted write"
+            );
+            continue;
+        }
+
+        if let Some(keys) = keys_by_path.get(relative_path) {
+            for key in keys {
+                files.push((key.clone(), entry.path().to_path_buf()));
+            }
+            continue;
+        }
+
+        if dedup
+            && relative_path
+                .strip_prefix(manifest::BLOBS_DIR_NAME)
+                .is_some_and(|blob| blob.starts_with('/'))
+        {
+            tracing::warn!(path = relative_path, "ignoring blob no key has");
+            continue;
+        }
+
+        let extension = extensions.get(relative_path);
+        let relative_path = match relative_path.strip_suffix(".gz") {
+            Some(uncompressed_path) if compressed => uncompressed_path,
+            _ => relative_path,
+        };
+        let relative_path = match extension {
+            Some(extension) => relative_path
+                .strip_suffix(extension.as_str())
+                .and_then(|relative_path| relative_path.strip_suffix('.'))
+                .context(format!(
+                    "{} doesn't have extension {}",
+                    relative_path, extension
+                ))?,
+            None => relative_path,
+        };
+        let relative_path = if raw {
+            match relative_path.strip_suffix(".bin") {
+                Some(relative_path) => relative_path,
+                None => bail!("{} is not a raw dump file", relative_path),
+            }
+        } else {
+            relative_path
+        };
+
+        files.push((
+            paths::path_to_key(relative_path)?,
+            entry.path().to_path_buf(),
+        ));
+    }
+
+    Ok(files)
+}
+
+/// Whether relative_path is the sidecar or raw copy of a value file. Only files next to a value
+/// file are, so that keys ending with their suffixes still get restored
+fn is_companion_file(input_dir: &Path, relative_path: &str, compressed: bool) -> bool {
+    let value_file_exists = |value_path: &str| {
+        input_dir.join(value_path).is_file()
+            || compressed && input_dir.join(format!("{}.gz", value_path)).is_file()
+    };
+    let uncompressed_path = match relative_path.strip_suffix(".gz") {
+        Some(uncompressed_path) if compressed => uncompressed_path,
+        _ => relative_path,
+    };
+
+    relative_path
+        .strip_suffix(manifest::SIDECAR_SUFFIX)
+        .is_some_and(|value_path| input_dir.join(value_path).is_file())
+        || uncompressed_path
+            .strip_suffix(manifest::RAW_COPY_SUFFIX)
+            .is_some_and(value_file_exists)
+}
+
+async fn restore_keys(
+    context: Arc<RestoreContext>,
+    files: Vec<(String, PathBuf)>,
+    concurrency: usize,
+) -> Result<usize> {
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+
+    let mut tasks = Vec::new();
+    for (key, path) in files {
+        let permit = Arc::clone(&semaphore).acquire_owned().await?;
+        let task = put_key(Arc::clone(&context), key, path);
+        tasks.push(tokio::spawn(async move {
+            let _permit = permit;
+            task.await
+        }));
+    }
+
+    let count = tasks.len();
+    for task in tasks {
+        task.await??;
+    }
+
+    Ok(count)
+}
+
+/// Everything put_key needs that is shared between all keys of a restore
+struct RestoreContext {
+    reqclient: Client,
+    client: EtcdClient,
+    /// None when raw
+    ouger_pool: Option<ouger::OugerPool>,
+    /// The keys that skipped ouger when dumped or that ouger couldn't decode, whose files are put
+    /// as they are
+    as_is: HashSet<String>,
+    /// The files of the dump were written with compression
+    compressed: bool,
+    request_timeout: Duration,
+    etcd_retry_policy: retry::RetryPolicy,
+    ouger_retry_policy: retry::RetryPolicy,
+}
+
+#[tracing::instrument(skip(context, path))]
+async fn put_key(context: Arc<RestoreContext>, key: String, path: PathBuf) -> Result<()> {
+    let file_value = read_dump_file(&path, context.compressed)?;
+
+    let etcd_value = match &context.ouger_pool {
+        None => file_value,
+        Some(_) if context.as_is.contains(&key) => file_value,
+        Some(ouger_pool) => context
+            .ouger_retry_policy
+            .retry(ouger::is_retriable, || {
+                ouger::ouger(
+                    &context.reqclient,
+                    ouger_pool.url(),
+                    ouger::OugerCommand::Encode,
+                    &file_value,
+                )
+            })
+            .await
+            .context("encoding value with ouger")?,
+    };
+
+    tracing::debug!(size = etcd_value.len(), "putting key");
+    context
+        .etcd_retry_policy
+        .retry(etcd::is_retriable, || {
+            let mut kv_client = context.client.kv_client();
+            let key = key.clone();
+            let etcd_value = etcd_value.clone();
+            let request_timeout = context.request_timeout;
+            async move {
+                etcd::with_timeout(request_timeout, kv_client.put(key, etcd_value, None)).await
+            }
+        })
+        .await
+        .context("during etcd put")?;
+
+    Ok(())
+}
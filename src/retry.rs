@@ -0,0 +1,93 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::time::{Duration, Instant};
+
+/// How often and how fast to retry failed requests
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after every retry
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            base_delay: Duration::ZERO,
+        }
+    }
+
+    /// Runs operation until it succeeds, fails with an error is_retriable rejects, or runs out of
+    /// retries. The delay between attempts doubles after each retry, starting at base_delay.
+    pub(crate) async fn retry<T, E, Fut>(
+        &self,
+        is_retriable: impl Fn(&E) -> bool,
+        mut operation: impl FnMut() -> Fut,
+    ) -> Result<T, E>
+    where
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match operation().await {
+                Err(err) if attempt < self.max_retries && is_retriable(&err) => {
+                    tokio::time::sleep(self.base_delay.saturating_mul(1 << attempt.min(16))).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Counts the retries of the requests of all keys together, to give up on every one of them once
+/// there are more than max_retries within a window, as the server is then most likely down rather
+/// than having a blip. Windows are fixed, starting when the breaker is created
+pub(crate) struct RetryBreaker {
+    pub(crate) max_retries: usize,
+    pub(crate) window: Duration,
+    start: Instant,
+    /// The number of the window retries are currently counted in, since start
+    current_window: AtomicU64,
+    retries: AtomicUsize,
+    tripped: AtomicBool,
+}
+
+impl RetryBreaker {
+    pub(crate) fn new(max_retries: usize, window: Duration) -> Self {
+        Self {
+            max_retries,
+            window,
+            start: Instant::now(),
+            current_window: AtomicU64::new(0),
+            retries: AtomicUsize::new(0),
+            tripped: AtomicBool::new(false),
+        }
+    }
+
+    /// Counts a retry about to happen, returning whether the breaker tripped, because of this
+    /// retry or an earlier one
+    pub(crate) fn retry(&self) -> bool {
+        if self.tripped() {
+            return true;
+        }
+
+        let window = (self.start.elapsed().as_nanos() / self.window.as_nanos().max(1)) as u64;
+        // Retries counted by other keys while resetting may get lost, which only ever delays
+        // tripping
+        if self.current_window.swap(window, Ordering::Relaxed) != window {
+            self.retries.store(0, Ordering::Relaxed);
+        }
+        if self.retries.fetch_add(1, Ordering::Relaxed) + 1 > self.max_retries {
+            self.tripped.store(true, Ordering::Relaxed);
+            return true;
+        }
+
+        false
+    }
+
+    pub(crate) fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,360 @@
+use crate::retry::RetryPolicy;
+use crate::utc::UtcTime;
+use anyhow::{bail, ensure, Context, Result};
+use futures::FutureExt;
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// The characters AWS signatures leave as is in paths, besides the slashes between segments
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Where to upload a dump, one object per file
+#[derive(Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to the relative path of every file, without leading or trailing slashes
+    pub prefix: String,
+    /// An S3 compatible service like MinIO, addressed path-style. Defaults to AWS itself
+    pub endpoint: Option<Url>,
+    pub region: String,
+    pub credentials: S3Credentials,
+    /// How many objects to upload at once
+    pub concurrency: usize,
+    /// Applied to uploads failing to reach the service or failing with a server error
+    pub retry_policy: RetryPolicy,
+}
+
+/// AWS access keys, see S3Credentials::from_env
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Only for temporary credentials
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    /// Reads the credentials the way AWS tools do: from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+    /// and AWS_SESSION_TOKEN, or else from the AWS_PROFILE (default: default) profile of the
+    /// AWS_SHARED_CREDENTIALS_FILE (default: ~/.aws/credentials) file
+    pub fn from_env() -> Result<Self> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(Self {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+
+        let path = match std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(std::env::var_os("HOME").context(
+                "no AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, nor HOME to find credentials in",
+            )?)
+            .join(".aws/credentials"),
+        };
+        let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        let contents = std::fs::read_to_string(&path).context(format!(
+            "no AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, reading {} instead",
+            path.display()
+        ))?;
+
+        let mut in_profile = false;
+        let (mut access_key_id, mut secret_access_key, mut session_token) = (None, None, None);
+        for line in contents.lines().map(str::trim) {
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                in_profile = section.trim() == profile;
+            } else if let (true, Some((name, value))) = (in_profile, line.split_once('=')) {
+                let value = Some(value.trim().to_string());
+                match name.trim() {
+                    "aws_access_key_id" => access_key_id = value,
+                    "aws_secret_access_key" => secret_access_key = value,
+                    "aws_session_token" => session_token = value,
+                    _ => {}
+                }
+            }
+        }
+
+        match (access_key_id, secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Self {
+                access_key_id,
+                secret_access_key,
+                session_token,
+            }),
+            _ => bail!(
+                "no credentials for profile {} in {}",
+                profile,
+                path.display()
+            ),
+        }
+    }
+}
+
+/// The region from AWS_REGION or AWS_DEFAULT_REGION, defaulting to us-east-1 like AWS tools do
+pub fn default_region() -> String {
+    std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|_| "us-east-1".to_string())
+}
+
+/// Splits an s3://bucket/prefix URL into its bucket and prefix
+pub fn parse_url(url: &str) -> Result<(String, String)> {
+    let Some(location) = url.strip_prefix("s3://") else {
+        bail!("unsupported URL {}, expected s3://bucket/prefix", url);
+    };
+    let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+    ensure!(!bucket.is_empty(), "missing bucket in {}", url);
+
+    Ok((bucket.to_string(), prefix.trim_matches('/').to_string()))
+}
+
+/// Context of every error uploading to S3, which can be downcast to it to tell those apart from
+/// failures to reach ouger, both being HTTP
+#[derive(Debug)]
+pub struct UploadError;
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uploading to S3")
+    }
+}
+
+/// An error response of the service
+#[derive(Debug)]
+struct ServiceError {
+    status: StatusCode,
+    body: String,
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+fn is_retriable(err: &anyhow::Error) -> bool {
+    if let Some(err) = err.downcast_ref::<ServiceError>() {
+        return err.status.is_server_error() || err.status == StatusCode::TOO_MANY_REQUESTS;
+    }
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|err| err.is_connect() || err.is_timeout() || err.is_request())
+}
+
+/// Uploads files in the background as they are written, at most concurrency at a time. Needs a
+/// multi-threaded tokio runtime, as writes block until an upload slot frees up
+pub(crate) struct S3Output {
+    client: Client,
+    config: Arc<S3Config>,
+    runtime: Handle,
+    permits: Arc<Semaphore>,
+    uploads: Mutex<Vec<JoinHandle<Result<()>>>>,
+}
+
+impl S3Output {
+    pub(crate) fn new(config: &S3Config) -> Result<Self> {
+        Ok(Self {
+            client: Client::builder()
+                .pool_max_idle_per_host(config.concurrency)
+                .build()
+                .context("building S3 client")?,
+            config: Arc::new(config.clone()),
+            runtime: Handle::try_current().context("S3 output needs a tokio runtime")?,
+            permits: Arc::new(Semaphore::new(config.concurrency)),
+            uploads: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Starts uploading value as relative_path, failing if an earlier upload failed
+    pub(crate) fn write(&self, relative_path: &str, value: &[u8]) -> Result<()> {
+        let permit = tokio::task::block_in_place(|| {
+            self.runtime
+                .block_on(Arc::clone(&self.permits).acquire_owned())
+        })?;
+
+        let mut uploads = self.uploads.lock().unwrap();
+        // Finished uploads are checked on every write, so that a failing bucket fails the dump
+        // right away rather than once everything was uploaded
+        let mut failure = None;
+        uploads.retain_mut(|upload| match upload.now_or_never() {
+            None => true,
+            Some(result) => {
+                if let Err(err) = result.map_err(anyhow::Error::from).and_then(|r| r) {
+                    failure.get_or_insert(err);
+                }
+                false
+            }
+        });
+        if let Some(err) = failure {
+            return Err(err);
+        }
+
+        let client = self.client.clone();
+        let config = Arc::clone(&self.config);
+        let key = object_key(&config.prefix, relative_path);
+        let value = value.to_vec();
+        uploads.push(self.runtime.spawn(async move {
+            let _permit = permit;
+            config
+                .retry_policy
+                .retry(is_retriable, || put_object(&client, &config, &key, &value))
+                .await
+                .context(format!("uploading {}", key))
+                .context(UploadError)
+        }));
+
+        Ok(())
+    }
+
+    /// Waits for every upload started so far to be done
+    pub(crate) fn finish(&self) -> Result<()> {
+        let uploads = std::mem::take(&mut *self.uploads.lock().unwrap());
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                for upload in uploads {
+                    upload.await??;
+                }
+                Ok(())
+            })
+        })
+    }
+}
+
+fn object_key(prefix: &str, relative_path: &str) -> String {
+    if prefix.is_empty() {
+        relative_path.to_string()
+    } else {
+        format!("{}/{}", prefix, relative_path)
+    }
+}
+
+/// Puts value as key into the bucket, signed with AWS signature version 4
+async fn put_object(client: &Client, config: &S3Config, key: &str, value: &[u8]) -> Result<()> {
+    let encoded_key = key
+        .split('/')
+        .map(|segment| percent_encoding::utf8_percent_encode(segment, UNRESERVED).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    let url = match &config.endpoint {
+        Some(endpoint) => Url::parse(&format!(
+            "{}/{}/{}",
+            endpoint.as_str().trim_end_matches('/'),
+            config.bucket,
+            encoded_key
+        )),
+        None => Url::parse(&format!(
+            "https://{}.s3.{}.amazonaws.com/{}",
+            config.bucket, config.region, encoded_key
+        )),
+    }
+    .context("building object URL")?;
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let amz_date = amz_date(SystemTime::now());
+    let date = &amz_date[..8];
+    let payload_hash = format!("{:x}", Sha256::digest(value));
+
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", payload_hash.clone()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(session_token) = &config.credentials.session_token {
+        headers.push(("x-amz-security-token", session_token.clone()));
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value))
+        .collect();
+
+    let canonical_request = format!(
+        "PUT\n{}\n\n{}\n{}\n{}",
+        url.path(),
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        amz_date,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+
+    let signing_key = [config.region.as_str(), "s3", "aws4_request"].iter().fold(
+        hmac(
+            format!("AWS4{}", config.credentials.secret_access_key).as_bytes(),
+            date,
+        ),
+        |key, part| hmac(&key, part),
+    );
+    let signature: String = hmac(&signing_key, &string_to_sign)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let mut request = client.put(url).body(value.to_vec()).header(
+        "authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            config.credentials.access_key_id, scope, signed_headers, signature
+        ),
+    );
+    // Host is set by reqwest itself from the URL
+    for (name, value) in headers.into_iter().skip(1) {
+        request = request.header(name, value);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ServiceError {
+            status,
+            body: response.text().await.unwrap_or_default(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// now in UTC, formatted like 20230901T123456Z
+fn amz_date(now: SystemTime) -> String {
+    let now = UtcTime::from(now);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year, now.month, now.day, now.hour, now.minute, now.second
+    )
+}
@@ -0,0 +1,466 @@
+use anyhow::{bail, ensure, Context, Result};
+use etcd_client::proto::PbKeyValue;
+use etcd_client::KeyValue;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// Just enough of the bbolt file format to walk the buckets of an etcd snapshot, and to write new
+// ones. bbolt writes its structures in the native byte order, so little endian everywhere that
+// matters
+const MAGIC: u32 = 0xED0C_DAED;
+const VERSION: u32 = 2;
+const PAGE_HEADER_SIZE: usize = 16;
+const ELEMENT_SIZE: usize = 16;
+const BRANCH_PAGE: u16 = 0x01;
+const LEAF_PAGE: u16 = 0x02;
+const META_PAGE: u16 = 0x04;
+const FREELIST_PAGE: u16 = 0x10;
+const BUCKET_LEAF: u32 = 0x01;
+/// The page size of written snapshots, what bbolt picks on most systems
+const PAGE_SIZE: usize = 4096;
+/// Deeper trees would need more keys than any snapshot can hold, so this only catches cycles
+const MAX_DEPTH: usize = 64;
+
+/// The bucket etcd keeps every revision of every key in
+const KEY_BUCKET: &[u8] = b"key";
+/// Revisions are 8 bytes of main revision, an underscore and 8 bytes of sub revision, followed by
+/// a t when the revision deleted the key
+const REVISION_SIZE: usize = 17;
+const TOMBSTONE: u8 = b't';
+/// The bucket etcd keeps its own bookkeeping in, like the consistent index, which restoring a
+/// snapshot sets
+const META_BUCKET: &[u8] = b"meta";
+
+/// The keys of an etcd snapshot, as saved by etcdctl snapshot save, as of revision or the last
+/// revision of the snapshot. Tombstoned keys are left out
+pub(crate) fn read_snapshot(
+    path: &Path,
+    revision: Option<i64>,
+) -> Result<BTreeMap<String, KeyValue>> {
+    let data = std::fs::read(path).context(format!("reading snapshot {}", path.display()))?;
+    let db = Db::open(&data).context(format!("opening snapshot {}", path.display()))?;
+
+    let mut key_bucket = None;
+    db.for_each(db.root_page()?, 0, &mut |flags, key, value| {
+        if flags & BUCKET_LEAF != 0 && key == KEY_BUCKET {
+            key_bucket = Some(value);
+        }
+        Ok(())
+    })?;
+    let key_bucket = key_bucket.context("snapshot has no key bucket, is it an etcd snapshot?")?;
+
+    // Revisions are big endian, so they are walked in order and the last one of every key wins
+    let mut kvs = BTreeMap::new();
+    db.for_each(
+        db.bucket_page(key_bucket)?,
+        0,
+        &mut |flags, revision_key, value| {
+            if flags & BUCKET_LEAF != 0 {
+                return Ok(());
+            }
+            ensure!(
+                revision_key.len() >= REVISION_SIZE,
+                "malformed revision {:?}",
+                revision_key
+            );
+            let main_revision = i64::from_be_bytes(revision_key[..8].try_into()?);
+            if revision.is_some_and(|revision| main_revision > revision) {
+                return Ok(());
+            }
+
+            let kv = PbKeyValue::decode(value)
+                .context(format!("decoding key value of revision {}", main_revision))?;
+            let key = String::from_utf8(kv.key.clone())
+                .context(format!("key {:?} is not UTF-8", kv.key))?;
+            if revision_key.get(REVISION_SIZE) == Some(&TOMBSTONE) {
+                kvs.remove(&key);
+            } else {
+                kvs.insert(key, <&KeyValue>::from(&kv).clone());
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(kvs)
+}
+
+/// Writes kvs to path as an etcd snapshot, which etcdctl snapshot restore takes like one it saved
+/// itself. There is a single revision of every key, without its lease as leases aren't carried
+/// over, and keys last modified by the same revision get sub revisions in key order
+pub(crate) fn write_snapshot(path: &Path, kvs: &[KeyValue]) -> Result<()> {
+    let mut kvs = kvs.iter().collect::<Vec<_>>();
+    kvs.sort_by(|a, b| (a.mod_revision(), a.key()).cmp(&(b.mod_revision(), b.key())));
+
+    let mut revisions = Vec::with_capacity(kvs.len());
+    let mut sub_revision = 0;
+    for (i, kv) in kvs.iter().enumerate() {
+        if i > 0 && kvs[i - 1].mod_revision() == kv.mod_revision() {
+            sub_revision += 1;
+        } else {
+            sub_revision = 0;
+        }
+
+        let mut revision_key = Vec::with_capacity(REVISION_SIZE);
+        revision_key.extend_from_slice(&kv.mod_revision().to_be_bytes());
+        revision_key.push(b'_');
+        revision_key.extend_from_slice(&(sub_revision as i64).to_be_bytes());
+        let value = PbKeyValue {
+            key: kv.key().to_vec(),
+            create_revision: kv.create_revision(),
+            mod_revision: kv.mod_revision(),
+            version: kv.version(),
+            value: kv.value().to_vec(),
+            lease: 0,
+        }
+        .encode_to_vec();
+        revisions.push((0, revision_key, value));
+    }
+
+    // Meta pages first, then the freelist, which bbolt wants even when nothing is free, then the
+    // trees of the buckets and of the root bucket
+    let mut pages = Pages {
+        data: vec![0; 3 * PAGE_SIZE],
+    };
+    pages.write(2, FREELIST_PAGE, 0, &[]);
+    let key_bucket = pages.write_tree(&revisions);
+    let meta_bucket = pages.write_tree(&[]);
+    let bucket = |root: u64| [root.to_le_bytes(), 0u64.to_le_bytes()].concat();
+    let root = pages.write_tree(&[
+        (BUCKET_LEAF, KEY_BUCKET.to_vec(), bucket(key_bucket)),
+        (BUCKET_LEAF, META_BUCKET.to_vec(), bucket(meta_bucket)),
+    ]);
+    let page_count = (pages.data.len() / PAGE_SIZE) as u64;
+    for txid in 0..2u64 {
+        let mut meta = Vec::with_capacity(64);
+        meta.extend_from_slice(&MAGIC.to_le_bytes());
+        meta.extend_from_slice(&VERSION.to_le_bytes());
+        meta.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        meta.extend_from_slice(&0u32.to_le_bytes());
+        meta.extend_from_slice(&bucket(root));
+        meta.extend_from_slice(&2u64.to_le_bytes());
+        meta.extend_from_slice(&page_count.to_le_bytes());
+        meta.extend_from_slice(&txid.to_le_bytes());
+        meta.extend_from_slice(&fnv1a(&meta).to_le_bytes());
+        pages.write(txid, META_PAGE, 0, &meta);
+    }
+
+    // Like etcd does for snapshots it sends, so that restoring checks them
+    let mut data = pages.data;
+    let hash = Sha256::digest(&data);
+    data.extend_from_slice(&hash);
+    std::fs::write(path, data).context(format!("writing snapshot {}", path.display()))
+}
+
+/// The pages of a bbolt file being written, one after the other
+struct Pages {
+    data: Vec<u8>,
+}
+
+impl Pages {
+    /// Writes a page with the given header fields and body at id, taking as many overflow pages
+    /// as the body needs
+    fn write(&mut self, id: u64, flags: u16, count: u16, body: &[u8]) {
+        let size = PAGE_HEADER_SIZE + body.len();
+        let overflow = size.div_ceil(PAGE_SIZE).max(1) - 1;
+        let offset = id as usize * PAGE_SIZE;
+        let end = offset + (overflow + 1) * PAGE_SIZE;
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+
+        let page = &mut self.data[offset..end];
+        page[..8].copy_from_slice(&id.to_le_bytes());
+        page[8..10].copy_from_slice(&flags.to_le_bytes());
+        page[10..12].copy_from_slice(&count.to_le_bytes());
+        page[12..16].copy_from_slice(&(overflow as u32).to_le_bytes());
+        page[PAGE_HEADER_SIZE..size].copy_from_slice(body);
+    }
+
+    fn next_id(&self) -> u64 {
+        (self.data.len() / PAGE_SIZE) as u64
+    }
+
+    /// Writes a tree holding the leaf elements, given as flags, key and value sorted by key,
+    /// returning the id of its root page. Pages are filled up to the page size, or hold a single
+    /// element on overflow pages when it doesn't fit
+    fn write_tree(&mut self, elements: &[(u32, Vec<u8>, Vec<u8>)]) -> u64 {
+        // The first key and id of every page of the level being written
+        let mut level = Vec::new();
+        for page in chunk(elements, |(_, key, value)| key.len() + value.len()) {
+            let mut body = Vec::new();
+            let mut data = Vec::new();
+            for (i, (flags, key, value)) in page.iter().enumerate() {
+                let position = (page.len() - i) * ELEMENT_SIZE + data.len();
+                body.extend_from_slice(&flags.to_le_bytes());
+                body.extend_from_slice(&(position as u32).to_le_bytes());
+                body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                data.extend_from_slice(key);
+                data.extend_from_slice(value);
+            }
+            body.extend_from_slice(&data);
+
+            let id = self.next_id();
+            self.write(id, LEAF_PAGE, page.len() as u16, &body);
+            level.push((
+                page.first()
+                    .map(|(_, key, _)| key.clone())
+                    .unwrap_or_default(),
+                id,
+            ));
+        }
+
+        while level.len() > 1 {
+            let mut parent_level = Vec::new();
+            for page in chunk(&level, |(key, _)| key.len()) {
+                let mut body = Vec::new();
+                let mut data = Vec::new();
+                for (i, (key, id)) in page.iter().enumerate() {
+                    let position = (page.len() - i) * ELEMENT_SIZE + data.len();
+                    body.extend_from_slice(&(position as u32).to_le_bytes());
+                    body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                    body.extend_from_slice(&id.to_le_bytes());
+                    data.extend_from_slice(key);
+                }
+                body.extend_from_slice(&data);
+
+                let id = self.next_id();
+                self.write(id, BRANCH_PAGE, page.len() as u16, &body);
+                parent_level.push((page[0].0.clone(), id));
+            }
+            level = parent_level;
+        }
+
+        level[0].1
+    }
+}
+
+/// Splits elements into the runs that fit a page given the size of their data, with at least one
+/// element per run. No elements still make a single empty page
+fn chunk<T>(elements: &[T], data_size: impl Fn(&T) -> usize) -> Vec<&[T]> {
+    let mut chunks = Vec::new();
+    let (mut start, mut size) = (0, PAGE_HEADER_SIZE);
+    for (i, element) in elements.iter().enumerate() {
+        let element_size = ELEMENT_SIZE + data_size(element);
+        if i > start && size + element_size > PAGE_SIZE {
+            chunks.push(&elements[start..i]);
+            (start, size) = (i, PAGE_HEADER_SIZE);
+        }
+        size += element_size;
+    }
+    chunks.push(&elements[start..]);
+
+    chunks
+}
+
+struct Db<'a> {
+    data: &'a [u8],
+    page_size: usize,
+    /// The page of the root bucket, whose leaves are the top-level buckets
+    root: u64,
+}
+
+impl<'a> Db<'a> {
+    /// Picks the newest valid one of the two meta pages, like bbolt does
+    fn open(data: &'a [u8]) -> Result<Self> {
+        let meta = |offset: usize| -> Result<(u64, usize, u64)> {
+            let meta = slice(data, offset + PAGE_HEADER_SIZE, 64)?;
+            ensure!(read_u32(meta, 0)? == MAGIC, "not a bbolt file");
+            ensure!(
+                read_u32(meta, 4)? == VERSION,
+                "unsupported bbolt version {}",
+                read_u32(meta, 4)?
+            );
+            ensure!(
+                read_u64(meta, 56)? == fnv1a(&meta[..56]),
+                "meta page checksum mismatch"
+            );
+            Ok((
+                read_u64(meta, 48)?,
+                read_u32(meta, 8)? as usize,
+                read_u64(meta, 16)?,
+            ))
+        };
+
+        let first = meta(0);
+        let second = match &first {
+            Ok((_, page_size, _)) => meta(*page_size),
+            Err(_) => Err(anyhow::anyhow!("first meta page is invalid")),
+        };
+        let (_, page_size, root) = match (first, second) {
+            (Ok(first), Ok(second)) => std::cmp::max_by_key(first, second, |(txid, _, _)| *txid),
+            (Ok(meta), Err(_)) | (Err(_), Ok(meta)) => meta,
+            (Err(err), Err(_)) => return Err(err),
+        };
+
+        Ok(Self {
+            data,
+            page_size,
+            root,
+        })
+    }
+
+    fn root_page(&self) -> Result<&'a [u8]> {
+        self.page(self.root)
+    }
+
+    /// The rest of the file from page id on, which covers its overflow pages too
+    fn page(&self, id: u64) -> Result<&'a [u8]> {
+        let offset = usize::try_from(id)?
+            .checked_mul(self.page_size)
+            .context("page id out of range")?;
+        self.data
+            .get(offset..)
+            .context(format!("page {} is past the end of the file", id))
+    }
+
+    /// The root page of the bucket with the given leaf value, which holds the whole bucket when
+    /// it's inlined
+    fn bucket_page(&self, bucket: &'a [u8]) -> Result<&'a [u8]> {
+        match read_u64(bucket, 0)? {
+            0 => bucket.get(16..).context("truncated inline bucket"),
+            root => self.page(root),
+        }
+    }
+
+    /// Calls f with the flags, key and value of every leaf element under page, in key order
+    fn for_each(
+        &self,
+        page: &'a [u8],
+        depth: usize,
+        f: &mut impl FnMut(u32, &'a [u8], &'a [u8]) -> Result<()>,
+    ) -> Result<()> {
+        ensure!(
+            depth < MAX_DEPTH,
+            "bbolt tree is too deep, the file must be corrupt"
+        );
+
+        let flags = read_u16(page, 8)?;
+        let count = read_u16(page, 10)? as usize;
+        for i in 0..count {
+            let element_offset = PAGE_HEADER_SIZE + i * ELEMENT_SIZE;
+            let element = slice(page, element_offset, ELEMENT_SIZE)?;
+            match flags {
+                BRANCH_PAGE => {
+                    let child = read_u64(element, 8)?;
+                    self.for_each(self.page(child)?, depth + 1, f)?;
+                }
+                LEAF_PAGE => {
+                    let key_offset = element_offset + read_u32(element, 4)? as usize;
+                    let key_size = read_u32(element, 8)? as usize;
+                    let value_size = read_u32(element, 12)? as usize;
+                    f(
+                        read_u32(element, 0)?,
+                        slice(page, key_offset, key_size)?,
+                        slice(page, key_offset + key_size, value_size)?,
+                    )?;
+                }
+                flags => bail!("unexpected page flags {:#x}", flags),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .context("truncated bbolt file")
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(slice(data, offset, 2)?.try_into()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(slice(data, offset, 4)?.try_into()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(slice(data, offset, 8)?.try_into()?))
+}
+
+/// The checksum of bbolt meta pages
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("etcddump-snapshot-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn kv(key: &str, value: &[u8], mod_revision: i64) -> KeyValue {
+        let kv = PbKeyValue {
+            key: key.as_bytes().to_vec(),
+            create_revision: mod_revision - 1,
+            mod_revision,
+            version: 2,
+            value: value.to_vec(),
+            lease: 7,
+        };
+        <&KeyValue>::from(&kv).clone()
+    }
+
+    /// Everything a snapshot keeps of kv, leases aren't carried over
+    fn written(kv: &KeyValue) -> (Vec<u8>, Vec<u8>, i64, i64, i64) {
+        (
+            kv.key().to_vec(),
+            kv.value().to_vec(),
+            kv.create_revision(),
+            kv.mod_revision(),
+            kv.version(),
+        )
+    }
+
+    #[test]
+    fn written_snapshots_read_back() {
+        let dir = test_dir("roundtrip");
+        let path = dir.join("snapshot.db");
+        // Enough keys to need several leaf pages and a branch page above them
+        let mut kvs: Vec<KeyValue> = (0..1000)
+            .map(|i| {
+                kv(
+                    &format!("/registry/configmaps/default/{:04}", i),
+                    format!("value {}", i).repeat(8).as_bytes(),
+                    i + 10,
+                )
+            })
+            .collect();
+        // Overflows its page
+        kvs.push(kv("/registry/big", &[b'x'; 3 * PAGE_SIZE + 100], 2000));
+        // Put by the same transaction
+        kvs.push(kv("/registry/same/b", b"second", 3000));
+        kvs.push(kv("/registry/same/a", b"first", 3000));
+
+        write_snapshot(&path, &kvs).unwrap();
+        let read = read_snapshot(&path, None).unwrap();
+
+        assert_eq!(read.len(), kvs.len());
+        for kv in &kvs {
+            let read_kv = &read[std::str::from_utf8(kv.key()).unwrap()];
+            assert_eq!(written(read_kv), written(kv));
+            assert_eq!(read_kv.lease(), 0);
+        }
+
+        let older = read_snapshot(&path, Some(2000)).unwrap();
+        assert_eq!(older.len(), 1001);
+        assert!(older.contains_key("/registry/big"));
+        assert!(!older.contains_key("/registry/same/a"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
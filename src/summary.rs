@@ -0,0 +1,92 @@
+use crate::dump::{self, EtcdDumper};
+use crate::etcd::{self, EtcdConfig};
+use anyhow::{Context, Result};
+use etcd_client::{Client as EtcdClient, GetOptions};
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+impl EtcdDumper {
+    /// Counts the keys dump would write by group, see key_group, without fetching any value
+    pub async fn count_by_group(&self, depth: NonZeroUsize) -> Result<BTreeMap<String, usize>> {
+        let mut counts = BTreeMap::new();
+        for key in self.list_keys().await? {
+            *counts
+                .entry(key_group(&key, depth).to_string())
+                .or_default() += 1;
+        }
+
+        Ok(counts)
+    }
+}
+
+/// Counts the keys under every prefix but those under exclude_prefixes, in the order of prefixes,
+/// without even listing them. Every prefix is counted at the same revision, the current one unless
+/// given
+pub async fn count_keys(
+    etcd_config: &EtcdConfig,
+    prefixes: &[String],
+    exclude_prefixes: &[String],
+    mut revision: Option<i64>,
+) -> Result<Vec<(String, i64)>> {
+    let client = etcd_config.connect().await?;
+
+    let mut counts = Vec::new();
+    for prefix in prefixes {
+        let mut count = 0;
+        for (start, range_end) in dump::listing_ranges(prefix, exclude_prefixes) {
+            let (range_count, range_revision) = count_range(
+                &client,
+                start,
+                range_end,
+                revision,
+                etcd_config.request_timeout,
+            )
+            .await
+            .context(format!("counting keys under {}", prefix))?;
+            count += range_count;
+            revision.get_or_insert(range_revision);
+        }
+        counts.push((prefix.clone(), count));
+    }
+
+    Ok(counts)
+}
+
+/// How many keys there are in [start, range_end) at revision, along with the revision they were
+/// counted at. etcd only returns the count
+async fn count_range(
+    client: &EtcdClient,
+    start: Vec<u8>,
+    range_end: Vec<u8>,
+    revision: Option<i64>,
+    request_timeout: Duration,
+) -> Result<(i64, i64)> {
+    let mut options = GetOptions::new().with_range(range_end).with_count_only();
+    if let Some(revision) = revision {
+        options = options.with_revision(revision);
+    }
+
+    let mut kv_client = client.kv_client();
+    let get_response = etcd::with_timeout(request_timeout, kv_client.get(start, Some(options)))
+        .await
+        .map_err(|err| etcd::revision_error(err, revision))?;
+
+    Ok((
+        get_response.count(),
+        get_response
+            .header()
+            .map(|header| header.revision())
+            .context("no header in etcd response")?,
+    ))
+}
+
+/// The first depth path segments of key, e.g. /registry/pods for /registry/pods/default/web at a
+/// depth of 2. Keys with no more segments than that are their own group
+pub fn key_group(key: &str, depth: NonZeroUsize) -> &str {
+    let leading_slash = usize::from(key.starts_with('/'));
+    match key.match_indices('/').nth(depth.get() - 1 + leading_slash) {
+        Some((end, _)) => &key[..end],
+        None => key,
+    }
+}
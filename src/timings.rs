@@ -0,0 +1,87 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Where the time of a dump went, to tell whether etcd, ouger or the output is the bottleneck
+#[derive(Debug)]
+pub struct Timings {
+    /// Connecting to etcd
+    pub connect: Duration,
+    /// Listing the keys to dump
+    pub list: Duration,
+    /// Getting keys from etcd, summed over all keys, so larger than the wall clock time it took
+    /// when fetching several keys at once
+    pub fetch: Duration,
+    /// Decoding values with ouger, summed over all keys like fetch
+    pub decode: Duration,
+    /// Writing keys to the output
+    pub write: Duration,
+    /// Of a single etcd get, retries included
+    pub get_percentiles: Percentiles,
+    /// Of a single ouger decode, retries included
+    pub decode_percentiles: Percentiles,
+}
+
+/// Percentiles of the durations of one kind of request, zero when there were none
+#[derive(Debug, Default)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// Durations sampled while dumping, shared by all keys
+pub(crate) struct TimingSamples {
+    connect: Duration,
+    list: Duration,
+    gets: Mutex<Vec<Duration>>,
+    decodes: Mutex<Vec<Duration>>,
+}
+
+impl TimingSamples {
+    pub(crate) fn new(connect: Duration, list: Duration) -> Self {
+        Self {
+            connect,
+            list,
+            gets: Mutex::default(),
+            decodes: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn get(&self, duration: Duration) {
+        self.gets.lock().unwrap().push(duration);
+    }
+
+    pub(crate) fn decode(&self, duration: Duration) {
+        self.decodes.lock().unwrap().push(duration);
+    }
+
+    pub(crate) fn timings(&self, write: Duration) -> Timings {
+        let mut gets = self.gets.lock().unwrap();
+        let mut decodes = self.decodes.lock().unwrap();
+
+        Timings {
+            connect: self.connect,
+            list: self.list,
+            fetch: gets.iter().sum(),
+            decode: decodes.iter().sum(),
+            write,
+            get_percentiles: percentiles(&mut gets),
+            decode_percentiles: percentiles(&mut decodes),
+        }
+    }
+}
+
+fn percentiles(samples: &mut [Duration]) -> Percentiles {
+    if samples.is_empty() {
+        return Percentiles::default();
+    }
+
+    samples.sort_unstable();
+    // Nearest rank
+    let percentile = |percent: usize| samples[(samples.len() * percent).div_ceil(100) - 1];
+    Percentiles {
+        p50: percentile(50),
+        p95: percentile(95),
+        p99: percentile(99),
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use std::process::{ExitStatus, Stdio};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// A transform command that exited with a failure, which is treated like a value ouger can't
+/// decode
+#[derive(Debug)]
+pub(crate) struct TransformError {
+    status: ExitStatus,
+    stderr: String,
+}
+
+impl std::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transform failed with {}", self.status)?;
+        if !self.stderr.is_empty() {
+            write!(f, ": {}", self.stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+/// Pipes value through command, run by sh, returning what it writes to stdout
+pub(crate) async fn transform(command: &str, value: Vec<u8>) -> Result<Vec<u8>> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context(format!("running transform {}", command))?;
+
+    // Written concurrently with reading the output, so that a command writing a lot before
+    // reading all of its input doesn't deadlock
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let write = async move {
+        // A command that doesn't read all of its input is free to exit early
+        match stdin.write_all(&value).await {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => Err(err),
+            _ => Ok(()),
+        }
+    };
+    let (written, output) = tokio::join!(write, child.wait_with_output());
+    let output = output.context(format!("running transform {}", command))?;
+    written.context(format!("writing to transform {}", command))?;
+
+    if !output.status.success() {
+        return Err(TransformError {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
+    }
+
+    Ok(output.stdout)
+}
@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Result};
+use etcddump::{DumpInterrupt, DumpProgress, Metrics};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Log lines written while the view is up, as they would garble it. None when it isn't up
+static HELD_LOGS: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// How far back throughput is measured
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Writes logs to stderr, or holds them until the view is down while it's up
+pub(crate) struct LogWriter;
+
+pub(crate) fn log_writer() -> LogWriter {
+    LogWriter
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match HELD_LOGS.lock().unwrap().as_mut() {
+            Some(held) => {
+                held.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            None => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// The terminal taken over by the view, given back with the held logs when dropped
+struct Screen(DefaultTerminal);
+
+impl Screen {
+    fn take() -> Self {
+        *HELD_LOGS.lock().unwrap() = Some(Vec::new());
+        Self(ratatui::init())
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        ratatui::restore();
+        if let Some(held) = HELD_LOGS.lock().unwrap().take() {
+            let _ = std::io::stderr().write_all(&held);
+        }
+    }
+}
+
+/// Keeps a full screen view of the progress of a dump in sync with it. Ctrl-C doesn't raise a
+/// signal while the view is up, so it interrupts the dump itself, and only returns when drawing
+/// fails or when interrupted again
+pub(crate) async fn show(
+    progress: DumpProgress,
+    metrics: Metrics,
+    interrupt: DumpInterrupt,
+) -> Result<Infallible> {
+    let mut screen = Screen::take();
+    let start = Instant::now();
+    // Done keys and written bytes over the throughput window
+    let mut samples: VecDeque<(Instant, usize, u64)> = VecDeque::new();
+
+    let mut interval = tokio::time::interval(Duration::from_millis(200));
+    loop {
+        interval.tick().await;
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && ctrl_c && !interrupt.interrupt() {
+                    return Err(anyhow!("interrupted"));
+                }
+            }
+        }
+
+        let now = Instant::now();
+        samples.push_back((now, progress.done(), metrics.bytes_written()));
+        while samples
+            .front()
+            .is_some_and(|(time, _, _)| now - *time > THROUGHPUT_WINDOW)
+        {
+            samples.pop_front();
+        }
+        let (first, last) = (samples.front().unwrap(), samples.back().unwrap());
+        let window = (last.0 - first.0).as_secs_f64().max(f64::EPSILON);
+        let throughput = Throughput {
+            keys_per_sec: (last.1 - first.1) as f64 / window,
+            bytes_per_sec: (last.2 - first.2) as f64 / window,
+        };
+
+        screen
+            .0
+            .draw(|frame| draw(frame, &progress, &metrics, &throughput, start.elapsed()))?;
+    }
+}
+
+struct Throughput {
+    keys_per_sec: f64,
+    bytes_per_sec: f64,
+}
+
+fn draw(
+    frame: &mut Frame,
+    progress: &DumpProgress,
+    metrics: &Metrics,
+    throughput: &Throughput,
+    elapsed: Duration,
+) {
+    let prefixes = progress.prefixes();
+    let errors = progress.recent_errors();
+    let [overall, stats, prefixes_area, errors_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(1),
+        Constraint::Length(prefixes.len().min(10) as u16 + 3),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+
+    let (done, total) = (progress.done(), progress.total());
+    let overall_label = match total {
+        0 => "listing keys".to_string(),
+        _ => format!("{}/{} keys", done, total),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(" etcddump "))
+            .ratio(ratio(done, total))
+            .label(overall_label),
+        overall,
+    );
+
+    frame.render_widget(
+        Line::from(format!(
+            " {:.0} keys/s, {:.1} MiB/s, {} keys in flight, {}s elapsed",
+            throughput.keys_per_sec,
+            throughput.bytes_per_sec / (1024.0 * 1024.0),
+            metrics.keys_in_flight(),
+            elapsed.as_secs()
+        )),
+        stats,
+    );
+
+    let rows = prefixes.iter().map(|prefix| {
+        Row::new([
+            prefix.prefix.clone(),
+            format!("{}/{}", prefix.done, prefix.total),
+            format!("{:.0}%", ratio(prefix.done, prefix.total) * 100.0),
+        ])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(24),
+                Constraint::Length(5),
+            ],
+        )
+        .header(Row::new(["prefix", "keys", ""]))
+        .block(Block::bordered().title(" prefixes ")),
+        prefixes_area,
+    );
+
+    // The most recent errors that fit, newest at the bottom
+    let shown = errors_area.height.saturating_sub(2) as usize;
+    let items = errors
+        .iter()
+        .skip(errors.len().saturating_sub(shown))
+        .map(|error| ListItem::new(format!("{}: {}", error.key, error.error)));
+    frame.render_widget(
+        List::new(items)
+            .block(Block::bordered().title(format!(" recent errors ({}) ", errors.len()))),
+        errors_area,
+    );
+}
+
+fn ratio(done: usize, total: usize) -> f64 {
+    match total {
+        0 => 0.0,
+        _ => (done as f64 / total as f64).min(1.0),
+    }
+}
@@ -0,0 +1,76 @@
+use crate::dump::EtcdDumper;
+use crate::{manifest, restore};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// How a dump differs from etcd, each list sorted by key
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub missing_from_etcd: Vec<String>,
+    pub missing_from_dump: Vec<String>,
+    /// Keys whose value in etcd doesn't match the dumped one
+    pub mismatched: Vec<String>,
+    /// Keys modified in etcd since the mod_revision their sidecar records, even if to the same
+    /// value
+    pub stale_sidecars: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_empty(&self) -> bool {
+        self.missing_from_etcd.is_empty()
+            && self.missing_from_dump.is_empty()
+            && self.mismatched.is_empty()
+            && self.stale_sidecars.is_empty()
+    }
+}
+
+impl EtcdDumper {
+    /// Compares the dump in dump_dir with what dumping etcd would write right now, honoring the
+    /// prefixes and filters of the config
+    pub async fn verify(&self, dump_dir: &Path) -> Result<VerifyReport> {
+        let mut files: BTreeMap<String, PathBuf> = restore::dump_files(dump_dir, self.config.raw)
+            .context("listing files of the dump")?
+            .into_iter()
+            .collect();
+        let compressed = restore::is_compressed(dump_dir)?;
+        // Written as stored in etcd, whether or not the prefixes ouger is skipped for were given
+        let ouger_skipped: HashSet<String> = manifest::Manifest::read(dump_dir)?
+            .map(|manifest| manifest.keys)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| entry.ouger_skipped)
+            .map(|entry| entry.key)
+            .collect();
+
+        let mut report = VerifyReport::default();
+        let mut fetched_keys = Box::pin(self.fetched_keys());
+        while let Some(fetched) = fetched_keys.next().await {
+            let (key, fetched_key) = fetched?;
+            let value = if ouger_skipped.contains(&key) {
+                fetched_key.kv.value()
+            } else {
+                &fetched_key.value
+            };
+
+            match files.remove(&key) {
+                Some(path) => {
+                    let dumped_value = restore::read_dump_file(&path, compressed)?;
+                    if manifest::Sidecar::read(&path)?.is_some_and(|sidecar| {
+                        sidecar.mod_revision != fetched_key.kv.mod_revision()
+                    }) {
+                        report.stale_sidecars.push(key.clone());
+                    }
+                    if dumped_value != value {
+                        report.mismatched.push(key);
+                    }
+                }
+                None => report.missing_from_dump.push(key),
+            }
+        }
+        report.missing_from_etcd = files.into_keys().collect();
+
+        Ok(report)
+    }
+}
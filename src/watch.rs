@@ -0,0 +1,350 @@
+use crate::dump::{self, DumpConfig, DumpContext, EtcdDumper, Fetched, FetchedKey, KeyPaths};
+use crate::manifest::Sidecar;
+use crate::output::{Output, OutputConfig};
+use crate::{etcd, restore};
+use anyhow::{bail, ensure, Context, Result};
+use etcd_client::{Client as EtcdClient, EventType, WatchOptions, WatchResponse};
+use futures::{stream, StreamExt};
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+impl EtcdDumper {
+    /// Dumps everything like dump, then keeps applying every change made in etcd to the output dir
+    /// until an error occurs. Whenever the watch or the dump falls behind a compaction, everything
+    /// is dumped again at a newer revision and the files of keys that no longer exist are
+    /// removed. The manifest only reflects the last full dump
+    pub async fn watch(&self) -> Result<()> {
+        let OutputConfig::Dir(output_dir) = &self.config.output else {
+            bail!("only dirs can be kept in sync with etcd");
+        };
+        ensure!(
+            self.config.snapshot.is_none(),
+            "snapshots can't be watched, only a live etcd can"
+        );
+        ensure!(
+            !self.config.include_raw,
+            "raw copies of values can't be kept in sync"
+        );
+
+        let mut resyncs = 0u64;
+        loop {
+            match self.sync(output_dir).await {
+                Ok(()) => {}
+                Err(err) if etcd::is_compaction_error(&err) => {
+                    tracing::warn!("dump fell behind a compaction: {:#}", err)
+                }
+                Err(err) => return Err(err),
+            }
+
+            resyncs += 1;
+            tracing::warn!(
+                resyncs,
+                "resyncing after a compaction, dumping everything again"
+            );
+        }
+    }
+
+    /// Dumps everything at the current revision and applies changes from there on, until the watch
+    /// falls behind a compaction
+    async fn sync(&self, output_dir: &Path) -> Result<()> {
+        let client = self.config.etcd.connect().await?;
+        let revision = etcd::current_revision(&client, self.config.etcd.request_timeout).await?;
+
+        let (context, keys) = self.prepare(Some(revision)).await?;
+        let output = self.config.output.create(self.config.follow_symlinks)?;
+
+        remove_stale_files(&output, output_dir, self.config.raw, &keys)?;
+        let mut key_paths = KeyPaths::new(&keys, &self.config)?;
+        let summary = self
+            .dump_to(Arc::clone(&context), keys, &output, None, None)
+            .await?;
+        tracing::info!(
+            revision,
+            count = summary.keys,
+            "dumped keys, watching for changes"
+        );
+
+        self.apply_changes(&client, &context, &output, &mut key_paths, revision + 1)
+            .await
+    }
+
+    /// Applies every change after revision to output, until the watch gets canceled because of a
+    /// compaction
+    async fn apply_changes(
+        &self,
+        client: &EtcdClient,
+        context: &DumpContext,
+        output: &Output,
+        key_paths: &mut KeyPaths,
+        revision: i64,
+    ) -> Result<()> {
+        // The watchers cancel their watch when dropped
+        let mut watchers = Vec::new();
+        let mut watch_streams = Vec::new();
+        for prefix in &self.config.prefixes {
+            let (watcher, watch_stream) = client
+                .watch_client()
+                .max_decoding_message_size(self.config.etcd.max_recv_message_size)
+                .watch(
+                    prefix.as_str(),
+                    Some(
+                        WatchOptions::new()
+                            .with_prefix()
+                            .with_start_revision(revision),
+                    ),
+                )
+                .await
+                .context(format!("watching {}", prefix))?;
+            watchers.push(watcher);
+            watch_streams.push(watch_stream);
+        }
+
+        let mut watch_responses = stream::select_all(watch_streams);
+        while let Some(watch_response) = watch_responses.next().await {
+            let watch_response = watch_response.context("watching etcd")?;
+
+            if watch_response.canceled() {
+                if watch_response.compact_revision() > 0 {
+                    tracing::warn!(
+                        compact_revision = watch_response.compact_revision(),
+                        "watch fell behind a compaction"
+                    );
+                    return Ok(());
+                }
+
+                bail!("watch canceled: {}", watch_response.cancel_reason());
+            }
+
+            self.apply_response(context, output, key_paths, &watch_response)
+                .await?;
+        }
+
+        bail!("watch ended")
+    }
+
+    async fn apply_response(
+        &self,
+        context: &DumpContext,
+        output: &Output,
+        key_paths: &mut KeyPaths,
+        watch_response: &WatchResponse,
+    ) -> Result<()> {
+        for event in watch_response.events() {
+            let Some(kv) = event.kv() else {
+                continue;
+            };
+            let key = kv.key_str()?;
+            if !dump::key_matches(key, &self.config) {
+                continue;
+            }
+
+            let result = match event.event_type() {
+                EventType::Put => {
+                    tracing::debug!(key, "key changed");
+                    match dump::decode_key(context, kv.clone()).await {
+                        Ok(Fetched::Key(fetched_key)) => {
+                            put_key(output, &self.config, key_paths, key, fetched_key)
+                                .map(|written| self.metrics.key_written(written))
+                        }
+                        Ok(Fetched::Undecodable(error)) => {
+                            tracing::warn!(key, "skipping undecodable key: {}", error);
+                            Ok(())
+                        }
+                        Ok(_) => Ok(()),
+                        Err(err) => Err(err),
+                    }
+                }
+                EventType::Delete => {
+                    tracing::debug!(key, "key deleted");
+                    delete_key(output, &self.config, key_paths, key)
+                }
+            };
+
+            match result {
+                Ok(()) => {}
+                Err(err) if self.config.continue_on_error => {
+                    tracing::warn!(key, "failed to apply change: {:#}", err)
+                }
+                Err(err) => return Err(err.context(format!("applying change to key {}", key))),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a changed key where a dump of all keys would, first moving the files of the keys that go
+/// elsewhere now that it exists. Returns how many bytes were written for it
+fn put_key(
+    output: &Output,
+    config: &DumpConfig,
+    key_paths: &mut KeyPaths,
+    key: &str,
+    fetched_key: FetchedKey,
+) -> Result<usize> {
+    let moved = key_paths.update(key, true, config)?;
+    move_files(output, config, key_paths, moved)?;
+
+    dump::write_key(
+        output,
+        config,
+        key,
+        key_paths.renamed(key),
+        fetched_key,
+        &mut HashSet::new(),
+    )
+    .map(|(_, written)| written)
+}
+
+/// Removes the files of a deleted key, then moves those of the keys that go elsewhere now that
+/// it's gone
+fn delete_key(
+    output: &Output,
+    config: &DumpConfig,
+    key_paths: &mut KeyPaths,
+    key: &str,
+) -> Result<()> {
+    // Undecodable values may have been written raw instead
+    for raw in [config.raw, true] {
+        let relative_path = key_paths.output_path(key, config, raw)?;
+        output.remove(&relative_path)?;
+        output.remove(&Sidecar::path(&relative_path))?;
+    }
+
+    let moved = key_paths.update(key, false, config)?;
+    move_files(output, config, key_paths, moved)
+}
+
+/// Moves the files of the moved keys from the path they were written to, without extensions, to
+/// the one key_paths has for them now. All of them are taken before any is written again, as they
+/// may move to where another one was
+fn move_files(
+    output: &Output,
+    config: &DumpConfig,
+    key_paths: &KeyPaths,
+    moved: Vec<(String, String)>,
+) -> Result<()> {
+    let mut files = Vec::new();
+    for (key, old_path) in moved {
+        for raw in [config.raw, true] {
+            let old_path = dump::with_extensions(old_path.clone(), config, raw);
+            let new_path = key_paths.output_path(&key, config, raw)?;
+            // The sidecar goes first, so that the dir is left empty once the value is gone too
+            if let Some(sidecar) = output.take(&Sidecar::path(&old_path))? {
+                files.push((Sidecar::path(&new_path), sidecar));
+            }
+            if let Some(value) = output.take(&old_path)? {
+                tracing::debug!(key, from = old_path, to = new_path, "moving key");
+                files.push((new_path, value));
+            }
+        }
+    }
+
+    for (relative_path, value) in files {
+        output.write(&relative_path, &value)?;
+    }
+
+    Ok(())
+}
+
+/// Removes the files of keys that are not in keys, left over from a previous dump
+fn remove_stale_files(
+    output: &Output,
+    output_dir: &Path,
+    raw: bool,
+    keys: &BTreeSet<String>,
+) -> Result<()> {
+    for (key, path) in restore::dump_files(output_dir, raw).context("listing dumped files")? {
+        if !keys.contains(&key) {
+            tracing::debug!(key, "removing key that no longer exists");
+            let relative_path = path.strip_prefix(output_dir)?;
+            let relative_path = relative_path
+                .to_str()
+                .context(format!("non UTF-8 path {}", relative_path.display()))?;
+            output.remove(relative_path)?;
+            output.remove(&Sidecar::path(relative_path))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etcd_client::proto::PbKeyValue;
+    use etcd_client::KeyValue;
+    use std::path::PathBuf;
+
+    /// A new empty dir for this test, removed first if a previous run left it behind
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("etcddump-watch-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn fetched_key(key: &str) -> FetchedKey {
+        let kv = PbKeyValue {
+            key: key.as_bytes().to_vec(),
+            create_revision: 2,
+            mod_revision: 2,
+            version: 1,
+            value: key.as_bytes().to_vec(),
+            lease: 0,
+        };
+        FetchedKey::raw(<&KeyValue>::from(&kv).clone(), None)
+    }
+
+    /// The files under dir with what they hold
+    fn files(dir: &Path) -> Vec<(String, String)> {
+        restore::dump_files(dir, false)
+            .unwrap()
+            .into_iter()
+            .map(|(_, path)| {
+                (
+                    path.strip_prefix(dir).unwrap().display().to_string(),
+                    std::fs::read_to_string(&path).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn changes_go_where_a_dump_writes_them_whatever_their_order() {
+        for (name, order) in [
+            ("parent-first", ["/a/b", "/a/b/c"]),
+            ("child-first", ["/a/b/c", "/a/b"]),
+        ] {
+            let dir = test_dir(name);
+            let config = DumpConfig {
+                output: OutputConfig::Dir(dir.clone()),
+                ..Default::default()
+            };
+            let output = config.output.create(false).unwrap();
+            let mut key_paths = KeyPaths::new(&BTreeSet::new(), &config).unwrap();
+
+            for key in order {
+                put_key(&output, &config, &mut key_paths, key, fetched_key(key)).unwrap();
+            }
+            assert_eq!(
+                files(&dir),
+                vec![
+                    ("a/b/__value".to_string(), "/a/b".to_string()),
+                    ("a/b/c".to_string(), "/a/b/c".to_string()),
+                ]
+            );
+
+            delete_key(&output, &config, &mut key_paths, order[1]).unwrap();
+            let left = order[0];
+            assert_eq!(
+                files(&dir),
+                vec![(left.trim_start_matches('/').to_string(), left.to_string())]
+            );
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! Dumps a real etcd seeded with values encoded like the API server would have written them, and
+//! checks the decoded files, or restores the snapshots it writes. Needs etcd, etcdutl and
+//! ouger_server in PATH, run with `cargo test -- --ignored`
+
+use etcd_client::{Client as EtcdClient, GetOptions, Txn, TxnOp};
+use etcddump::ouger::{self, OugerCommand};
+use reqwest::Client;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// An etcd server with its own data dir, killed and removed when dropped
+struct EtcdServer {
+    child: Child,
+    data_dir: PathBuf,
+    endpoint: String,
+}
+
+impl EtcdServer {
+    async fn start() -> Self {
+        Self::start_in(scratch_dir("etcd"), ouger::free_port().unwrap()).await
+    }
+
+    /// Starts etcd on data_dir, which may have been restored for a member with peer_port
+    async fn start_in(data_dir: PathBuf, peer_port: u16) -> Self {
+        let client_port = ouger::free_port().unwrap();
+        let client_url = format!("http://127.0.0.1:{}", client_port);
+        let peer_url = peer_url(peer_port);
+
+        let child = Command::new("etcd")
+            .arg("--data-dir")
+            .arg(&data_dir)
+            .args(["--listen-client-urls", &client_url])
+            .args(["--advertise-client-urls", &client_url])
+            .args(["--listen-peer-urls", &peer_url])
+            .args(["--initial-advertise-peer-urls", &peer_url])
+            .args(["--initial-cluster", &format!("default={}", peer_url)])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("running etcd, is it in PATH?");
+
+        let server = Self {
+            child,
+            data_dir,
+            endpoint: format!("127.0.0.1:{}", client_port),
+        };
+        server.wait_ready().await;
+        server
+    }
+
+    async fn wait_ready(&self) {
+        let start = Instant::now();
+        loop {
+            if let Ok(mut client) = EtcdClient::connect([&self.endpoint], None).await {
+                if client.get("/", None).await.is_ok() {
+                    return;
+                }
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "etcd didn't become ready"
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+impl Drop for EtcdServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+fn peer_url(port: u16) -> String {
+    format!("http://127.0.0.1:{}", port)
+}
+
+/// A new empty dir for this test run
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "etcddump-test-{}-{}-{}",
+        name,
+        std::process::id(),
+        ouger::free_port().unwrap()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn config_map(name: &str, value: &str) -> serde_json::Value {
+    serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": {"name": name, "namespace": "default"},
+        "data": {"key": value},
+    })
+}
+
+#[tokio::test]
+#[ignore = "needs etcd and ouger_server in PATH"]
+async fn dump_writes_decoded_values() {
+    let etcd = EtcdServer::start().await;
+    let port = ouger::free_port().unwrap();
+    let _ouger_child_process = ouger::launch_ouger_server(ouger::DEFAULT_OUGER_BIN, port)
+        .await
+        .expect("running ouger_server, is it in PATH?");
+    let client = Client::new();
+    let url = ouger::local_url(port);
+
+    // Seed raw etcd values the same way the API server would have written them
+    let mut etcd_client = EtcdClient::connect([&etcd.endpoint], None).await.unwrap();
+    let config_maps = [("a", "first"), ("b", "second")];
+    for (name, value) in config_maps {
+        let encoded = ouger::ouger(
+            &client,
+            &url,
+            OugerCommand::Encode,
+            &serde_json::to_vec(&config_map(name, value)).unwrap(),
+        )
+        .await
+        .unwrap();
+        etcd_client
+            .put(
+                format!("/registry/configmaps/default/{}", name),
+                encoded,
+                None,
+            )
+            .await
+            .unwrap();
+    }
+    etcd_client
+        .put("/other/ignored", "outside of the prefix", None)
+        .await
+        .unwrap();
+
+    let output_dir = scratch_dir("dump");
+    let status = Command::new(env!("CARGO_BIN_EXE_etcddump"))
+        .args(["--quiet", "dump", "--etcd-endpoint", &etcd.endpoint])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .args(["--prefix", "/registry/"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "dump failed with {}", status);
+
+    for (name, value) in config_maps {
+        let path = output_dir.join("registry/configmaps/default").join(name);
+        let decoded: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(decoded["metadata"]["name"], name);
+        assert_eq!(decoded["data"]["key"], value);
+    }
+    assert!(!output_dir.join("other").exists());
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(output_dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(
+        manifest["keys"].as_array().unwrap().len(),
+        config_maps.len()
+    );
+
+    std::fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs etcd and ouger_server in PATH"]
+async fn values_skipping_ouger_verify() {
+    let etcd = EtcdServer::start().await;
+    let port = ouger::free_port().unwrap();
+    let _ouger_child_process = ouger::launch_ouger_server(ouger::DEFAULT_OUGER_BIN, port)
+        .await
+        .expect("running ouger_server, is it in PATH?");
+
+    let mut etcd_client = EtcdClient::connect([&etcd.endpoint], None).await.unwrap();
+    let encoded = ouger::ouger(
+        &Client::new(),
+        &ouger::local_url(port),
+        OugerCommand::Encode,
+        &serde_json::to_vec(&config_map("a", "first")).unwrap(),
+    )
+    .await
+    .unwrap();
+    etcd_client
+        .put("/registry/configmaps/default/a", encoded, None)
+        .await
+        .unwrap();
+    etcd_client
+        .put("/registry/plain/a", "not for ouger", None)
+        .await
+        .unwrap();
+
+    let output_dir = scratch_dir("skip-ouger");
+    let status = Command::new(env!("CARGO_BIN_EXE_etcddump"))
+        .args(["--quiet", "dump", "--etcd-endpoint", &etcd.endpoint])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .args(["--prefix", "/registry/"])
+        .args(["--skip-ouger-for-prefix", "/registry/plain/"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "dump failed with {}", status);
+
+    // The manifest is enough to tell which values to compare raw
+    let status = Command::new(env!("CARGO_BIN_EXE_etcddump"))
+        .args(["--quiet", "verify", "--etcd-endpoint", &etcd.endpoint])
+        .arg(&output_dir)
+        .args(["--prefix", "/registry/"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "verify failed with {}", status);
+
+    std::fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs etcd in PATH"]
+async fn verify_reports_stale_sidecars() {
+    let etcd = EtcdServer::start().await;
+    let mut etcd_client = EtcdClient::connect([&etcd.endpoint], None).await.unwrap();
+    etcd_client.put("/registry/a", "same", None).await.unwrap();
+
+    let output_dir = scratch_dir("sidecars");
+    let status = Command::new(env!("CARGO_BIN_EXE_etcddump"))
+        .args([
+            "--quiet",
+            "dump",
+            "--etcd-endpoint",
+            &etcd.endpoint,
+            "--raw",
+            "--sidecar-meta",
+        ])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .args(["--prefix", "/registry/"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "dump failed with {}", status);
+
+    let verify = || {
+        Command::new(env!("CARGO_BIN_EXE_etcddump"))
+            .args([
+                "--quiet",
+                "verify",
+                "--etcd-endpoint",
+                &etcd.endpoint,
+                "--raw",
+            ])
+            .arg(&output_dir)
+            .args(["--prefix", "/registry/"])
+            .output()
+            .unwrap()
+    };
+    let output = verify();
+    assert!(
+        output.status.success(),
+        "verify failed with {}",
+        output.status
+    );
+
+    // Put again without changing the value
+    etcd_client.put("/registry/a", "same", None).await.unwrap();
+    let output = verify();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "stale sidecar: /registry/a\n"
+    );
+
+    std::fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs etcd and etcdutl in PATH"]
+async fn snapshot_out_restores() {
+    let etcd = EtcdServer::start().await;
+    let mut etcd_client = EtcdClient::connect([&etcd.endpoint], None).await.unwrap();
+    for i in 0..100 {
+        etcd_client
+            .put(
+                format!("/registry/keys/{:03}", i),
+                vec![i as u8; i * 100],
+                None,
+            )
+            .await
+            .unwrap();
+    }
+    // Keys modified by the same revision need sub revisions
+    etcd_client
+        .txn(Txn::new().and_then([
+            TxnOp::put("/registry/txn/a", "a", None),
+            TxnOp::put("/registry/txn/b", "b", None),
+        ]))
+        .await
+        .unwrap();
+    etcd_client
+        .put("/registry/keys/000", "overwritten", None)
+        .await
+        .unwrap();
+    etcd_client
+        .put("/other/ignored", "outside of the prefix", None)
+        .await
+        .unwrap();
+
+    let snapshot = scratch_dir("snapshot").join("etcd.db");
+    let status = Command::new(env!("CARGO_BIN_EXE_etcddump"))
+        .args(["--quiet", "dump", "--etcd-endpoint", &etcd.endpoint])
+        .arg("--snapshot-out")
+        .arg(&snapshot)
+        .args(["--prefix", "/registry/"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "dump failed with {}", status);
+
+    // etcdutl wants to create the data dir itself
+    let peer_port = ouger::free_port().unwrap();
+    let data_dir = scratch_dir("restored");
+    std::fs::remove_dir(&data_dir).unwrap();
+    let status = Command::new("etcdutl")
+        .args(["snapshot", "restore"])
+        .arg(&snapshot)
+        .arg("--data-dir")
+        .arg(&data_dir)
+        .args([
+            "--initial-cluster",
+            &format!("default={}", peer_url(peer_port)),
+        ])
+        .args(["--initial-advertise-peer-urls", &peer_url(peer_port)])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("running etcdutl, is it in PATH?");
+    assert!(status.success(), "restore failed with {}", status);
+    std::fs::remove_dir_all(snapshot.parent().unwrap()).unwrap();
+
+    let restored = EtcdServer::start_in(data_dir, peer_port).await;
+    let mut restored_client = EtcdClient::connect([&restored.endpoint], None)
+        .await
+        .unwrap();
+    let kvs = |response: etcd_client::GetResponse| {
+        response
+            .kvs()
+            .iter()
+            .map(|kv| {
+                (
+                    kv.key().to_vec(),
+                    kv.value().to_vec(),
+                    kv.create_revision(),
+                    kv.mod_revision(),
+                    kv.version(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let prefix = Some(GetOptions::new().with_prefix());
+    let expected = kvs(etcd_client.get("/registry/", prefix.clone()).await.unwrap());
+    let restored_kvs = kvs(restored_client.get("/registry/", prefix).await.unwrap());
+    assert_eq!(restored_kvs.len(), 102);
+    assert_eq!(restored_kvs, expected);
+    assert_eq!(
+        restored_client
+            .get("/other/ignored", None)
+            .await
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs etcd in PATH"]
+async fn key_with_keys_below_it_goes_into_its_dir() {
+    let etcd = EtcdServer::start().await;
+    let mut etcd_client = EtcdClient::connect([&etcd.endpoint], None).await.unwrap();
+    // Created in the opposite order of the keys, as that shouldn't change where they go
+    etcd_client
+        .put("/registry/a/b/c", "below", None)
+        .await
+        .unwrap();
+    etcd_client
+        .put("/registry/a/b", "above", None)
+        .await
+        .unwrap();
+
+    let output_dir = scratch_dir("collision");
+    let status = Command::new(env!("CARGO_BIN_EXE_etcddump"))
+        .args([
+            "--quiet",
+            "dump",
+            "--etcd-endpoint",
+            &etcd.endpoint,
+            "--raw",
+        ])
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .args(["--prefix", "/registry/"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "dump failed with {}", status);
+
+    let dir = output_dir.join("registry/a/b");
+    assert_eq!(std::fs::read(dir.join("__value.bin")).unwrap(), b"above");
+    assert_eq!(std::fs::read(dir.join("c.bin")).unwrap(), b"below");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_etcddump"))
+        .args([
+            "--quiet",
+            "verify",
+            "--etcd-endpoint",
+            &etcd.endpoint,
+            "--raw",
+        ])
+        .arg(&output_dir)
+        .args(["--prefix", "/registry/"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "verify failed with {}", status);
+
+    std::fs::remove_dir_all(&output_dir).unwrap();
+}
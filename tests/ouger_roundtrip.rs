@@ -0,0 +1,42 @@
+//! Checks that values survive a decode/encode round trip through a real ouger server, which is
+//! what restore relies on. Needs ouger_server in PATH, run with `cargo test -- --ignored`
+
+use etcddump::ouger::{self, OugerCommand};
+use reqwest::Client;
+
+const CONFIG_MAP_JSON: &str = r#"{
+    "apiVersion": "v1",
+    "kind": "ConfigMap",
+    "metadata": {"name": "roundtrip", "namespace": "default"},
+    "data": {"key": "value"}
+}"#;
+
+#[tokio::test]
+#[ignore = "needs ouger_server in PATH"]
+async fn decode_then_encode_is_byte_identical() {
+    let port = ouger::free_port().unwrap();
+    let _ouger_child_process = ouger::launch_ouger_server(ouger::DEFAULT_OUGER_BIN, port)
+        .await
+        .expect("running ouger_server, is it in PATH?");
+    let client = Client::new();
+    let url = ouger::local_url(port);
+
+    // Seed a raw etcd value the same way the API server would have written it
+    let raw_etcd_value = ouger::ouger(
+        &client,
+        &url,
+        OugerCommand::Encode,
+        CONFIG_MAP_JSON.as_bytes(),
+    )
+    .await
+    .unwrap();
+
+    let decoded = ouger::ouger(&client, &url, OugerCommand::Decode, &raw_etcd_value)
+        .await
+        .unwrap();
+    let reencoded = ouger::ouger(&client, &url, OugerCommand::Encode, &decoded)
+        .await
+        .unwrap();
+
+    assert_eq!(raw_etcd_value, reencoded);
+}